@@ -10,7 +10,7 @@ use crate::{
 use serde::{Serialize, Deserialize};
 use camino::Utf8PathBuf;
 use crate::group_cmp::GroupCompMultiOpts;
-use main_execs::SimulationMode;
+use main_execs::{SimulationMode, flow_helper::DEFAULT_TOLERANCE};
 
 #[derive(Parser, Debug)]
 pub struct ParseEnrichOpts{
@@ -180,10 +180,15 @@ pub struct MultiShockOpt{
     #[arg(long, short, requires("group_files"))]
     pub compare_successive_years: bool,
 
-    #[arg(long, short)]
+    #[arg(long, short, default_value = "classic")]
     /// Decide if we want classic mode or
     /// if we want to include stock variation data
-    pub mode: SimulationMode
+    pub mode: SimulationMode,
+
+    #[arg(long, conflicts_with = "mode")]
+    /// Run all simulation modes and additionally write 
+    /// their results side by side
+    pub compare_modes: bool
 }
 
 /// Created by Yannick Feld
@@ -246,7 +251,39 @@ pub enum CmdChooser{
     /// Analyze stocks of FAO file
     StockAnalysis(StockOpt),
     /// Filter G values by trade volume - add trade volume to files
-    FilterAddTradeG(FilterAddTradeGOpts)
+    FilterAddTradeG(FilterAddTradeGOpts),
+    /// Supplier concentration and import dependency indices for all items specified by globbing
+    DependencyIndices(main_execs::dependency_indices::DependencyIndicesOpts),
+    /// Analyze several items of the same year as layers of one multiplex network
+    Multiplex(main_execs::multiplex::MultiplexOpts),
+    /// Maximal flow from all producers to a target importer and the corresponding minimal cut
+    MaxFlow(main_execs::max_flow::MaxFlowOpts),
+    /// Fit power law and alternatives to degree, strength and weight distributions
+    DistFit(main_execs::dist_fit::DistFitOpts),
+    /// Fit a gravity model (PPML with exporter and importer fixed effects) and write the expected networks
+    Gravity(main_execs::gravity::GravityOpts),
+    /// Simulate consecutive years with persisting shocks and stock carry-over
+    DynamicShock(main_execs::dynamic::DynamicShockOpts),
+    /// Exporters whose availability drops below a trigger restrict their own exports, until no new restrictions follow
+    ExportBanCascade(main_execs::cascade::CascadeOpts),
+    /// Search the disruption of k exporters (or of an export budget) that hurts the most and compare it to the top k exporters
+    WorstCase(main_execs::worst_case::WorstCaseOpts),
+    /// Sensitivity of the availability of every importer to the exports of every exporter, with ranked critical suppliers
+    Sensitivity(main_execs::sensitivity::SensitivityOpts),
+    /// Origin of the supply of every country, i.e. the full origin by destination matrix, and virtual resource flows
+    OriginAttribution(main_execs::origin::OriginOpts),
+    /// Cropland or other resources embodied in the consumption and in the trade flows, summed over items
+    Footprint(main_execs::footprint::FootprintOpts),
+    /// Repeat shocks on perturbed edge weights and enrichments and report confidence bands
+    Uncertainty(main_execs::uncertainty::UncertaintyOpts),
+    /// Sobol first order and total indices of the simulation parameters from a Saltelli design
+    GlobalSensitivity(main_execs::global_sensitivity::GlobalSensitivityOpts),
+    /// Disrupt all members of a named group of countries, e.g. a region, at once
+    GroupShock(main_execs::group_shock::GroupShockOpts),
+    /// Run a list of named scenarios in parallel and write one consolidated results table
+    Scenarios(main_execs::scenario::ScenarioOpts),
+    /// Remove countries from the network and compare the availability with the baseline
+    Counterfactual(main_execs::counterfactual::CounterfactualOpts)
 }
 
 #[derive(Debug, Clone, Parser)]
@@ -300,7 +337,17 @@ pub struct ShockCloudCmdOpt{
     pub quiet: bool,
 
     #[arg(long, short, default_value_t)]
-    pub out_stub: String
+    pub out_stub: String,
+
+    #[arg(long, short, default_value = "classic")]
+    /// Decide if we want classic mode or
+    /// if we want to include stock variation data
+    pub mode: SimulationMode,
+
+    #[arg(long, conflicts_with = "mode")]
+    /// Run all simulation modes and additionally write 
+    /// their results side by side
+    pub compare_modes: bool
 }
 
 #[derive(Derivative, Clone, Parser)]
@@ -320,10 +367,20 @@ pub struct ShockCloudAllCmdOpt{
     #[derivative(Default(value="NonZeroUsize::new(5).unwrap()"))]
     pub threads: NonZeroUsize,
 
-    #[arg(long, short)]
+    #[arg(long, short, default_value = "classic")]
     /// Decide if we want classic mode or
     /// if we want to include stock variation data
-    pub mode: SimulationMode
+    pub mode: SimulationMode,
+
+    #[arg(long, conflicts_with = "mode")]
+    /// Run all simulation modes and additionally write 
+    /// their results side by side
+    pub compare_modes: bool,
+
+    #[arg(long)]
+    /// Skip the (item, year) units that the ledger of a previous run
+    /// lists as finished and whose output files are unchanged
+    pub resume: bool
 }
 
 #[derive(Debug, Parser)]
@@ -733,6 +790,11 @@ pub struct ShockOpts{
     #[arg(short, long)]
     pub iterations: usize,
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// The iterations are then only the upper limit
+    #[arg(long, default_value_t = DEFAULT_TOLERANCE)]
+    pub tolerance: f64,
+
     /// fraction of old exports that are still exported
     #[arg(short, long)]
     pub export: f64,
@@ -760,6 +822,11 @@ pub struct ShockAvailOpts{
     #[arg(short, long)]
     pub iterations: usize,
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// The iterations are then only the upper limit
+    #[arg(long, default_value_t = DEFAULT_TOLERANCE)]
+    pub tolerance: f64,
+
     /// fraction of old exports that are still exported
     #[arg(long)]
     pub export: f64,
@@ -767,6 +834,15 @@ pub struct ShockAvailOpts{
     #[arg(long)]
     /// Item code, e.g. 27 for Rice
     pub item_code: Option<String>,
+
+    /// Also report the number of affected people and per capita losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[arg(long)]
+    pub population: bool,
+
+    /// the fraction at which countries are counted as affected by the population output
+    #[arg(long, default_value_t = 0.7)]
+    pub unstable_country_threshold: f64
 }
 
 #[derive(Parser, Debug)]
@@ -786,6 +862,11 @@ pub struct ShockDistOpts{
     #[arg(short, long)]
     pub iterations: usize,
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// The iterations are then only the upper limit
+    #[arg(long, default_value_t = DEFAULT_TOLERANCE)]
+    pub tolerance: f64,
+
     /// fraction of old exports that are still exported
     #[arg(short, long, required(true))]
     pub export: Vec<f64>,
@@ -801,6 +882,15 @@ pub struct ShockDistOpts{
     /// Do not include the country that reduces its exports in the histogram
     #[arg(long, short)]
     pub without: bool,
+
+    /// Also report the number of affected people and per capita losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[arg(long)]
+    pub population: bool,
+
+    /// the fraction at which countries are counted as affected by the population output
+    #[arg(long, default_value_t = 0.7)]
+    pub unstable_country_threshold: f64
 }
 
 #[derive(Parser, Debug)]
@@ -820,6 +910,11 @@ pub struct XOpts{
     #[arg(short, long)]
     pub iterations: usize,
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// The iterations are then only the upper limit
+    #[arg(long, default_value_t = DEFAULT_TOLERANCE)]
+    pub tolerance: f64,
+
     /// fraction of old exports that are still exported
     #[arg(long, default_value_t=0.0)]
     pub export_start: f64,
@@ -868,7 +963,16 @@ pub struct XOpts{
 
     #[arg(long)]
     /// file that maps ids to countries
-    pub country_map: Option<String>
+    pub country_map: Option<String>,
+
+    /// Also report the number of affected people and per capita losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[arg(long)]
+    pub population: bool,
+
+    /// the fraction at which countries are counted as affected by the population output
+    #[arg(long, default_value_t = 0.7)]
+    pub unstable_country_threshold: f64
 }
 
 #[derive(Debug, ValueEnum, Clone, Copy, Serialize, Deserialize)]
@@ -1031,11 +1135,21 @@ pub struct FlowOpt{
     #[arg(long)]
     pub iterations: usize,
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// The iterations are then only the upper limit
+    #[arg(long, default_value_t = DEFAULT_TOLERANCE)]
+    pub tolerance: f64,
+
     #[arg(long)]
     pub item_code: Option<String>,
 
     #[arg(short, long)]
     pub enrich_file: String,
+
+    /// Also report how many people are supplied by the focus country.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[arg(long)]
+    pub population: bool,
 }
 
 #[derive(Parser, Debug)]
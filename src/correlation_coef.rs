@@ -231,7 +231,7 @@ where I: IntoIterator<Item = (F, F)>,
 
 }
 
-fn spearman_correlation_coefficent<I, F>(iterator: I) -> f64
+pub(crate) fn spearman_correlation_coefficent<I, F>(iterator: I) -> f64
 where I: IntoIterator<Item = (F, F)>,
     F: Borrow<f64>
 {
@@ -294,7 +294,7 @@ where I: IntoIterator<Item = (F, F)>,
     }
 }
 
-fn pearson_correlation_coefficient<I, F>(iterator: I) -> f64
+pub(crate) fn pearson_correlation_coefficient<I, F>(iterator: I) -> f64
 where I: IntoIterator<Item = (F, F)>,
     F: Borrow<f64>
 {
@@ -916,8 +916,8 @@ pub fn correlations(opt: CorrelationOpts)
                     .inspect(|_| count += 1)
                     .tuple_windows()
                     .any(|(a,b)| a.ne(b));
-                if country_name_map.is_some() && !any {
-                    let country = country_name_map.as_ref().unwrap().get(&country.to_string()).unwrap();
+                if let (Some(map), false) = (country_name_map.as_ref(), any) {
+                    let country = map.get(&country.to_string()).unwrap();
                     println!("Country: {country} {count}");
                 }
                 any
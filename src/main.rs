@@ -26,13 +26,14 @@ fn main() {
         CmdChooser::ParseEnrichment(o) => enrich_to_bin(o),
         CmdChooser::ParseAllEnrichments(opt) => parse_all_extras(opt.in_files, opt.only_unit),
         CmdChooser::ShockCloudAll(opt) => {
-            let mode = opt.mode;
-            set_global_simulation_mode(mode);
+            let contexts = SimulationContext::contexts(opt.mode, opt.compare_modes);
             main_execs::all_random_cloud_shocks(
                 opt.json,
+                &contexts,
                 &opt.out_stub,
                 opt.quiet,
-                opt.threads
+                opt.threads,
+                opt.resume
             )
         },
         CmdChooser::ShockCloudCmpYears(opt) => {
@@ -45,11 +46,11 @@ fn main() {
             sort_year_cmps::sort_compare_multiple_years(opt);
         },
         CmdChooser::MultiShocks(opt) => {
-            let mode = opt.mode;
-            set_global_simulation_mode(mode);
+            let contexts = SimulationContext::contexts(opt.mode, opt.compare_modes);
             measure_multi_shock(
                 opt.json,
                 opt.which, 
+                &contexts,
                 &opt.out_stub,
                 opt.quiet,
                 opt.group_files,
@@ -77,8 +78,10 @@ fn main() {
         CmdChooser::CompareGroupsCommandCreator(opt) => group_cmp::command_creator(opt),
         CmdChooser::CompareThGroups(opt) => group_cmp::compare_th_exec(opt),
         CmdChooser::ShockCloud(opt) => {
+            let contexts = SimulationContext::contexts(opt.mode, opt.compare_modes);
             main_execs::random_cloud_shock(
                 opt.json,
+                &contexts,
                 &opt.out_stub,
                 opt.quiet
             )
@@ -101,7 +104,23 @@ fn main() {
         },
         CmdChooser::FilterAddTradeG(opt) => {
             network::main_execs::g_filter(opt);
-        }
+        },
+        CmdChooser::DependencyIndices(opt) => dependency_indices::dependency_indices(opt),
+        CmdChooser::Multiplex(opt) => multiplex::multiplex(opt),
+        CmdChooser::MaxFlow(opt) => max_flow::max_flow_exec(opt),
+        CmdChooser::DistFit(opt) => dist_fit::dist_fit(opt),
+        CmdChooser::Gravity(opt) => gravity::gravity(opt),
+        CmdChooser::DynamicShock(opt) => dynamic::dynamic_shock(opt),
+        CmdChooser::ExportBanCascade(opt) => cascade::export_ban_cascade(opt),
+        CmdChooser::WorstCase(opt) => worst_case::worst_case_search(opt),
+        CmdChooser::Sensitivity(opt) => sensitivity::sensitivity_matrix(opt),
+        CmdChooser::OriginAttribution(opt) => origin::origin_attribution(opt),
+        CmdChooser::Footprint(opt) => footprint::footprint(opt),
+        CmdChooser::Uncertainty(opt) => uncertainty::uncertainty(opt),
+        CmdChooser::GlobalSensitivity(opt) => global_sensitivity::global_sensitivity(opt),
+        CmdChooser::GroupShock(opt) => group_shock::group_shock(opt),
+        CmdChooser::Scenarios(opt) => scenario::run_scenarios(opt),
+        CmdChooser::Counterfactual(opt) => counterfactual::counterfactual(opt)
     }
 }
 
@@ -36,14 +36,6 @@ impl Direction{
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, EnumString)]
-pub enum NetworkType{
-    #[strum(ascii_case_insensitive)]
-    Value,
-    #[strum(ascii_case_insensitive)]
-    Quantity
-}
-
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -868,15 +860,13 @@ impl Network{
                         rec(n, edge.index, counter, low, num, stack, components);
                         low[id] = low[id].min(low[edge.index]);
                     },
-                    TarjanNumberHelper::Visited(num) if num < this_num => {
-                        // is frond or cross-link
-                        // If this function is ever to slow:
-                        // I should be able to exchange the 'contains' by just checking wheather the number 
-                        // is larger or equal to a threshold, which is determined when the function is first called,
-                        // i.e., before the recursion
-                        if stack.contains(&edge.index) {
-                            low[id] = low[id].min(TarjanNumberHelper::Visited(num));
-                        }
+                    // is frond or cross-link
+                    // If this function is ever to slow:
+                    // I should be able to exchange the 'contains' by just checking wheather the number 
+                    // is larger or equal to a threshold, which is determined when the function is first called,
+                    // i.e., before the recursion
+                    TarjanNumberHelper::Visited(num) if num < this_num && stack.contains(&edge.index) => {
+                        low[id] = low[id].min(TarjanNumberHelper::Visited(num));
                     },
                     _ => {
                         // edge is to be ignored
@@ -967,6 +957,26 @@ pub fn largest_connected_component(network: &Network) -> LargestComponents
 }
 
 
+/// Export network for the tests. The nodes are called 0..n,
+/// the edges are (exporter, importer, amount)
+#[cfg(test)]
+pub(crate) fn test_export_network(n: usize, edges: &[(usize, usize, f64)]) -> Network
+{
+    let mut nodes: Vec<_> = (0..n)
+        .map(|i| Node::new(i.to_string()))
+        .collect();
+    for &(from, to, amount) in edges{
+        nodes[from].adj.push(Edge{index: to, amount});
+    }
+    Network{
+        direction: Direction::ExportTo,
+        data_origin: ReadType::ImportQuantity,
+        unit: "t".to_owned(),
+        nodes,
+        year: 2000,
+        sorted_item_codes: Vec::new()
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -21,9 +21,11 @@ pub const EXPORT_QUANTITY: &str = "Export Quantity";
 pub const IMPORT_QUANTITY: &str = "Import Quantity";
 pub const STOCK_VARIATION: &str = "Stock Variation";
 pub const STOCK: &str = "Stocks";
+pub const AREA_HARVESTED: &str = "Area harvested";
+pub const YIELD: &str = "Yield";
 
 const POSSIBLE_NODE_INFO: [&str; 37] = [
-    "Area harvested",
+    AREA_HARVESTED,
     "Domestic supply quantity",
     EXPORT_QUANTITY,
     "Fat supply quantity (g/capita/day)",
@@ -50,7 +52,7 @@ const POSSIBLE_NODE_INFO: [&str; 37] = [
     STOCK_VARIATION,
     "Tourist consumption",
     TOTAL_POPULATION,
-    "Yield",
+    YIELD,
     "Yield/Carcass Weight",
     "Calories/Year",
     "Fats/Year",
@@ -193,7 +195,7 @@ impl LazyEnrichmentInfos{
         panic!("{}", crate::misc::AVAILABILITY_ERR)
     }
 
-    pub fn extra_info_idmap_unchecked(&self) -> ExtraInfoMap
+    pub fn extra_info_idmap_unchecked(&self) -> ExtraInfoMap<'_>
     {
         self.enrichment_infos_unchecked().get_node_map()
     }
@@ -226,7 +228,7 @@ pub struct EnrichmentInfos{
 }
 
 impl EnrichmentInfos{
-    pub fn get_node_map(&self) -> ExtraInfoMap
+    pub fn get_node_map(&self) -> ExtraInfoMap<'_>
     {
         ExtraInfoMap::from_slice(self.possible_node_info.as_slice())
     }
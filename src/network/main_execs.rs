@@ -1,10 +1,32 @@
 mod execs;
 mod flow;
 pub mod flow_helper;
+pub mod shock_solver;
+pub mod rerouting;
+pub mod population;
+pub mod samplers;
+pub mod cloud_stats;
+pub mod ledger;
 pub mod match_maker;
 pub mod av_analyzer;
 pub mod trade_count;
 pub mod g_filter;
+pub mod dependency_indices;
+pub mod multiplex;
+pub mod max_flow;
+pub mod dist_fit;
+pub mod gravity;
+pub mod dynamic;
+pub mod cascade;
+pub mod worst_case;
+pub mod sensitivity;
+pub mod origin;
+pub mod footprint;
+pub mod uncertainty;
+pub mod global_sensitivity;
+pub mod group_shock;
+pub mod scenario;
+pub mod counterfactual;
 
 pub use execs::*;
 pub use flow::*;
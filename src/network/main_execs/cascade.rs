@@ -0,0 +1,518 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    shock_solver::{sweep_order, SolverBackend},
+    population::{Population, PopulationImpact},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    production_vec,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct CascadeOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "cascade")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct ExportBanCascade{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    /// Years that are simulated independently
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// Ids of the countries causing the initial shock. If empty, the top exporters are used
+    pub countries: Vec<String>,
+
+    /// How many top exporters cause the initial shock if no countries are given
+    #[derivative(Default(value = "1"))]
+    pub top: usize,
+
+    /// Remaining fraction of exports or production of the initially shocked countries
+    pub shock_frac: f64,
+
+    /// Restrict the exports or reduce the production of the initially shocked countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// An exporter whose availability divided by its availability without shock
+    /// drops below this value restricts its own exports
+    #[derivative(Default(value = "0.9"))]
+    pub trigger: f64,
+
+    /// A triggered exporter exports at most this fraction of its original exports.
+    /// If the shortage of its own imports leaves less, it exports less
+    #[derivative(Default(value = "0.5"))]
+    pub policy_frac: f64,
+
+    /// Maximal number of cascade rounds
+    #[derivative(Default(value = "100"))]
+    pub max_rounds: usize,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock are neither counted as unstable nor triggered
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64,
+
+    /// Also report the number of affected people and population weighted losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[serde(default)]
+    pub population: bool
+}
+
+/// A country that joined the cascade
+struct Triggered{
+    round: usize,
+    index: usize,
+    country: String,
+    /// relative availability that caused the restriction
+    ratio: f64,
+    /// fraction of its original exports the country keeps at the end of the cascade
+    export_frac: f64
+}
+
+/// Export fraction of a country if it exported everything it does not lose
+/// through missing imports or production, like an unrestricted exporter
+fn propagated_export_frac(job: &CalcShockMultiJob, res: &ShockRes, index: usize) -> f64
+{
+    let missing_imports = (1.0 - res.import_fracs[index]) * job.original_imports[index];
+    let available_for_export = job.original_exports[index]
+        - missing_imports
+        - job.production_loss(index);
+    if available_for_export <= 0.0 {
+        0.0
+    } else {
+        available_for_export * job.original_exports_recip[index]
+    }
+}
+
+struct CascadeSummary{
+    year: i32,
+    cascade_size: usize,
+    rounds: usize,
+    unstable_initial: usize,
+    unstable_final: usize,
+    impact: Option<PopulationImpact>,
+    convergence: ConvergenceSummary
+}
+
+/// Result of the cascade rounds of one year
+struct CascadeRun{
+    triggered: Vec<Triggered>,
+    rounds: usize,
+    unstable_initial: usize,
+    unstable_final: usize,
+    final_avail: Vec<f64>,
+    convergence: ConvergenceSummary
+}
+
+/// Propagates the shock of the job. Free exporters whose relative availability drops below
+/// the trigger restrict their exports, which is repeated until nothing changes anymore
+#[allow(clippy::too_many_arguments)]
+fn run_cascade(
+    json: &ExportBanCascade,
+    export: &Network,
+    import: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    ctx: &SimulationContext,
+    mut job: CalcShockMultiJob,
+    baseline: &[f64],
+    counted: &[usize],
+    quiet: bool
+) -> CascadeRun
+{
+    let count_unstable = |avail: &[f64]| {
+        counted.iter()
+            .filter(|&&idx| avail[idx] / baseline[idx] < json.unstable_country_threshold)
+            .count()
+    };
+
+    let mut convergence = ConvergenceSummary::default();
+    let mut triggered: Vec<Triggered> = Vec::new();
+    let mut unstable_initial = None;
+    let mut rounds = 0;
+    let (unstable_final, final_avail) = loop {
+        let res = multi_shock_distribution(import, &job);
+        res.convergence.warn_if_not_converged("cascade");
+        convergence.add(&res.convergence);
+        let (avail, _) = calc_available(export, enrich, &res, ctx, quiet);
+        let unstable = count_unstable(&avail);
+        if unstable_initial.is_none(){
+            unstable_initial = Some(unstable);
+        }
+
+        if rounds == json.max_rounds {
+            if !quiet{
+                eprintln!("Year {}: cascade stopped after max_rounds = {rounds}", export.year);
+            }
+            break (unstable, avail);
+        }
+
+        // triggered exporters follow the shortage of their own imports,
+        // but never export more than the policy allows
+        let updated = job.exporter
+            .iter()
+            .filter(|e| triggered.iter().any(|t| t.index == e.export_id))
+            .map(|e| propagated_export_frac(&job, &res, e.export_id).min(json.policy_frac))
+            .collect_vec();
+        let mut changed = false;
+        for (e, frac) in job.exporter
+            .iter_mut()
+            .filter(|e| triggered.iter().any(|t| t.index == e.export_id))
+            .zip(updated)
+        {
+            changed |= (e.export_frac - frac).abs() > json.tolerance;
+            e.export_frac = frac;
+        }
+
+        // only exporters that are still free can join the cascade
+        let new = job.unrestricted_node_idxs
+            .iter()
+            .copied()
+            .filter(|&idx| job.original_exports[idx] > 0.0)
+            .filter(|idx| counted.contains(idx))
+            .filter_map(
+                |idx|
+                {
+                    let ratio = avail[idx] / baseline[idx];
+                    (ratio < json.trigger).then_some((idx, ratio))
+                }
+            ).collect_vec();
+        if new.is_empty() && !changed {
+            break (unstable, avail);
+        }
+        rounds += 1;
+        for (idx, ratio) in new{
+            let export_frac = res.export_fracs[idx].min(json.policy_frac);
+            job.add_exporter(ExportShockItem{export_id: idx, export_frac});
+            triggered.push(
+                Triggered{
+                    round: rounds,
+                    index: idx,
+                    country: export.nodes[idx].identifier.clone(),
+                    ratio,
+                    export_frac
+                }
+            );
+        }
+    };
+
+    for t in triggered.iter_mut(){
+        t.export_frac = job.exporter
+            .iter()
+            .find(|e| e.export_id == t.index)
+            .unwrap()
+            .export_frac;
+    }
+
+    CascadeRun{
+        triggered,
+        rounds,
+        unstable_initial: unstable_initial.unwrap(),
+        unstable_final,
+        final_avail,
+        convergence
+    }
+}
+
+pub fn export_ban_cascade(opt: CascadeOpts)
+{
+    let json: ExportBanCascade = parse_and_add_to_global(opt.json);
+    assert!(
+        (0.0..=1.0).contains(&json.shock_frac),
+        "shock_frac has to be in range 0.0..=1.0"
+    );
+    assert!(
+        (0.0..=1.0).contains(&json.policy_frac),
+        "policy_frac has to be in range 0.0..=1.0"
+    );
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&node_map);
+    let mode_str = ctx.mode_str();
+    let shock_str = json.shock_type.name_addition();
+
+    let summaries = json.years
+        .clone()
+        .into_par_iter()
+        .map(
+            |year|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+
+                let initial = if json.countries.is_empty(){
+                    get_top_k_ids(&export, json.top)
+                } else {
+                    json.countries
+                        .iter()
+                        .filter_map(|c| export.get_index(c))
+                        .collect_vec()
+                };
+                assert!(
+                    !initial.is_empty(),
+                    "None of the initially shocked countries trade in year {year}"
+                );
+
+                let original_exports = calc_acc_trade(&export);
+                let original_exports_recip = calc_recip(&original_exports);
+                let original_imports = calc_acc_trade(&import);
+                let original_imports_recip = calc_recip(&original_imports);
+                let production = production_vec(&export, enrich, &ctx);
+                let order = if json.solver.needs_order(){
+                    sweep_order(&import)
+                } else {
+                    Vec::new()
+                };
+
+                let job = match json.shock_type{
+                    ShockType::ExportRestriction => {
+                        CalcShockMultiJob::new_const_export(
+                            &initial,
+                            json.shock_frac,
+                            json.iterations,
+                            &export,
+                            &original_exports,
+                            &original_exports_recip,
+                            &original_imports,
+                            &original_imports_recip
+                        )
+                    },
+                    ShockType::Production => {
+                        CalcShockMultiJob::new_const_production(
+                            &initial,
+                            json.shock_frac,
+                            &production,
+                            json.iterations,
+                            &export,
+                            &original_exports,
+                            &original_exports_recip,
+                            &original_imports,
+                            &original_imports_recip
+                        )
+                    }
+                }.with_tolerance(json.tolerance)
+                .with_solver(json.solver, &order);
+
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, flow_status) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+                let counted = (0..export.node_count())
+                    .filter(|idx| !initial.contains(idx))
+                    .filter(|&idx| baseline[idx] >= json.original_avail_filter)
+                    .collect_vec();
+                let CascadeRun{
+                    triggered,
+                    rounds,
+                    unstable_initial,
+                    unstable_final,
+                    final_avail,
+                    convergence
+                } = run_cascade(&json, &export, &import, enrich, &ctx, job, &baseline, &counted, opt.quiet);
+
+                let impact = json.population.then(
+                    ||
+                    {
+                        let population = Population::new(&export, enrich, &node_map);
+                        population.write_missing(
+                            format!("{}_Y{year}.missing_population", opt.out_stub),
+                            &export,
+                            &counted
+                        );
+                        let buf = create_buf_with_command_and_version_and_header(
+                            format!("{}_Y{year}.per_capita", opt.out_stub),
+                            Population::per_capita_header(&export.unit)
+                        );
+                        population.write_per_capita(buf, &export, &counted, &baseline, &final_avail)
+                            .unwrap();
+                        population.impact(&counted, &baseline, &final_avail, json.unstable_country_threshold)
+                    }
+                );
+
+                let name = format!(
+                    "{}{}_Y{year}_T{}_P{}_{mode_str}{shock_str}.cascade",
+                    flow_status.name_addition(),
+                    opt.out_stub,
+                    json.trigger,
+                    json.policy_frac
+                );
+                let header = [
+                    "round",
+                    "country",
+                    "relative_availability",
+                    "remaining_export_frac"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(name, header);
+                writeln!(
+                    buf,
+                    "# initial {}",
+                    initial.iter()
+                        .map(|&idx| export.nodes[idx].identifier.as_str())
+                        .join(",")
+                ).unwrap();
+                for t in triggered.iter(){
+                    writeln!(buf, "{} {} {} {}", t.round, t.country, t.ratio, t.export_frac).unwrap();
+                }
+
+                CascadeSummary{
+                    year,
+                    cascade_size: triggered.len(),
+                    rounds,
+                    unstable_initial,
+                    unstable_final,
+                    impact,
+                    convergence
+                }
+            }
+        ).collect::<Vec<_>>();
+
+    let mut header = vec![
+        "year",
+        "cascade_size",
+        "rounds",
+        "unstable_initial_shock",
+        "unstable_after_cascade"
+    ];
+    if json.population{
+        header.extend(PopulationImpact::HEADER);
+    }
+    let name = format!(
+        "{}_T{}_P{}_{mode_str}{shock_str}.summary",
+        opt.out_stub,
+        json.trigger,
+        json.policy_frac
+    );
+    let mut buf = create_buf_with_command_and_version_and_header(name, &header);
+    let mut total = ConvergenceSummary::default();
+    for s in summaries.iter(){
+        write!(
+            buf,
+            "{} {} {} {} {}",
+            s.year,
+            s.cascade_size,
+            s.rounds,
+            s.unstable_initial,
+            s.unstable_final
+        ).unwrap();
+        if let Some(impact) = s.impact.as_ref(){
+            impact.write(&mut buf).unwrap();
+        }
+        writeln!(buf).unwrap();
+        total.merge(&s.convergence);
+    }
+    total.write_comment(&mut buf).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+
+    #[test]
+    fn triggered_exporters_follow_their_imports() {
+        // 0 is shocked, 1 re-exports to 4, which re-exports to 6. 5 only exports
+        let export = test_export_network(
+            7,
+            &[(0, 1, 10.0), (5, 1, 10.0), (1, 4, 8.0), (4, 6, 4.0)]
+        );
+        let import = export.invert();
+        let oe = calc_acc_trade(&export);
+        let oe_recip = calc_recip(&oe);
+        let oi = calc_acc_trade(&import);
+        let oi_recip = calc_recip(&oi);
+        let json = ExportBanCascade{
+            // every exporter that is counted joins the cascade in the first round
+            trigger: 1.5,
+            policy_frac: 0.6,
+            ..Default::default()
+        };
+        let job = CalcShockMultiJob::new_const_export(&[0], 0.8, 10000, &export, &oe, &oe_recip, &oi, &oi_recip);
+        let ctx = SimulationContext::default();
+        let enrich = Default::default();
+        let (baseline, _) = calc_available(&export, &enrich, &ShockRes::no_shock(7), &ctx, true);
+        let counted = (1..7)
+            .filter(|&idx| baseline[idx] >= json.original_avail_filter)
+            .collect_vec();
+        assert_eq!(counted, vec![1, 4, 6]);
+
+        let run = run_cascade(&json, &export, &import, &enrich, &ctx, job, &baseline, &counted, true);
+        let frac = |idx| run.triggered
+            .iter()
+            .find(|t| t.index == idx)
+            .unwrap()
+            .export_frac;
+        assert_eq!(run.triggered.len(), 2);
+        // 1 could still export 0.75 of its exports, but the policy caps it
+        assert!((frac(1) - 0.6).abs() < 1e-9);
+        // 4 was triggered at 0.5, the restriction of 1 leaves it 0.2 in the next round
+        assert!((frac(4) - 0.2).abs() < 1e-9);
+        assert_eq!(run.rounds, 2);
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::Write;
+use derivative::Derivative;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Which parts of the distribution of the unstable country counts
+/// are written in addition to the averages
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct CloudDistribution{
+    /// Number of Poisson bootstrap replicates of the averages
+    #[derivative(Default(value = "200"))]
+    pub bootstrap_samples: usize,
+
+    /// Level of the bootstrap confidence interval
+    #[derivative(Default(value = "0.95"))]
+    pub confidence: f64,
+
+    /// The probability of more than this many unstable countries is reported
+    #[derivative(Default(value = "vec![0, 5, 10, 20]"))]
+    pub exceedance: Vec<u32>
+}
+
+/// Exact distribution of an integer count.
+/// Memory is bounded by the maximal count, not by the number of samples
+#[derive(Debug, Clone, Default)]
+pub struct CountDistribution{
+    /// counts[c] is how often c was added
+    counts: Vec<u64>,
+    hits: u64
+}
+
+impl CountDistribution{
+    pub fn add(&mut self, count: u32)
+    {
+        let c = count as usize;
+        if c >= self.counts.len() {
+            self.counts.resize(c + 1, 0);
+        }
+        self.counts[c] += 1;
+        self.hits += 1;
+    }
+
+    pub fn hits(&self) -> u64
+    {
+        self.hits
+    }
+
+    /// Smallest count c with P(X <= c) >= q, None without hits
+    pub fn quantile(&self, q: f64) -> Option<u32>
+    {
+        if self.hits == 0 {
+            return None;
+        }
+        let target = q * self.hits as f64;
+        let mut cumulative = 0;
+        for (c, &n) in self.counts.iter().enumerate(){
+            cumulative += n;
+            if cumulative as f64 >= target && cumulative > 0 {
+                return Some(c as u32);
+            }
+        }
+        Some((self.counts.len() - 1) as u32)
+    }
+
+    /// P(X > n)
+    pub fn exceedance(&self, n: u32) -> f64
+    {
+        let above: u64 = self.counts
+            .iter()
+            .skip(n as usize + 1)
+            .sum();
+        above as f64 / self.hits as f64
+    }
+
+    /// Non empty counts and how often they occurred
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_
+    {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 0)
+            .map(|(c, &n)| (c as u32, n))
+    }
+}
+
+/// Poisson bootstrap of a mean. Every sample enters every replicate
+/// with a Poisson(1) distributed weight, so no samples need to be stored
+#[derive(Debug, Clone)]
+pub struct PoissonBootstrap{
+    sums: Vec<f64>,
+    weights: Vec<f64>
+}
+
+/// Knuth's algorithm, fast for the small mean of 1
+fn poisson_1<R: Rng>(rng: &mut R) -> u32
+{
+    let limit = (-1.0_f64).exp();
+    let mut k = 0;
+    let mut p: f64 = rng.gen();
+    while p > limit {
+        k += 1;
+        p *= rng.gen::<f64>();
+    }
+    k
+}
+
+impl PoissonBootstrap{
+    pub fn new(replicates: usize) -> Self
+    {
+        Self{
+            sums: vec![0.0; replicates],
+            weights: vec![0.0; replicates]
+        }
+    }
+
+    pub fn add<R: Rng>(&mut self, value: f64, rng: &mut R)
+    {
+        for (sum, weight) in self.sums.iter_mut().zip(self.weights.iter_mut()){
+            let w = poisson_1(rng) as f64;
+            *sum += w * value;
+            *weight += w;
+        }
+    }
+
+    /// Percentile confidence interval of the mean
+    pub fn confidence_interval(&self, confidence: f64) -> (f64, f64)
+    {
+        let mut means: Vec<f64> = self.sums
+            .iter()
+            .zip(self.weights.iter())
+            .filter(|(_, &w)| w > 0.0)
+            .map(|(s, w)| s / w)
+            .collect();
+        if means.is_empty(){
+            return (f64::NAN, f64::NAN);
+        }
+        means.sort_unstable_by(f64::total_cmp);
+        let alpha = (1.0 - confidence) / 2.0;
+        let last = means.len() - 1;
+        let idx = |q: f64| ((q * last as f64).round() as usize).min(last);
+        (means[idx(alpha)], means[idx(1.0 - alpha)])
+    }
+}
+
+/// Streaming statistics of the unstable country counts of one disruption bin
+#[derive(Debug, Clone)]
+pub struct BinStats{
+    pub distribution: CountDistribution,
+    pub bootstrap: PoissonBootstrap
+}
+
+impl BinStats{
+    pub fn new(opt: &CloudDistribution) -> Self
+    {
+        Self{
+            distribution: CountDistribution::default(),
+            bootstrap: PoissonBootstrap::new(opt.bootstrap_samples)
+        }
+    }
+
+    pub fn add<R: Rng>(&mut self, count: u32, rng: &mut R)
+    {
+        self.distribution.add(count);
+        self.bootstrap.add(count as f64, rng);
+    }
+}
+
+pub fn quantile_header(opt: &CloudDistribution) -> Vec<String>
+{
+    [
+        "interval_left",
+        "interval_right",
+        "hits",
+        "ci_low",
+        "ci_high",
+        "q05",
+        "median",
+        "q95"
+    ].into_iter()
+        .map(str::to_owned)
+        .chain(opt.exceedance.iter().map(|n| format!("P(>{n})")))
+        .collect()
+}
+
+/// One line of the quantile file
+pub fn write_quantile_line<W: Write>(
+    mut w: W,
+    interval: [f64; 2],
+    stats: &BinStats,
+    opt: &CloudDistribution
+) -> std::io::Result<()>
+{
+    let dist = &stats.distribution;
+    let (low, high) = stats.bootstrap.confidence_interval(opt.confidence);
+    let q = |q| dist.quantile(q).map_or(f64::NAN, |c| c as f64);
+    write!(
+        w,
+        "{} {} {} {low:e} {high:e} {} {} {}",
+        interval[0],
+        interval[1],
+        dist.hits(),
+        q(0.05),
+        q(0.5),
+        q(0.95)
+    )?;
+    for &n in opt.exceedance.iter(){
+        write!(w, " {:e}", dist.exceedance(n))?;
+    }
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn quantiles_and_exceedance() {
+        let mut dist = CountDistribution::default();
+        for c in 1..=100{
+            dist.add(c);
+        }
+        assert_eq!(dist.quantile(0.5), Some(50));
+        assert_eq!(dist.quantile(0.05), Some(5));
+        assert_eq!(dist.quantile(0.95), Some(95));
+        assert!((dist.exceedance(90) - 0.1).abs() < 1e-12);
+
+        let mut rng = Pcg64::seed_from_u64(8);
+        let mut boot = PoissonBootstrap::new(500);
+        for c in 1..=100{
+            boot.add(c as f64, &mut rng);
+        }
+        let (low, high) = boot.confidence_interval(0.95);
+        assert!(low < 50.5 && 50.5 < high);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    calc_available,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct CounterfactualOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "counterfactual")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+/// What happens to the imports that came from removed countries
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Reallocation{
+    /// The imports are lost
+    #[default]
+    Lost,
+    /// Every importer gets the lost amount from its remaining suppliers,
+    /// proportional to what they already deliver. Importers without remaining suppliers lose it
+    Proportional
+}
+
+impl Reallocation{
+    pub fn name(self) -> &'static str
+    {
+        match self{
+            Self::Lost => "Lost",
+            Self::Proportional => "Prop"
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct Counterfactual{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// Ids of the countries that are removed from the network
+    pub removed: Vec<String>,
+
+    #[serde(default)]
+    pub reallocation: Reallocation,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product in the baseline are not listed
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64
+}
+
+/// The export network without the removed countries
+pub struct CounterfactualNetwork{
+    pub network: Network,
+    /// Node i of the new network is node kept[i] of the original network
+    pub kept: Vec<usize>,
+    /// Imports of the remaining countries that came from removed countries
+    pub lost: f64,
+    /// Part of the lost imports that remaining suppliers deliver instead
+    pub reallocated: f64
+}
+
+pub fn counterfactual_network(
+    export_network: &Network,
+    removed: &[usize],
+    reallocation: Reallocation
+) -> CounterfactualNetwork
+{
+    assert!(!export_network.direction.is_import());
+    let n = export_network.node_count();
+    let mut is_removed = vec![false; n];
+    for &idx in removed{
+        is_removed[idx] = true;
+    }
+    let kept = (0..n)
+        .filter(|&idx| !is_removed[idx])
+        .collect_vec();
+
+    let mut lost_imports = vec![0.0; n];
+    let mut remaining_imports = vec![0.0; n];
+    for (i, node) in export_network.nodes.iter().enumerate(){
+        for e in node.adj.iter(){
+            if is_removed[e.index]{
+                continue;
+            }
+            if is_removed[i]{
+                lost_imports[e.index] += e.amount;
+            } else {
+                remaining_imports[e.index] += e.amount;
+            }
+        }
+    }
+    let lost: f64 = lost_imports.iter().sum();
+
+    let mut network = export_network.filtered_network(kept.iter());
+    let mut reallocated = 0.0;
+    if reallocation == Reallocation::Proportional {
+        let factor = lost_imports.iter()
+            .zip(remaining_imports.iter())
+            .map(
+                |(l, r)|
+                {
+                    if *r > 0.0 {
+                        1.0 + l / r
+                    } else {
+                        1.0
+                    }
+                }
+            ).collect_vec();
+        for node in network.nodes.iter_mut(){
+            for e in node.adj.iter_mut(){
+                let f = factor[kept[e.index]];
+                reallocated += e.amount * (f - 1.0);
+                e.amount *= f;
+            }
+        }
+    }
+    CounterfactualNetwork{
+        network,
+        kept,
+        lost,
+        reallocated
+    }
+}
+
+pub fn counterfactual(opt: CounterfactualOpts)
+{
+    let json: Counterfactual = parse_and_add_to_global(opt.json);
+    assert!(!json.removed.is_empty(), "No countries to remove");
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+    let mode_str = ctx.mode_str();
+    let realloc_str = json.reallocation.name();
+
+    json.years
+        .clone()
+        .into_par_iter()
+        .for_each(
+            |year|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let enrich = enrichment_infos.get_year(year);
+                let removed = json.removed
+                    .iter()
+                    .filter_map(|id| export.get_index(id))
+                    .collect_vec();
+                if removed.is_empty(){
+                    if !opt.quiet{
+                        eprintln!("Y{year}: none of the removed countries trade, skipping");
+                    }
+                    return;
+                }
+
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, flow_status) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+                let cf = counterfactual_network(&export, &removed, json.reallocation);
+                let no_shock = ShockRes::no_shock(cf.network.node_count());
+                let (avail, _) = calc_available(&cf.network, enrich, &no_shock, &ctx, opt.quiet);
+
+                let listed = cf.kept
+                    .iter()
+                    .zip(avail.iter())
+                    .filter(|(&old, _)| baseline[old] >= json.original_avail_filter)
+                    .collect_vec();
+                let unstable = listed.iter()
+                    .filter(|(&old, &a)| a / baseline[old] < json.unstable_country_threshold)
+                    .count();
+
+                let name = format!(
+                    "{}{}_Y{year}_{realloc_str}_{mode_str}.counterfactual",
+                    flow_status.name_addition(),
+                    opt.out_stub
+                );
+                let header = [
+                    "country",
+                    "baseline",
+                    "counterfactual",
+                    "relative_availability",
+                    "difference"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(name, header);
+                writeln!(
+                    buf,
+                    "# removed {}",
+                    removed.iter()
+                        .map(|&idx| export.nodes[idx].identifier.as_str())
+                        .join(",")
+                ).unwrap();
+                writeln!(buf, "# lost imports {:e} reallocated {:e}", cf.lost, cf.reallocated).unwrap();
+                writeln!(
+                    buf,
+                    "# {unstable} of {} countries below {}",
+                    listed.len(),
+                    json.unstable_country_threshold
+                ).unwrap();
+                for (&old, &a) in listed{
+                    let b = baseline[old];
+                    writeln!(
+                        buf,
+                        "{} {b:e} {a:e} {:e} {:e}",
+                        export.nodes[old].identifier,
+                        a / b,
+                        a - b
+                    ).unwrap();
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+
+    #[test]
+    fn proportional_keeps_imports() {
+        // 0 is removed, 3 imports from 0, 1 and 2, 4 only from 0
+        let amounts = [
+            (0, 3, 6.0),
+            (1, 3, 1.0),
+            (2, 3, 3.0),
+            (0, 4, 2.0)
+        ];
+        let export = test_export_network(5, &amounts);
+        let cf = counterfactual_network(&export, &[0], Reallocation::Proportional);
+        assert_eq!(cf.kept, vec![1, 2, 3, 4]);
+        assert!((cf.lost - 8.0).abs() < 1e-12);
+        // 4 has no remaining supplier
+        assert!((cf.reallocated - 6.0).abs() < 1e-12);
+        let import = cf.network.invert();
+        assert!((import.nodes[2].trade_amount() - 10.0).abs() < 1e-12);
+        assert!((cf.network.nodes[1].adj[0].amount - 7.5).abs() < 1e-12);
+    }
+}
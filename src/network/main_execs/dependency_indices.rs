@@ -0,0 +1,199 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    io::Write,
+    ops::Deref
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use itertools::Itertools;
+use ordered_float::OrderedFloat;
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, LazyNetworks},
+    UNIT_TESTER
+};
+use super::{flow::get_files, flow_helper::calc_acc_trade};
+
+#[derive(Debug, Clone, Parser)]
+pub struct DependencyIndicesOpts{
+    /// Globbing for the network files. The item code is the first number in the filename
+    #[arg(long, short)]
+    pub network_glob: String,
+
+    /// Globbing for the enrichment files. The item code is the first number in the filename
+    #[arg(long, short)]
+    pub enrich_glob: String,
+
+    /// Name of the output file
+    #[arg(long, short, default_value = "dependency_indices.dat")]
+    pub out: Utf8PathBuf
+}
+
+/// Indicators for one country in one item-year network
+struct CountryIndices{
+    imports: f64,
+    exports: f64,
+    production: f64,
+    supplier_hhi: f64,
+    top1_share: f64,
+    top3_share: f64,
+    import_dependency: f64,
+    self_sufficiency: f64,
+    export_market_share: f64
+}
+
+/// Herfindahl-Hirschman index as well as the top 1 and top 3 shares
+/// of the given amounts. Returns NaN if the total is not positive
+fn concentration(amounts: impl Iterator<Item = f64>) -> (f64, f64, f64)
+{
+    let sorted = amounts
+        .sorted_unstable_by_key(|a| Reverse(OrderedFloat(*a)))
+        .collect_vec();
+    let total: f64 = sorted.iter().sum();
+    if total <= 0.0 {
+        return (f64::NAN, f64::NAN, f64::NAN);
+    }
+    let recip = total.recip();
+    let hhi = sorted.iter()
+        .map(|a| (a * recip).powi(2))
+        .sum();
+    let top1 = sorted[0] * recip;
+    let top3 = sorted.iter()
+        .take(3)
+        .sum::<f64>() * recip;
+    (hhi, top1, top3)
+}
+
+/// Import dependency and self sufficiency, both relative to the domestic supply
+/// imports + production - exports. Returns NaN if the domestic supply is not positive,
+/// which happens for countries that re-export more than they produce
+fn dependency(imports: f64, exports: f64, production: f64) -> (f64, f64)
+{
+    let supply = imports + production - exports;
+    if supply > 0.0 {
+        (imports / supply, production / supply)
+    } else {
+        (f64::NAN, f64::NAN)
+    }
+}
+
+pub fn dependency_indices(opt: DependencyIndicesOpts)
+{
+    let network_files = get_files(&opt.network_glob);
+    let enrich_files = get_files(&opt.enrich_glob);
+
+    let header = [
+        "item",
+        "year",
+        "country",
+        "imports",
+        "exports",
+        "production",
+        "supplier_hhi",
+        "top1_share",
+        "top3_share",
+        "import_dependency",
+        "self_sufficiency",
+        "export_market_share",
+        "exporter_hhi"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(&opt.out, header);
+    let unit_tester = UNIT_TESTER.deref();
+
+    for (item, network_path) in network_files.iter(){
+        let enrich_path = match enrich_files.get(item){
+            Some(path) => path,
+            None => {
+                println!("No enrichment for item {item} - SKIPPING ITEM");
+                continue;
+            }
+        };
+        let mut lazy_networks = LazyNetworks::Filename(network_path.clone());
+        lazy_networks.assure_availability();
+        let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+            enrich_path.to_string(),
+            Some(item.to_string())
+        );
+        lazy_enrichments.assure_availability();
+        let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+        let production_id = lazy_enrichments
+            .extra_info_idmap_unchecked()
+            .get(PRODUCTION);
+
+        for export in lazy_networks.export_networks_unchecked(){
+            let year = export.year;
+            let export = export.without_unconnected_nodes();
+            let import = export.invert();
+            let original_exports = calc_acc_trade(&export);
+            let original_imports = calc_acc_trade(&import);
+
+            let enrich = usize::try_from(year - enrichment_infos.starting_year)
+                .ok()
+                .and_then(|idx| enrichment_infos.enrichments.get(idx));
+            if enrich.is_none(){
+                println!("Item {item}: no enrichment for year {year}");
+            }
+
+            let total_export: f64 = original_exports.iter().sum();
+            let (exporter_hhi, _, _) = concentration(original_exports.iter().copied());
+
+            let rows: BTreeMap<&str, CountryIndices> = import.nodes
+                .iter()
+                .enumerate()
+                .map(
+                    |(idx, node)|
+                    {
+                        let imports = original_imports[idx];
+                        let exports = original_exports[idx];
+                        let production = enrich
+                            .and_then(|e| e.get(node.identifier.as_str()))
+                            .and_then(|extra| extra.map.get(&production_id))
+                            .map_or(
+                                f64::NAN,
+                                |p|
+                                {
+                                    assert!(
+                                        unit_tester.is_equiv(&p.unit, &export.unit),
+                                        "incompatible units"
+                                    );
+                                    p.amount
+                                }
+                            );
+                        let (supplier_hhi, top1_share, top3_share) = concentration(
+                            node.adj.iter().map(|e| e.amount)
+                        );
+                        let (import_dependency, self_sufficiency) = dependency(imports, exports, production);
+                        let indices = CountryIndices{
+                            imports,
+                            exports,
+                            production,
+                            supplier_hhi,
+                            top1_share,
+                            top3_share,
+                            import_dependency,
+                            self_sufficiency,
+                            export_market_share: exports / total_export
+                        };
+                        (node.identifier.as_str(), indices)
+                    }
+                ).collect();
+
+            for (country, c) in rows{
+                writeln!(
+                    buf,
+                    "{item} {year} {country} {:e} {:e} {:e} {} {} {} {} {} {} {exporter_hhi}",
+                    c.imports,
+                    c.exports,
+                    c.production,
+                    c.supplier_hhi,
+                    c.top1_share,
+                    c.top3_share,
+                    c.import_dependency,
+                    c.self_sufficiency,
+                    c.export_market_share
+                ).unwrap();
+            }
+        }
+    }
+}
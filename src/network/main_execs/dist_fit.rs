@@ -0,0 +1,622 @@
+use std::{
+    f64::consts::{PI, SQRT_2},
+    io::Write
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use crate::{
+    misc::*,
+    network::*
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct DistFitOpts{
+    /// Path to network
+    #[arg(long, short)]
+    pub network_file: Utf8PathBuf,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "fit")]
+    pub out_stub: String,
+
+    /// Number of bootstrap samples for the goodness of fit p-value of the power law.
+    /// 0 skips the bootstrap
+    #[arg(long, short, default_value_t = 100)]
+    pub bootstrap: usize,
+
+    /// Seed for the bootstrap
+    #[arg(long, short, default_value_t = 1294)]
+    pub seed: u64,
+
+    /// Bins per decade for the log-binned histograms
+    #[arg(long, default_value_t = 10)]
+    pub bins_per_decade: usize,
+
+    /// Minimal number of data points above xmin
+    #[arg(long, default_value_t = 10)]
+    pub min_tail: usize,
+
+    /// At most this many xmin candidates are scanned
+    #[arg(long, default_value_t = 100)]
+    pub xmin_candidates: usize
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Quantity{
+    InDegree,
+    OutDegree,
+    InStrength,
+    OutStrength,
+    Weight
+}
+
+impl Quantity{
+    const ALL: [Quantity; 5] = [
+        Quantity::InDegree,
+        Quantity::OutDegree,
+        Quantity::InStrength,
+        Quantity::OutStrength,
+        Quantity::Weight
+    ];
+
+    fn name(self) -> &'static str
+    {
+        match self{
+            Self::InDegree => "in_degree",
+            Self::OutDegree => "out_degree",
+            Self::InStrength => "in_strength",
+            Self::OutStrength => "out_strength",
+            Self::Weight => "weight"
+        }
+    }
+
+    /// Only positive values are returned, sorted in ascending order.
+    /// Degrees are treated with the continuous approximation
+    fn data(self, export: &Network, import: &Network) -> Vec<f64>
+    {
+        let data: Vec<f64> = match self{
+            Self::InDegree => import.nodes.iter().map(|n| n.adj.len() as f64).collect(),
+            Self::OutDegree => export.nodes.iter().map(|n| n.adj.len() as f64).collect(),
+            Self::InStrength => import.nodes.iter().map(Node::trade_amount).collect(),
+            Self::OutStrength => export.nodes.iter().map(Node::trade_amount).collect(),
+            Self::Weight => {
+                export.nodes
+                    .iter()
+                    .flat_map(|n| n.adj.iter().map(|e| e.amount))
+                    .collect()
+            }
+        };
+        data.into_iter()
+            .filter(|x| *x > 0.0)
+            .sorted_unstable_by(f64::total_cmp)
+            .collect()
+    }
+}
+
+/// Natural logarithm of the complementary error function.
+/// Uses the Chebyshev approximation from Numerical Recipes,
+/// fractional error below 1.2e-7 everywhere
+fn ln_erfc(x: f64) -> f64
+{
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418
+        + t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587
+        + t * (-0.82215223 + t * 0.17087277))))))));
+    let ln_erfc_abs = t.ln() + poly;
+    if x >= 0.0 {
+        ln_erfc_abs
+    } else {
+        (2.0 - ln_erfc_abs.exp()).ln()
+    }
+}
+
+/// Minimizes f with the Nelder-Mead simplex algorithm. NaN is treated as infinity
+fn nelder_mead<F>(f: F, start: &[f64], step: f64, max_iter: usize) -> (Vec<f64>, f64)
+where F: Fn(&[f64]) -> f64
+{
+    let eval = |x: &[f64]| {
+        let v = f(x);
+        if v.is_nan() { f64::INFINITY } else { v }
+    };
+    let dim = start.len();
+    let mut simplex = vec![start.to_vec()];
+    for i in 0..dim{
+        let mut p = start.to_vec();
+        p[i] += step;
+        simplex.push(p);
+    }
+    let mut values = simplex.iter()
+        .map(|p| eval(p))
+        .collect_vec();
+
+    let along = |from: &[f64], to: &[f64], factor: f64| -> Vec<f64> {
+        from.iter()
+            .zip(to)
+            .map(|(a, b)| a + factor * (b - a))
+            .collect()
+    };
+
+    for _ in 0..max_iter{
+        let order = (0..=dim)
+            .sorted_by(|a, b| values[*a].total_cmp(&values[*b]))
+            .collect_vec();
+        simplex = order.iter().map(|&i| simplex[i].clone()).collect();
+        values = order.iter().map(|&i| values[i]).collect();
+        let best = values[0];
+        let worst = values[dim];
+        if (worst - best).abs() <= 1e-12 * (best.abs() + 1e-12) {
+            break;
+        }
+        let mut centroid = vec![0.0; dim];
+        for p in simplex[..dim].iter(){
+            for (c, v) in centroid.iter_mut().zip(p){
+                *c += v / dim as f64;
+            }
+        }
+        let reflected = along(&centroid, &simplex[dim], -1.0);
+        let f_reflected = eval(&reflected);
+        if f_reflected < best {
+            let expanded = along(&centroid, &simplex[dim], -2.0);
+            let f_expanded = eval(&expanded);
+            if f_expanded < f_reflected {
+                simplex[dim] = expanded;
+                values[dim] = f_expanded;
+            } else {
+                simplex[dim] = reflected;
+                values[dim] = f_reflected;
+            }
+            continue;
+        }
+        if f_reflected < values[dim - 1] {
+            simplex[dim] = reflected;
+            values[dim] = f_reflected;
+            continue;
+        }
+        let (contracted, f_limit) = if f_reflected < worst {
+            (along(&centroid, &reflected, 0.5), f_reflected)
+        } else {
+            (along(&centroid, &simplex[dim], 0.5), worst)
+        };
+        let f_contracted = eval(&contracted);
+        if f_contracted < f_limit {
+            simplex[dim] = contracted;
+            values[dim] = f_contracted;
+            continue;
+        }
+        // shrink towards best point
+        #[allow(clippy::needless_range_loop)]
+        for i in 1..=dim{
+            simplex[i] = along(&simplex[0], &simplex[i], 0.5);
+            values[i] = eval(&simplex[i]);
+        }
+    }
+    let best = (0..=dim)
+        .min_by(|a, b| values[*a].total_cmp(&values[*b]))
+        .unwrap();
+    (simplex[best].clone(), values[best])
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PowerLawFit{
+    pub xmin: f64,
+    pub alpha: f64,
+    pub ks: f64,
+    pub n_tail: usize
+}
+
+/// Continuous power law MLE for fixed xmin. `tail` has to be sorted
+/// and only contain values >= xmin
+fn power_law_fixed_xmin(tail: &[f64], xmin: f64) -> Option<PowerLawFit>
+{
+    let n = tail.len();
+    let log_sum: f64 = tail.iter()
+        .map(|x| (x / xmin).ln())
+        .sum();
+    if n == 0 || log_sum <= 0.0 {
+        return None;
+    }
+    let alpha = 1.0 + n as f64 / log_sum;
+    let n_recip = (n as f64).recip();
+    let ks = tail.iter()
+        .enumerate()
+        .map(
+            |(i, x)|
+            {
+                let cdf = 1.0 - (x / xmin).powf(1.0 - alpha);
+                let below = (cdf - i as f64 * n_recip).abs();
+                let above = (cdf - (i + 1) as f64 * n_recip).abs();
+                below.max(above)
+            }
+        ).fold(0.0, f64::max);
+    Some(PowerLawFit { xmin, alpha, ks, n_tail: n })
+}
+
+/// Clauset-Shalizi-Newman: choose xmin by minimizing the KS distance.
+/// `sorted` needs to be sorted in ascending order
+pub fn fit_power_law(sorted: &[f64], min_tail: usize, max_candidates: usize) -> Option<PowerLawFit>
+{
+    if sorted.len() < min_tail.max(2) {
+        return None;
+    }
+    let last_allowed = sorted.len() - min_tail.max(2);
+    let candidates = sorted[..=last_allowed]
+        .iter()
+        .copied()
+        .dedup()
+        .collect_vec();
+    let stride = candidates.len().div_ceil(max_candidates.max(1)).max(1);
+    candidates.into_iter()
+        .step_by(stride)
+        .filter_map(
+            |xmin|
+            {
+                let start = sorted.partition_point(|x| *x < xmin);
+                power_law_fixed_xmin(&sorted[start..], xmin)
+            }
+        ).min_by(|a, b| a.ks.total_cmp(&b.ks))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Candidate{
+    PowerLaw{alpha: f64},
+    LogNormal{mu: f64, sigma: f64},
+    StretchedExp{beta: f64, lambda: f64},
+    TruncatedPowerLaw{alpha: f64, lambda: f64, ln_norm: f64}
+}
+
+impl Candidate{
+    /// Log of the probability density, normalized on [xmin, infinity)
+    fn ln_pdf(&self, x: f64, xmin: f64) -> f64
+    {
+        match *self{
+            Self::PowerLaw { alpha } => {
+                (alpha - 1.0).ln() - xmin.ln() - alpha * (x / xmin).ln()
+            },
+            Self::LogNormal { mu, sigma } => {
+                let ln_x = x.ln();
+                let norm = (0.5_f64).ln() + ln_erfc((xmin.ln() - mu) / (sigma * SQRT_2));
+                -ln_x - sigma.ln() - 0.5 * (2.0 * PI).ln()
+                    - (ln_x - mu).powi(2) / (2.0 * sigma * sigma)
+                    - norm
+            },
+            Self::StretchedExp { beta, lambda } => {
+                beta.ln() + lambda.ln() + (beta - 1.0) * x.ln()
+                    - lambda * (x.powf(beta) - xmin.powf(beta))
+            },
+            Self::TruncatedPowerLaw { alpha, lambda, ln_norm } => {
+                -alpha * x.ln() - lambda * x - ln_norm
+            }
+        }
+    }
+
+    fn ln_likelihood(&self, tail: &[f64], xmin: f64) -> f64
+    {
+        tail.iter()
+            .map(|x| self.ln_pdf(*x, xmin))
+            .sum()
+    }
+
+    fn params(&self) -> [f64; 2]
+    {
+        match *self{
+            Self::PowerLaw { alpha } => [alpha, f64::NAN],
+            Self::LogNormal { mu, sigma } => [mu, sigma],
+            Self::StretchedExp { beta, lambda } => [beta, lambda],
+            Self::TruncatedPowerLaw { alpha, lambda, .. } => [alpha, lambda]
+        }
+    }
+}
+
+fn fit_log_normal(tail: &[f64], xmin: f64) -> Candidate
+{
+    let n = tail.len() as f64;
+    let mean = tail.iter().map(|x| x.ln()).sum::<f64>() / n;
+    let var = tail.iter().map(|x| (x.ln() - mean).powi(2)).sum::<f64>() / n;
+    let start = [mean, var.sqrt().max(1e-3).ln()];
+    let (best, _) = nelder_mead(
+        |p| -Candidate::LogNormal { mu: p[0], sigma: p[1].exp() }.ln_likelihood(tail, xmin),
+        &start,
+        0.5,
+        2000
+    );
+    Candidate::LogNormal { mu: best[0], sigma: best[1].exp() }
+}
+
+fn fit_stretched_exp(tail: &[f64], xmin: f64) -> Candidate
+{
+    let n = tail.len() as f64;
+    let log_sum: f64 = tail.iter().map(|x| x.ln()).sum();
+    // lambda can be calculated analytically for given beta
+    let lambda_of = |beta: f64| {
+        let s: f64 = tail.iter()
+            .map(|x| x.powf(beta) - xmin.powf(beta))
+            .sum();
+        n / s
+    };
+    let profile = |ln_beta: f64| {
+        let beta = ln_beta.exp();
+        let lambda = lambda_of(beta);
+        let ll = n * beta.ln() + n * lambda.ln() + (beta - 1.0) * log_sum - n;
+        if ll.is_finite() { ll } else { f64::NEG_INFINITY }
+    };
+    // golden section search
+    let golden = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut a, mut b) = (0.01_f64.ln(), 5.0_f64.ln());
+    for _ in 0..100{
+        let c = b - golden * (b - a);
+        let d = a + golden * (b - a);
+        if profile(c) > profile(d) {
+            b = d;
+        } else {
+            a = c;
+        }
+    }
+    let beta = ((a + b) / 2.0).exp();
+    Candidate::StretchedExp { beta, lambda: lambda_of(beta) }
+}
+
+/// ln of the integral of x^-alpha exp(-lambda x) from xmin to infinity
+fn ln_truncated_norm(alpha: f64, lambda: f64, xmin: f64) -> f64
+{
+    const STEPS: usize = 2000;
+    let c = lambda * xmin;
+    let mut upper = (50.0 / c).max(1.0).ln() + 3.0;
+    if alpha > 1.0 {
+        upper = upper.min(50.0 / (alpha - 1.0));
+    }
+    let du = upper / STEPS as f64;
+    // substitution x = xmin * exp(u)
+    let exponents = (0..=STEPS)
+        .map(
+            |k|
+            {
+                let u = k as f64 * du;
+                (1.0 - alpha) * u - c * u.exp()
+            }
+        ).collect_vec();
+    let max = exponents.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let simpson: f64 = exponents.iter()
+        .enumerate()
+        .map(
+            |(k, e)|
+            {
+                let weight = if k == 0 || k == STEPS {
+                    1.0
+                } else if k % 2 == 1 {
+                    4.0
+                } else {
+                    2.0
+                };
+                weight * (e - max).exp()
+            }
+        ).sum::<f64>() * du / 3.0;
+    (1.0 - alpha) * xmin.ln() + max + simpson.ln()
+}
+
+fn fit_truncated_power_law(tail: &[f64], xmin: f64, alpha_guess: f64) -> Candidate
+{
+    let candidate = |p: &[f64]| {
+        let lambda = p[1].exp();
+        Candidate::TruncatedPowerLaw {
+            alpha: p[0],
+            lambda,
+            ln_norm: ln_truncated_norm(p[0], lambda, xmin)
+        }
+    };
+    let mean = tail.iter().sum::<f64>() / tail.len() as f64;
+    let start = [alpha_guess, (0.01 / mean).ln()];
+    let (best, _) = nelder_mead(
+        |p| -candidate(p).ln_likelihood(tail, xmin),
+        &start,
+        0.5,
+        2000
+    );
+    candidate(&best)
+}
+
+/// Vuong test of the power law against an alternative.
+/// Returns the log likelihood ratio R and the p-value.
+/// Positive R favors the power law
+fn vuong(tail: &[f64], xmin: f64, power_law: &Candidate, alternative: &Candidate) -> (f64, f64)
+{
+    let diff = tail.iter()
+        .map(|x| power_law.ln_pdf(*x, xmin) - alternative.ln_pdf(*x, xmin))
+        .collect_vec();
+    let n = diff.len() as f64;
+    let ratio: f64 = diff.iter().sum();
+    let mean = ratio / n;
+    let var = diff.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / n;
+    if var <= 0.0 {
+        return (ratio, 1.0);
+    }
+    let z = ratio / (n * var).sqrt();
+    let p = ln_erfc(z.abs() / SQRT_2).exp();
+    (ratio, p)
+}
+
+/// Semi-parametric bootstrap as proposed by Clauset, Shalizi and Newman
+fn bootstrap_p_value<R>(
+    sorted: &[f64],
+    fit: &PowerLawFit,
+    opt: &DistFitOpts,
+    rng: &mut R
+) -> f64
+where R: Rng
+{
+    if opt.bootstrap == 0 {
+        return f64::NAN;
+    }
+    let below = &sorted[..sorted.len() - fit.n_tail];
+    let tail_prob = fit.n_tail as f64 / sorted.len() as f64;
+    let exponent = -(fit.alpha - 1.0).recip();
+    let mut worse = 0;
+    for _ in 0..opt.bootstrap{
+        let synthetic = (0..sorted.len())
+            .map(
+                |_|
+                {
+                    if below.is_empty() || rng.gen::<f64>() < tail_prob {
+                        fit.xmin * (1.0 - rng.gen::<f64>()).powf(exponent)
+                    } else {
+                        below[rng.gen_range(0..below.len())]
+                    }
+                }
+            ).sorted_unstable_by(f64::total_cmp)
+            .collect_vec();
+        if let Some(f) = fit_power_law(&synthetic, opt.min_tail, opt.xmin_candidates){
+            if f.ks >= fit.ks {
+                worse += 1;
+            }
+        }
+    }
+    worse as f64 / opt.bootstrap as f64
+}
+
+fn write_log_binned<W: Write>(mut buf: W, sorted: &[f64], bins_per_decade: usize)
+{
+    let header = [
+        "bin_left",
+        "bin_right",
+        "bin_center_geometric",
+        "hits",
+        "density"
+    ];
+    write_slice_head(&mut buf, header).unwrap();
+    let (first, last) = match (sorted.first(), sorted.last()){
+        (Some(f), Some(l)) => (*f, *l),
+        _ => return
+    };
+    let factor = 10.0_f64.powf((bins_per_decade.max(1) as f64).recip());
+    let total = sorted.len() as f64;
+    let mut left = first;
+    let mut start = 0;
+    while start < sorted.len(){
+        let right = left * factor;
+        let end = if right > last {
+            sorted.len()
+        } else {
+            sorted.partition_point(|x| *x < right)
+        };
+        let hits = end - start;
+        let density = hits as f64 / (total * (right - left));
+        let center = (left * right).sqrt();
+        writeln!(buf, "{left:e} {right:e} {center:e} {hits} {density:e}").unwrap();
+        left = right;
+        start = end;
+    }
+}
+
+pub fn dist_fit(opt: DistFitOpts)
+{
+    let mut lazy_networks = LazyNetworks::Filename(opt.network_file.clone());
+    lazy_networks.assure_availability();
+
+    let mut rng = Pcg64::seed_from_u64(opt.seed);
+    let jobs = lazy_networks.export_networks_unchecked()
+        .iter()
+        .flat_map(|n| Quantity::ALL.into_iter().map(move |q| (n, q)))
+        .map(|(n, q)| (n, q, Pcg64::from_rng(&mut rng).unwrap()))
+        .collect_vec();
+
+    let lines: Vec<_> = jobs.into_par_iter()
+        .map(
+            |(export, quantity, mut rng)|
+            {
+                let year = export.year;
+                let export = export.without_unconnected_nodes();
+                let import = export.invert();
+                let sorted = quantity.data(&export, &import);
+
+                let name = format!("{}_{}_Y{year}.logbin", opt.out_stub, quantity.name());
+                let buf = create_buf_with_command_and_version(name);
+                write_log_binned(buf, &sorted, opt.bins_per_decade);
+
+                let fit = match fit_power_law(&sorted, opt.min_tail, opt.xmin_candidates){
+                    Some(fit) => fit,
+                    None => {
+                        println!("Year {year}: not enough data for {}", quantity.name());
+                        return (quantity.name(), year, None);
+                    }
+                };
+                let tail = &sorted[sorted.len() - fit.n_tail..];
+                let p_value = bootstrap_p_value(&sorted, &fit, &opt, &mut rng);
+                let power_law = Candidate::PowerLaw { alpha: fit.alpha };
+                let alternatives = [
+                    fit_log_normal(tail, fit.xmin),
+                    fit_stretched_exp(tail, fit.xmin),
+                    fit_truncated_power_law(tail, fit.xmin, fit.alpha)
+                ];
+                let mut line = format!(
+                    "{year} {} {} {:e} {} {} {}",
+                    sorted.len(),
+                    fit.n_tail,
+                    fit.xmin,
+                    fit.alpha,
+                    fit.ks,
+                    p_value
+                );
+                for alternative in alternatives.iter(){
+                    let [a, b] = alternative.params();
+                    let (ratio, p) = vuong(tail, fit.xmin, &power_law, alternative);
+                    line.push_str(&format!(" {a:e} {b:e} {ratio} {p}"));
+                }
+                (quantity.name(), year, Some(line))
+            }
+        ).collect();
+
+    let header = [
+        "year",
+        "n",
+        "n_tail",
+        "xmin",
+        "alpha",
+        "ks",
+        "bootstrap_p",
+        "lognormal_mu",
+        "lognormal_sigma",
+        "R_vs_lognormal",
+        "p_vs_lognormal",
+        "stretched_beta",
+        "stretched_lambda",
+        "R_vs_stretched",
+        "p_vs_stretched",
+        "truncated_alpha",
+        "truncated_lambda",
+        "R_vs_truncated",
+        "p_vs_truncated"
+    ];
+    for quantity in Quantity::ALL{
+        let name = format!("{}_{}.fits", opt.out_stub, quantity.name());
+        let mut buf = create_buf_with_command_and_version_and_header(name, header);
+        writeln!(buf, "# R > 0 favors the power law, p is the significance of the sign of R").unwrap();
+        for (_, year, line) in lines.iter().filter(|(q, _, _)| *q == quantity.name()){
+            match line{
+                Some(l) => writeln!(buf, "{l}").unwrap(),
+                None => writeln!(buf, "# {year}: not enough data").unwrap()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_law_recovers_exponent() {
+        let mut rng = Pcg64::seed_from_u64(8923);
+        let alpha = 2.5;
+        let data = (0..5000)
+            .map(|_| (1.0 - rng.gen::<f64>()).powf(-1.0 / (alpha - 1.0)))
+            .sorted_unstable_by(f64::total_cmp)
+            .collect_vec();
+        let fit = power_law_fixed_xmin(&data, 1.0).unwrap();
+        assert!((fit.alpha - alpha).abs() < 0.1);
+        assert!(fit.ks < 0.05);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::{Deref, RangeInclusive}
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *},
+    UNIT_TESTER
+};
+use super::{
+    flow_helper::*,
+    population::{Population, PopulationImpact},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    production_vec,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct DynamicShockOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "dynamic")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic or with_stock_variation.
+    /// Stocks are handled by the dynamic model itself, so only_stock is not allowed
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct DynamicShock{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    /// Consecutive years that are simulated
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// First year of the disruption
+    #[derivative(Default(value = "2005"))]
+    pub shock_start: i32,
+
+    /// Number of years the disruption stays at full strength
+    #[derivative(Default(value = "1"))]
+    pub persistence: u32,
+
+    /// After the persistent phase the missing fraction is multiplied
+    /// by this factor every year. 0 means immediate recovery
+    #[derivative(Default(value = "0.5"))]
+    pub decay: f64,
+
+    /// Remaining fraction of exports or production of the
+    /// disrupted countries at full strength
+    pub shock_frac: f64,
+
+    /// Restrict the exports or reduce the production of the countries
+    pub shock_type: ShockType,
+
+    /// Ids of the disrupted countries. If empty, the top exporters
+    /// of the year the shock starts are used
+    pub countries: Vec<String>,
+
+    /// How many top exporters are disrupted if no countries are given
+    #[derivative(Default(value = "5"))]
+    pub top: usize,
+
+    /// Fraction of the missing stock that is refilled every year
+    #[derivative(Default(value = "0.25"))]
+    pub restock_rate: f64,
+
+    /// Maximal number of iterations
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// A country counts as recovered once its relative availability
+    /// stays above this value
+    #[derivative(Default(value = "0.99"))]
+    pub recovery_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock are not counted as unstable
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64,
+
+    /// Also report the number of affected people and population weighted losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[serde(default)]
+    pub population: bool
+}
+
+impl DynamicShock{
+    /// Remaining fraction of the disrupted countries in the given year
+    pub fn remaining_frac(&self, year: i32) -> f64
+    {
+        if year < self.shock_start {
+            return 1.0;
+        }
+        let missing = 1.0 - self.shock_frac;
+        let full_until = self.shock_start + self.persistence as i32;
+        if year < full_until {
+            1.0 - missing
+        } else {
+            let decay_years = year - full_until + 1;
+            1.0 - missing * self.decay.powi(decay_years)
+        }
+    }
+}
+
+/// State of one country in one year
+struct CountryYear{
+    year: i32,
+    baseline: f64,
+    after_shock: f64,
+    stock_draw: f64,
+    stock_deficit: f64
+}
+
+impl CountryYear{
+    fn available(&self) -> f64
+    {
+        self.after_shock + self.stock_draw
+    }
+
+    fn relative(&self) -> f64
+    {
+        self.available() / self.baseline
+    }
+}
+
+pub fn dynamic_shock(opt: DynamicShockOpts)
+{
+    assert!(
+        opt.mode != SimulationMode::OnlyStock,
+        "The dynamic simulation handles stocks itself, only_stock would count them twice"
+    );
+    let json: DynamicShock = parse_and_add_to_global(opt.json);
+    assert!(
+        (0.0..=1.0).contains(&json.shock_frac),
+        "shock_frac has to be in range 0.0..=1.0"
+    );
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&node_map);
+    let unit_tester = UNIT_TESTER.deref();
+
+    let countries = if json.countries.is_empty(){
+        let export = lazy_networks
+            .get_export_network_unchecked(json.shock_start)
+            .without_unconnected_nodes();
+        get_top_k_ids(&export, json.top)
+            .into_iter()
+            .map(|idx| export.nodes[idx].identifier.clone())
+            .collect_vec()
+    } else {
+        json.countries.clone()
+    };
+
+    let mut stock_deficit: BTreeMap<String, f64> = BTreeMap::new();
+    let mut trajectories: BTreeMap<String, Vec<CountryYear>> = BTreeMap::new();
+
+    let mut header = vec![
+        "year",
+        "remaining_frac",
+        "unstable_countries",
+        "iterations",
+        "residual"
+    ];
+    if json.population{
+        header.extend(PopulationImpact::HEADER);
+    }
+    let mut year_buf = create_buf_with_command_and_version_and_header(
+        format!("{}.years", opt.out_stub),
+        &header
+    );
+    writeln!(year_buf, "# disrupted {}", countries.join(",")).unwrap();
+
+    for year in json.years.clone(){
+        let export = lazy_networks
+            .get_export_network_unchecked(year)
+            .without_unconnected_nodes();
+        let import = export.invert();
+        let enrich = enrichment_infos.get_year(year);
+
+        let ids = countries.iter()
+            .filter_map(|c| export.get_index(c))
+            .sorted_unstable()
+            .collect_vec();
+
+        let original_exports = calc_acc_trade(&export);
+        let original_exports_recip = calc_recip(&original_exports);
+        let original_imports = calc_acc_trade(&import);
+        let original_imports_recip = calc_recip(&original_imports);
+        let production = production_vec(&export, enrich, &ctx);
+
+        let remaining = json.remaining_frac(year);
+        let no_shock = ShockRes::no_shock(export.node_count());
+        let shock_result = if remaining >= 1.0 || ids.is_empty() {
+            ShockRes::no_shock(export.node_count())
+        } else {
+            let job = match json.shock_type{
+                ShockType::ExportRestriction => {
+                    CalcShockMultiJob::new_const_export(
+                        &ids,
+                        remaining,
+                        json.iterations,
+                        &export,
+                        &original_exports,
+                        &original_exports_recip,
+                        &original_imports,
+                        &original_imports_recip
+                    )
+                },
+                ShockType::Production => {
+                    CalcShockMultiJob::new_const_production(
+                        &ids,
+                        remaining,
+                        &production,
+                        json.iterations,
+                        &export,
+                        &original_exports,
+                        &original_exports_recip,
+                        &original_imports,
+                        &original_imports_recip
+                    )
+                }
+            }.with_tolerance(json.tolerance);
+            let res = multi_shock_distribution(&import, &job);
+            res.convergence.warn_if_not_converged("dynamic shock");
+            res
+        };
+
+        let (baseline, _) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+        let (after_shock, _) = calc_available(&export, enrich, &shock_result, &ctx, opt.quiet);
+
+        let mut unstable = 0;
+        let mut counted = Vec::new();
+        let mut available = vec![0.0; export.node_count()];
+        for (idx, node) in export.nodes.iter().enumerate(){
+            let id = node.identifier.as_str();
+            let reported_stock = enrich.get(id)
+                .and_then(|e| e.map.get(&ctx.availability.stock_id))
+                .map_or(
+                    0.0,
+                    |s|
+                    {
+                        assert!(unit_tester.is_equiv(&s.unit, &export.unit));
+                        s.amount.max(0.0)
+                    }
+                );
+            let deficit = stock_deficit.entry(id.to_owned()).or_insert(0.0);
+            let opening_stock = (reported_stock - *deficit).max(0.0);
+            let missing = (baseline[idx] - after_shock[idx]).max(0.0);
+            let stock_draw = missing.min(opening_stock);
+            *deficit = (*deficit + stock_draw) * (1.0 - json.restock_rate);
+
+            let state = CountryYear{
+                year,
+                baseline: baseline[idx],
+                after_shock: after_shock[idx],
+                stock_draw,
+                stock_deficit: *deficit
+            };
+            available[idx] = state.available();
+            if !ids.contains(&idx) && state.baseline >= json.original_avail_filter {
+                counted.push(idx);
+                if state.relative() < json.unstable_country_threshold {
+                    unstable += 1;
+                }
+            }
+            trajectories.entry(id.to_owned())
+                .or_default()
+                .push(state);
+        }
+        write!(
+            year_buf,
+            "{year} {remaining} {unstable} {} {:e}",
+            shock_result.convergence.iterations,
+            shock_result.convergence.residual
+        ).unwrap();
+        if json.population{
+            let population = Population::new(&export, enrich, &node_map);
+            population.write_missing(
+                format!("{}_Y{year}.missing_population", opt.out_stub),
+                &export,
+                &counted
+            );
+            let buf = create_buf_with_command_and_version_and_header(
+                format!("{}_Y{year}.per_capita", opt.out_stub),
+                Population::per_capita_header(&export.unit)
+            );
+            population.write_per_capita(buf, &export, &counted, &baseline, &available)
+                .unwrap();
+            population.impact(&counted, &baseline, &available, json.unstable_country_threshold)
+                .write(&mut year_buf)
+                .unwrap();
+        }
+        writeln!(year_buf).unwrap();
+    }
+
+    let header = [
+        "country",
+        "year",
+        "baseline",
+        "after_shock",
+        "stock_draw",
+        "stock_deficit",
+        "available",
+        "relative_availability"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(
+        format!("{}.trajectories", opt.out_stub),
+        header
+    );
+    for (country, states) in trajectories.iter(){
+        for s in states{
+            writeln!(
+                buf,
+                "{country} {} {:e} {:e} {:e} {:e} {:e} {}",
+                s.year,
+                s.baseline,
+                s.after_shock,
+                s.stock_draw,
+                s.stock_deficit,
+                s.available(),
+                s.relative()
+            ).unwrap();
+        }
+    }
+
+    // recovery year: first year from which on the country stays above the recovery threshold
+    let header = [
+        "country",
+        "min_relative_availability",
+        "year_of_min",
+        "recovery_year",
+        "recovery_time"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(
+        format!("{}.recovery", opt.out_stub),
+        header
+    );
+    for (country, states) in trajectories.iter(){
+        let relevant = states.iter()
+            .filter(|s| s.year >= json.shock_start && s.baseline >= json.original_avail_filter)
+            .collect_vec();
+        let min = match relevant.iter().min_by(|a, b| a.relative().total_cmp(&b.relative())){
+            Some(m) => m,
+            None => continue
+        };
+        if min.relative() >= json.recovery_threshold {
+            // never affected
+            continue;
+        }
+        let recovery_year = relevant.iter()
+            .rposition(|s| s.relative() < json.recovery_threshold)
+            .and_then(|pos| relevant.get(pos + 1))
+            .map(|s| s.year);
+        match recovery_year{
+            Some(y) => {
+                writeln!(
+                    buf,
+                    "{country} {} {} {y} {}",
+                    min.relative(),
+                    min.year,
+                    y - json.shock_start
+                ).unwrap();
+            },
+            None => {
+                writeln!(
+                    buf,
+                    "{country} {} {} NaN NaN",
+                    min.relative(),
+                    min.year
+                ).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shock_schedule() {
+        let json = DynamicShock{
+            shock_start: 2000,
+            persistence: 2,
+            decay: 0.5,
+            shock_frac: 0.2,
+            ..Default::default()
+        };
+        assert_eq!(json.remaining_frac(1999), 1.0);
+        assert!((json.remaining_frac(2000) - 0.2).abs() < 1e-12);
+        assert!((json.remaining_frac(2001) - 0.2).abs() < 1e-12);
+        assert!((json.remaining_frac(2002) - 0.6).abs() < 1e-12);
+        assert!((json.remaining_frac(2003) - 0.8).abs() < 1e-12);
+    }
+}
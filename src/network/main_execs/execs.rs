@@ -304,7 +304,7 @@ fn degree_dists_helper(networks: &[Network], out: &str)
         ).max().unwrap();
 
     let mul = (max_degree+1).next_multiple_of(2) - 1;
-    let hist = HistUsize::new_inclusive(0, mul, (mul+1)/2)
+    let hist = HistUsize::new_inclusive(0, mul, (mul+1).div_ceil(2))
         .unwrap();
     //let hist = HistUsizeFast::new_inclusive(0, max_degree).unwrap();
 
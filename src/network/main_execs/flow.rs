@@ -1,5 +1,5 @@
 use{
-    super::flow_helper::*, crate::{
+    super::{cloud_stats::*, flow_helper::*, ledger::Ledger, population::{Population, PopulationImpact}, rerouting::{self, Adaptation}, samplers::PointSource, shock_solver::{self, SolverBackend}}, crate::{
         config::*, group_cmp::{GroupCompMultiOpts, X}, misc::*, network::{enriched_digraph::*, *}, parser::country_map, sync_queue, UNIT_TESTER
     }, camino::{Utf8Path, Utf8PathBuf}, clap::ValueEnum, derivative::Derivative, fs_err::File, itertools::Itertools, kahan::KahanSum, ordered_float::OrderedFloat, rand::{distributions::{Distribution, Uniform}, seq::SliceRandom, Rng, SeedableRng}, rand_pcg::Pcg64, rayon::prelude::*, sampling::{
         HistF64, 
@@ -12,7 +12,7 @@ use{
             AddAssign, 
             Deref, 
             RangeInclusive
-        }, path::Path, str::FromStr, sync::{Mutex, RwLock}
+        }, path::Path, str::FromStr, sync::Mutex
     }
 };
 
@@ -48,27 +48,94 @@ impl FromStr for SimulationMode{
     }
 }
 
-// Not pretty, but this was the easiest way to retrofit it in
-static MODE: RwLock<SimulationMode> = RwLock::new(SimulationMode::Classic);
+impl SimulationMode{
+    pub const ALL: [SimulationMode; 3] = [
+        SimulationMode::Classic,
+        SimulationMode::WithStockVariation,
+        SimulationMode::OnlyStock
+    ];
 
-fn global_simulation_mode_as_str() -> &'static str
-{
-    let lock = MODE.read().unwrap();
-    let s = match lock.deref()
+    pub fn as_str(self) -> &'static str
     {
-        SimulationMode::Classic => "CLASSIC",
-        SimulationMode::WithStockVariation => "W_Stock_Variation",
-        SimulationMode::OnlyStock => "OnlyStock",
-    };
-    drop(lock);
-    s
+        match self
+        {
+            SimulationMode::Classic => "CLASSIC",
+            SimulationMode::WithStockVariation => "W_Stock_Variation",
+            SimulationMode::OnlyStock => "OnlyStock",
+        }
+    }
+}
+
+/// Ids of the enrichment entries that determine how much of 
+/// the product is available in a country
+#[derive(Clone, Copy, Debug)]
+pub struct AvailabilityModel{
+    pub production_id: u8,
+    pub stock_id: u8,
+    pub stock_variation_id: u8
+}
+
+impl AvailabilityModel{
+    pub fn new(node_map: &ExtraInfoMap) -> Self
+    {
+        Self{
+            production_id: node_map.get(PRODUCTION),
+            stock_id: node_map.get(STOCK),
+            stock_variation_id: node_map.get(STOCK_VARIATION)
+        }
+    }
+}
+
+impl Default for AvailabilityModel{
+    fn default() -> Self {
+        Self::new(GLOBAL_NODE_INFO_MAP.deref())
+    }
 }
 
+/// Everything the simulation needs to know about how availability is calculated.
+/// Passed explicitly, so different modes can be used side by side
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimulationContext{
+    pub mode: SimulationMode,
+    pub availability: AvailabilityModel
+}
+
+impl SimulationContext{
+    pub fn new(mode: SimulationMode) -> Self
+    {
+        Self{
+            mode,
+            availability: AvailabilityModel::default()
+        }
+    }
+
+    /// Use the ids of the node map belonging to the enrichment file
+    pub fn with_node_map(self, node_map: &ExtraInfoMap) -> Self
+    {
+        Self{
+            mode: self.mode,
+            availability: AvailabilityModel::new(node_map)
+        }
+    }
+
+    #[inline]
+    pub fn mode_str(&self) -> &'static str
+    {
+        self.mode.as_str()
+    }
 
-pub fn set_global_simulation_mode(mode: SimulationMode){
-    let mut lock = MODE.write().unwrap();
-    *lock = mode;
-    drop(lock);
+    /// Either only the requested mode or all modes, if they should be compared
+    pub fn contexts(mode: SimulationMode, compare_modes: bool) -> Vec<Self>
+    {
+        if compare_modes {
+            SimulationMode::ALL
+                .iter()
+                .map(|&m| Self::new(m))
+                .collect()
+        } else {
+            vec![Self::new(mode)]
+        }
+    }
 }
 
 const ORIGINAL_AVAIL_FILTER_MIN: f64 = 1e-9;
@@ -126,14 +193,58 @@ pub fn flow<P>(opt: FlowOpt, in_file: P)
     let idx = (network.year - enrichments.starting_year) as usize;
     let extra = &enrichments.enrichments[idx];
 
-    let flow = flow_calc(&network, &opt.top_id, opt.iterations, extra);
+    let flow = flow_calc(
+        &network, 
+        &opt.top_id, 
+        opt.iterations, 
+        opt.tolerance,
+        extra, 
+        &SimulationContext::default()
+    );
+    flow.convergence.warn_if_not_converged("flow");
 
-    let file = File::create(opt.out)
+    let population = opt.population.then(
+        ||
+        {
+            let population = Population::new(&network, extra, &enrichments.get_node_map());
+            let all = (0..network.node_count()).collect_vec();
+            population.write_missing(
+                format!("{}.missing_population", opt.out),
+                &network,
+                &all
+            );
+            population
+        }
+    );
+
+    let file = File::create(&opt.out)
         .expect("unable to create file");
     let mut buf = BufWriter::new(file);
+    writeln!(
+        buf, 
+        "# iterations {} residual {:e} converged {}", 
+        flow.convergence.iterations,
+        flow.convergence.residual,
+        flow.convergence.converged
+    ).unwrap();
+    if let Some(p) = population.as_ref(){
+        // people whose available product stems from the focus, countries without population are ignored
+        let supplied: f64 = p.population
+            .iter()
+            .zip(flow.total.iter())
+            .filter_map(|(pop, total)| pop.map(|pop| pop * total))
+            .sum();
+        writeln!(buf, "# people supplied by {}: {supplied:e}", opt.top_id).unwrap();
+        writeln!(buf, "#index total import population people_supplied").unwrap();
+    }
 
     for (index, (total, import)) in flow.total.iter().zip(flow.imports.iter()).enumerate() {
-        writeln!(buf, "{index} {total} {import}").unwrap();
+        write!(buf, "{index} {total} {import}").unwrap();
+        if let Some(p) = population.as_ref(){
+            let pop = p.population[index].unwrap_or(f64::NAN);
+            write!(buf, " {pop:e} {:e}", pop * total).unwrap();
+        }
+        writeln!(buf).unwrap();
     }
 }
 
@@ -141,19 +252,16 @@ pub fn flow_calc(
     net: &Network, 
     focus: &str, 
     iterations: usize, 
-    extra: &BTreeMap<String, ExtraInfo>
+    tolerance: f64,
+    extra: &BTreeMap<String, ExtraInfo>,
+    ctx: &SimulationContext
 ) -> Flow
 {
-    let mode_lock = MODE.read()
-        .unwrap();
-    let mode = mode_lock.deref();
-
-
-    let info_map = crate::network::enriched_digraph::GLOBAL_NODE_INFO_MAP.deref();
+    let mode = &ctx.mode;
     let unit_tester = crate::UNIT_TESTER.deref();
-    let production_index = info_map.get(PRODUCTION);
-    let stock_variation_idx = info_map.get(STOCK_VARIATION);
-    let stock_idx = info_map.get(STOCK);
+    let production_index = ctx.availability.production_id;
+    let stock_variation_idx = ctx.availability.stock_variation_id;
+    let stock_idx = ctx.availability.stock_id;
     let mut percent = vec![0.0; net.node_count()];
     let mut new_percent = percent.clone();
 
@@ -208,12 +316,11 @@ pub fn flow_calc(
         production.push(pr);
 
     }
-    dbg!(mode);
-    dbg!(&stock_vec);
     let focus_idx = map.get(focus).unwrap();
     percent[*focus_idx] = 1.0;
 
     let import_from = net.get_network_with_direction(Direction::ImportFrom);
+    let mut convergence = Convergence::default();
 
     for _ in 0..iterations{
         for i in 0..production.len(){
@@ -249,7 +356,14 @@ pub fn flow_calc(
             }
         }
         new_percent[*focus_idx] = 1.0;
+        let residual = new_percent.iter()
+            .zip(percent.iter())
+            .map(|(new, old)| (new - old).abs())
+            .fold(0.0, f64::max);
         std::mem::swap(&mut new_percent, &mut percent);
+        if convergence.update(residual, tolerance){
+            break;
+        }
     }
 
     let mut imports = new_percent;
@@ -270,13 +384,15 @@ pub fn flow_calc(
 
     Flow{
         total: percent,
-        imports
+        imports,
+        convergence
     }
 }
 
 pub struct Flow{
     pub total: Vec<f64>,
-    pub imports: Vec<f64>
+    pub imports: Vec<f64>,
+    pub convergence: Convergence
 }
 
 pub fn shock_exec<P>(opt: ShockOpts, in_file: P)
@@ -302,13 +418,22 @@ pub fn shock_exec<P>(opt: ShockOpts, in_file: P)
         &network, 
         focus, 
         opt.export, 
-        opt.iterations
+        opt.iterations,
+        opt.tolerance
     );
+    fracts.convergence.warn_if_not_converged("shock");
 
     let name = format!("{}.dat", opt.out);
     let mut buf = create_buf(name);
 
     write_commands_and_version(&mut buf).unwrap();
+    writeln!(
+        buf, 
+        "# iterations {} residual {:e} converged {}", 
+        fracts.convergence.iterations,
+        fracts.convergence.residual,
+        fracts.convergence.converged
+    ).unwrap();
     writeln!(buf, "#index import_frac export_frac country").unwrap();
 
     for (index, (import, export)) in fracts.import_fracs.iter().zip(fracts.export_fracs.iter()).enumerate()
@@ -351,7 +476,8 @@ pub fn shock_distribution(
     network: &Network, 
     focus: usize, 
     export_frac: f64,
-    iterations: usize
+    iterations: usize,
+    tolerance: f64
 ) -> ShockRes
 {
     assert!(
@@ -371,19 +497,24 @@ pub fn shock_distribution(
 
     let mut current_export_frac = vec![1.0; original_exports.len()];
     current_export_frac[focus] = export_frac;
-    let mut reduced_import_frac = vec![1.0; current_export_frac.len()];
+    let mut reduced_import_frac: Vec<f64> = vec![1.0; current_export_frac.len()];
+    let mut convergence = Convergence::default();
 
     for _ in 0..iterations{
+        let mut residual: f64 = 0.0;
         for (index, n) in import.nodes.iter().enumerate(){
+            let old = reduced_import_frac[index];
             reduced_import_frac[index] = 0.0;
             if original_imports[index] == 0.0{
                 assert_eq!(n.adj.len(), 0);
+                residual = residual.max(old.abs());
                 continue;
             }
             for e in n.adj.iter(){
                 reduced_import_frac[index] += e.amount * current_export_frac[e.index];
             }
             reduced_import_frac[index] /= original_imports[index];
+            residual = residual.max((reduced_import_frac[index] - old).abs());
         }
 
         for index in 0..current_export_frac.len()
@@ -393,17 +524,24 @@ pub fn shock_distribution(
             }
             let missing_imports = (1.0 - reduced_import_frac[index]) * original_imports[index];
             let available_for_export = original_exports[index] - missing_imports;
-            current_export_frac[index] = if available_for_export <= 0.0 {
+            let new_frac = if available_for_export <= 0.0 {
                 0.0
             } else {
                 available_for_export / original_exports[index]
             };
+            residual = residual.max((new_frac - current_export_frac[index]).abs());
+            current_export_frac[index] = new_frac;
+        }
+        if convergence.update(residual, tolerance){
+            break;
         }
     }
 
     ShockRes { 
         import_fracs: reduced_import_frac, 
-        export_fracs: current_export_frac
+        export_fracs: current_export_frac,
+        convergence,
+        production_loss: Vec::new()
     }
 }
 
@@ -412,7 +550,28 @@ pub fn shock_distribution(
 #[derive(Debug)]
 pub struct ShockRes{
     pub import_fracs: Vec<f64>,
-    pub export_fracs: Vec<f64>
+    pub export_fracs: Vec<f64>,
+    pub convergence: Convergence,
+    /// Lost production, empty if the production was not shocked
+    pub production_loss: Vec<f64>
+}
+
+impl ShockRes{
+    /// Nothing is restricted, all fractions are 1
+    pub fn no_shock(len: usize) -> Self
+    {
+        let one = vec![1.0; len];
+        Self{
+            import_fracs: one.clone(),
+            export_fracs: one,
+            convergence: Convergence{
+                iterations: 0,
+                residual: 0.0,
+                converged: true
+            },
+            production_loss: Vec::new()
+        }
+    }
 }
 
 pub struct CalculatedShocks{
@@ -425,6 +584,26 @@ pub struct CalculatedShocks{
 }
 
 impl CalculatedShocks{
+    /// Population of the countries of the shocked network.
+    /// Needs the enrichment the shock was calculated with
+    pub fn population(&self, lazy_enrichment: &LazyEnrichmentInfos, year: i32) -> Population
+    {
+        Population::new(
+            &self.network,
+            lazy_enrichment.get_year_unchecked(year),
+            &lazy_enrichment.extra_info_idmap_unchecked()
+        )
+    }
+
+    /// Countries that count for the population impact,
+    /// i.e., all except the focus that had product before the shock
+    pub fn population_countries(&self) -> Vec<usize>
+    {
+        (0..self.network.node_count())
+            .filter(|&idx| idx != self.focus_index && self.available_before_shock[idx] > 0.0)
+            .collect()
+    }
+
     /// fraction of missing product after shock, negative to show that it is removed
     pub fn delta_iter(&'_ self) -> impl Iterator<Item = f64> + '_
     {
@@ -465,14 +644,16 @@ impl CalculatedShocks{
 }
 
 
-
+#[allow(clippy::too_many_arguments)]
 pub fn calc_shock(
     lazy_network: &mut LazyNetworks, 
     year: i32, 
     top_id: TopSpecifier, 
     export_frac: f64,
     iterations: usize,
+    tolerance: f64,
     lazy_enrichment: &mut LazyEnrichmentInfos,
+    ctx: &SimulationContext
 ) -> CalculatedShocks
 {
     lazy_network.assure_availability();
@@ -511,33 +692,33 @@ pub fn calc_shock(
         &export, 
         focus, 
         export_frac, 
-        iterations
+        iterations,
+        tolerance
     );
+    fracts.convergence.warn_if_not_converged("shock");
 
     lazy_enrichment.assure_availability();
     let enrichment_infos = lazy_enrichment.enrichment_infos_unchecked();
     let enrich = enrichment_infos.get_year(year);
 
     let node_info_map = lazy_enrichment.extra_info_idmap_unchecked();
+    let ctx = ctx.with_node_map(&node_info_map);
 
     let (avail_after_shock, _) = calc_available(
         &export, 
         enrich, 
         &fracts, 
-        &node_info_map,
+        &ctx,
         false
     );
 
-    let no_shock = ShockRes{
-        import_fracs: vec![1.0; fracts.import_fracs.len()],
-        export_fracs: vec![1.0; fracts.import_fracs.len()]
-    };
+    let no_shock = ShockRes::no_shock(fracts.import_fracs.len());
 
     let (available_before_shock, flow_status) = calc_available(
         &export, 
         enrich, 
         &no_shock, 
-        &node_info_map,
+        &ctx,
         false
     );
 
@@ -594,6 +775,7 @@ pub fn multi_shock_distribution(
     job: &CalcShockMultiJob
 ) -> ShockRes
 {
+    // checked here, so that every backend enforces them
     let interval = 0.0..=1.0;
     assert!(
         job.exporter.iter().all(|e| interval.contains(&e.export_frac)),
@@ -601,41 +783,70 @@ pub fn multi_shock_distribution(
     );
     assert!(import_network.direction.is_import());
 
-    
+    // rerouting has its own Jacobi sweeps, see rerouting::assert_solver_supported
+    if let Some(adaptation) = job.adaptation.as_ref(){
+        return rerouting::adaptive_shock_distribution(import_network, job, adaptation);
+    }
+    match job.solver{
+        SolverBackend::Jacobi => multi_shock_jacobi(import_network, job),
+        SolverBackend::GaussSeidel => shock_solver::gauss_seidel(import_network, job),
+        SolverBackend::Anderson => shock_solver::anderson(import_network, job)
+    }
+}
+
+fn multi_shock_jacobi(
+    import_network: &Network,
+    job: &CalcShockMultiJob
+) -> ShockRes
+{
     let mut current_export_frac = vec![1.0; job.original_exports.len()];
     for e in job.exporter.iter(){
         current_export_frac[e.export_id] = e.export_frac;
     }
-    let mut reduced_import_frac = vec![1.0; current_export_frac.len()];
+    let mut reduced_import_frac: Vec<f64> = vec![1.0; current_export_frac.len()];
+    let mut convergence = Convergence::default();
 
     for _ in 0..job.iterations{
+        let mut residual: f64 = 0.0;
         for (index, n) in import_network.nodes.iter().enumerate(){
+            let old = reduced_import_frac[index];
             reduced_import_frac[index] = 0.0;
             if job.original_imports[index] == 0.0{
                 assert_eq!(n.adj.len(), 0);
+                residual = residual.max(old.abs());
                 continue;
             }
             for e in n.adj.iter(){
                 reduced_import_frac[index] += e.amount * current_export_frac[e.index];
             }
             reduced_import_frac[index] *= job.original_imports_recip[index];
+            residual = residual.max((reduced_import_frac[index] - old).abs());
         }
 
         for &index in job.unrestricted_node_idxs.iter()
         {
             let missing_imports = (1.0 - reduced_import_frac[index]) * job.original_imports[index];
-            let available_for_export = job.original_exports[index] - missing_imports;
-            current_export_frac[index] = if available_for_export <= 0.0 {
+            let available_for_export = job.original_exports[index] 
+                - missing_imports 
+                - job.production_loss(index);
+            let new_frac = if available_for_export <= 0.0 {
                 0.0
             } else {
                 available_for_export * job.original_exports_recip[index]
             };
+            residual = residual.max((new_frac - current_export_frac[index]).abs());
+            current_export_frac[index] = new_frac;
+        }
+        if convergence.update(residual, job.tolerance){
+            break;
         }
     }
 
     ShockRes { 
         import_fracs: reduced_import_frac, 
-        export_fracs: current_export_frac
+        export_fracs: current_export_frac,
+        convergence,
+        production_loss: job.production_loss.clone()
     }
 }
 
@@ -662,6 +873,26 @@ pub struct InCommon{
     #[derivative(Default(value="10000"))]
     pub iterations: usize,    
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// iterations is then only the upper limit
+    #[serde(default = "default_tolerance")]
+    #[derivative(Default(value="DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Restrict the exports or reduce the production of the countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// If given, importers try to replace missing imports
+    /// by importing more from their other partners
+    #[serde(default)]
+    pub adaptation: Option<Adaptation>,
+
+    /// Also report the number of affected people and population weighted losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[serde(default)]
+    pub population: bool,
+
     /// Item code, e.g. 27 for Rice
     pub item_code: Option<String>,
 
@@ -704,7 +935,7 @@ impl Default for Percentages{
     }
 }
 
-fn get_files(glob: &str) -> BTreeMap<usize, Utf8PathBuf>
+pub(crate) fn get_files(glob: &str) -> BTreeMap<usize, Utf8PathBuf>
 {   
     let expr = r"\d+";
     let re = regex::Regex::new(expr)
@@ -720,14 +951,19 @@ fn get_files(glob: &str) -> BTreeMap<usize, Utf8PathBuf>
         ).collect()
 }
 
+/// Every (item, year) is an independent unit with its own seeded rng,
+/// so resumed runs give the same results as uninterrupted ones
 pub fn all_random_cloud_shocks<P>(
     json: Option<P>, 
+    contexts: &[SimulationContext],
     out_stub: &str,
     quiet: bool,
-    threads: NonZeroUsize
+    threads: NonZeroUsize,
+    resume: bool
 )where P: AsRef<Path>
 {
     let opt: ShockCloudAll = crate::misc::parse_and_add_to_global(json);
+    rerouting::assert_solver_supported(opt.adaptation.as_ref(), opt.solver);
     
     let enrich_files = get_files(&opt.enrich_glob);
     dbg!(&enrich_files);
@@ -736,6 +972,11 @@ pub fn all_random_cloud_shocks<P>(
         .chain(network_files.keys())
         .copied()
         .collect();
+    let ledger = Ledger::open(format!("{out_stub}_shock_cloud.ledger"), resume);
+    let modes = contexts.iter()
+        .map(|ctx| ctx.mode_str())
+        .join(",");
+    let mut skipped = 0;
     let mut job_opts = VecDeque::new();
     for key in all_item_codes{
         let enrich_path = match enrich_files.get(&key){
@@ -762,16 +1003,31 @@ pub fn all_random_cloud_shocks<P>(
                 item_code: Some(key.to_string()),
                 top: opt.top,
                 iterations: opt.iterations,
+                tolerance: opt.tolerance,
+                solver: opt.solver,
+                shock_type: opt.shock_type,
+                adaptation: opt.adaptation,
+                population: opt.population,
+                sampler: opt.sampler,
+                distribution: opt.distribution.clone(),
                 unstable_country_threshold: opt.unstable_country_threshold,
                 original_avail_filter: opt.original_avail_filter,
                 seed: opt.seed,
                 reducing_factor: opt.reducing_factor,
                 hist_bins: opt.hist_bins
             };
-            job_opts.push_back(shock_opt);
+            let config = shock_opt.config_hash(&modes);
+            if ledger.is_done(&key.to_string(), y, config){
+                skipped += 1;
+                continue;
+            }
+            job_opts.push_back((shock_opt, config));
         }
         
     }
+    if resume {
+        println!("Resuming: {skipped} finished units are skipped");
+    }
     
     let issues = Mutex::new(Vec::new());
 
@@ -781,20 +1037,31 @@ pub fn all_random_cloud_shocks<P>(
         .for_each(
             |_|
             {
-                while let Some(opt) = sync_queue.pop(){
+                while let Some((opt, config)) = sync_queue.pop(){
                     sync_queue.print_remaining();
                     let folder = opt.item_code.as_deref();
-                    let result = random_cloud_shock_helper(
+                    let result = random_cloud_shock_modes(
                         &opt, 
+                        contexts,
                         out_stub, 
                         quiet,
                         folder
                     );
-                    if let Err(info) = result {
-                        let mut lock = issues.lock()
-                            .unwrap();
-                        lock.push(info);
-                        drop(lock);
+                    match result {
+                        Ok(files) => {
+                            ledger.record(
+                                folder.unwrap(),
+                                *opt.years.start(),
+                                config,
+                                &files
+                            );
+                        },
+                        Err(info) => {
+                            let mut lock = issues.lock()
+                                .unwrap();
+                            lock.push(info);
+                            drop(lock);
+                        }
                     }
                 }
             }
@@ -837,20 +1104,112 @@ pub fn all_random_cloud_shocks<P>(
 
 pub fn random_cloud_shock<P>(
     json: Option<P>, 
+    contexts: &[SimulationContext],
     out_stub: &str,
     quiet: bool
 )
 where P: AsRef<Path>
 {
     let opt: ShockCloud = crate::misc::parse_and_add_to_global(json);
-    let _ = random_cloud_shock_helper(
+    rerouting::assert_solver_supported(opt.adaptation.as_ref(), opt.solver);
+    let _ = random_cloud_shock_modes(
         &opt, 
+        contexts,
         out_stub, 
         quiet,
         None
     );
 }
 
+/// Averages of the country count of one year, one entry per histogram bin.
+/// The last bin is the interval [1,1], i.e., the unshocked case
+pub struct CloudAverages{
+    pub year: i32,
+    pub intervals: Vec<[f64; 2]>,
+    pub averages: Vec<f64>,
+    /// All files that were written for this year
+    pub files: Vec<String>
+}
+
+fn folder_prefix(folder: Option<&str>) -> String
+{
+    match folder{
+        Some(f) => {
+            let _ = std::fs::create_dir(f);
+            format!("{f}/")
+        },
+        None => String::default()
+    }
+}
+
+/// Runs the shock cloud for every context. 
+/// If there is more than one context, the averages of all modes
+/// are additionally written side by side into one file per year.
+/// Returns the names of all written files
+pub fn random_cloud_shock_modes(
+    opt: &ShockCloud, 
+    contexts: &[SimulationContext],
+    out_stub: &str,
+    quiet: bool,
+    folder: Option<&str>,
+) -> Result<Vec<String>, MissingInfo>
+{
+    let mut all_averages = Vec::new();
+    for ctx in contexts{
+        let averages = random_cloud_shock_helper(opt, ctx, out_stub, quiet, folder)?;
+        all_averages.push(averages);
+    }
+    let mut files = all_averages.iter()
+        .flatten()
+        .flat_map(|a| a.files.iter().cloned())
+        .collect_vec();
+    if contexts.len() < 2 {
+        return Ok(files);
+    }
+
+    let folder = folder_prefix(folder);
+    let mut header = vec![
+        "interval_left".to_owned(),
+        "interval_right".to_owned()
+    ];
+    header.extend(
+        contexts.iter()
+            .map(|ctx| format!("average_{}", ctx.mode_str()))
+    );
+
+    // years can be missing for some modes, as the quick and dirty check depends on the mode
+    for first in all_averages[0].iter(){
+        let year = first.year;
+        let per_mode = all_averages.iter()
+            .map(|list| list.iter().find(|a| a.year == year))
+            .collect::<Option<Vec<_>>>();
+        let per_mode = match per_mode{
+            Some(p) => p,
+            None => {
+                println!("Year {year} not available in all modes - SKIPPING MODE COMPARISON");
+                continue;
+            }
+        };
+        let name = format!(
+            "{folder}{out_stub}_Y{year}_Th{}_R{}_modes{}.average", 
+            opt.unstable_country_threshold,
+            opt.reducing_factor,
+            opt.shock_type.name_addition()
+        );
+        let mut buf = create_buf_with_command_and_version(&name);
+        files.push(name);
+        write_slice_head(&mut buf, &header).unwrap();
+        for (i, interval) in first.intervals.iter().enumerate(){
+            write!(buf, "{} {}", interval[0], interval[1]).unwrap();
+            for averages in per_mode.iter(){
+                write!(buf, " {:e}", averages.averages[i]).unwrap();
+            }
+            writeln!(buf).unwrap();
+        }
+    }
+    Ok(files)
+}
+
 #[derive(Debug)]
 pub enum Reason{
     Production,
@@ -867,10 +1226,11 @@ pub struct MissingInfo{
 
 pub fn random_cloud_shock_helper(
     opt: &ShockCloud, 
+    ctx: &SimulationContext,
     out_stub: &str,
     quiet: bool,
     folder: Option<&str>,
-) -> Result<(), MissingInfo>
+) -> Result<Vec<CloudAverages>, MissingInfo>
 {
 
     let mut lazy_networks = LazyNetworks::Filename(opt.network_file.clone());
@@ -925,17 +1285,10 @@ pub fn random_cloud_shock_helper(
         }
     }
 
-    let folder = match folder{
-        Some(f) => {
-            let _ = std::fs::create_dir(folder.unwrap());
-            format!("{f}/")
-        },
-        None => String::default()
-    };
-
+    let folder = folder_prefix(folder);
     
     let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
-    let node_info_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let ctx = ctx.with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
 
     let mut original_avail_filter = opt.original_avail_filter;
     if original_avail_filter < ORIGINAL_AVAIL_FILTER_MIN {
@@ -946,12 +1299,22 @@ pub fn random_cloud_shock_helper(
         original_avail_filter = ORIGINAL_AVAIL_FILTER_MIN;
     }
 
-    let header = [
+    let mut header = vec![
         "disruption",
-        "num_of_countries"
+        "num_of_countries",
+        "iterations",
+        "residual",
+        "converged"
     ];
+    if opt.population{
+        header.extend(PopulationImpact::HEADER);
+    }
 
-    let mode_str = global_simulation_mode_as_str();
+    let mode_str = ctx.mode_str();
+    let shock_str = opt.shock_type.name_addition();
+    let adapt_str = opt.adaptation
+        .map(|a| a.name_addition())
+        .unwrap_or_default();
 
     let mut rng = Pcg64::seed_from_u64(opt.seed);
 
@@ -961,7 +1324,7 @@ pub fn random_cloud_shock_helper(
         .collect_vec();
 
 
-    years_and_rngs
+    let averages = years_and_rngs
         .into_par_iter()
         .filter_map(
             |(year, rng)|
@@ -981,7 +1344,8 @@ pub fn random_cloud_shock_helper(
                     &export_without_unconnected, 
                     enrich,
                     opt.item_code.as_deref().unwrap(),
-                    year
+                    year,
+                    &ctx
                 );
                 is_good.then_some(
                     (
@@ -995,7 +1359,7 @@ pub fn random_cloud_shock_helper(
                 )
             }
         )
-        .for_each(
+        .map(
             |
                 (
                     year, 
@@ -1009,16 +1373,12 @@ pub fn random_cloud_shock_helper(
             {
 
                 let (no_shock, flow_status) = {
-                    let one = vec![1.0; import_without_unconnected.node_count()];
-                    let no_shock = ShockRes{
-                        import_fracs: one.clone(),
-                        export_fracs: one
-                    };
+                    let no_shock = ShockRes::no_shock(import_without_unconnected.node_count());
                     calc_available(
                         &export_without_unconnected, 
                         enrich, 
                         &no_shock, 
-                        &node_info_map,
+                        &ctx,
                         quiet
                     )
                 };
@@ -1026,19 +1386,21 @@ pub fn random_cloud_shock_helper(
                 let flow_status_name_addition = flow_status.name_addition();
 
                 let out_name = format!(
-                    "{folder}{}{out_stub}_Y{year}_Th{}_R{}_{mode_str}.dat", 
+                    "{folder}{}{out_stub}_Y{year}_Th{}_R{}_{mode_str}{shock_str}{adapt_str}.dat", 
                     flow_status_name_addition,
                     opt.unstable_country_threshold,
                     opt.reducing_factor
                 );
                 let av_name = format!(
-                    "{folder}{}{out_stub}_Y{year}_Th{}_R{}_{mode_str}.average", 
+                    "{folder}{}{out_stub}_Y{year}_Th{}_R{}_{mode_str}{shock_str}{adapt_str}.average", 
                     flow_status_name_addition,
                     opt.unstable_country_threshold,
                     opt.reducing_factor
                 );
             
-                let mut buf = create_buf_with_command_and_version_and_header(out_name, header);
+                let mut buf = create_buf_with_command_and_version_and_header(&out_name, &header);
+                writeln!(buf, "# sampler {}", opt.sampler.name()).unwrap();
+                let mut files = vec![out_name.clone(), av_name.clone()];
 
                 let len = export_without_unconnected.node_count();
                 let countries_where_country_count_is_applicable = 
@@ -1048,70 +1410,136 @@ pub fn random_cloud_shock_helper(
                             !top.contains(idx)
                             && no_shock[*idx] >= original_avail_filter
                         ).collect_vec();
+                let population = opt.population.then(
+                    ||
+                    {
+                        let p = Population::new(&export_without_unconnected, enrich, &map);
+                        let missing_name = format!("{out_name}.missing_population");
+                        p.write_missing(
+                            &missing_name, 
+                            &export_without_unconnected, 
+                            &countries_where_country_count_is_applicable
+                        );
+                        files.push(missing_name);
+                        p
+                    }
+                );
                 let original_exports = calc_acc_trade(&export_without_unconnected);
                 let original_exports_recip = calc_recip(&original_exports);
                 let original_imports =  calc_acc_trade(&import_without_unconnected);
                 let original_imports_recip = calc_recip(&original_imports);
+                let order = if opt.solver.needs_order(){
+                    shock_solver::sweep_order(&import_without_unconnected)
+                } else {
+                    Vec::new()
+                };
                 let total_export = top.iter()
                     .map(|&idx| original_exports[idx])
                     .sum::<f64>();
+                let production = production_vec(&export_without_unconnected, enrich, &ctx);
                 let mut hist = HistF64::new(0.0, 1.0, opt.hist_bins.get())
                         .unwrap();
                 let mut sum = vec![0_u64; hist.bin_count() + 1];
                 let mut sum_sq = sum.clone();
                 let last_sum_idx = sum.len() - 1;
                 let mut last_hits = 0;
-
-                let max = top.len();
-                let delta = max as f64 / (opt.cloud_steps.get() - 1) as f64;
+                let mut convergence_summary = ConvergenceSummary::default();
+                // separate rng, so the samples do not depend on whether the distribution is requested
+                let mut distribution = opt.distribution
+                    .as_ref()
+                    .map(
+                        |d|
+                        {
+                            (
+                                vec![BinStats::new(d); sum.len()],
+                                Pcg64::seed_from_u64(opt.seed ^ year as u64)
+                            )
+                        }
+                    );
 
                 let maximal_target = top.len() as f64;
+                let mut points = opt.sampler.point_source(2 * top.len().saturating_sub(1), &mut rng);
                 for i in 0..opt.cloud_steps.get(){
-                    let target = (i as f64 * delta).min(maximal_target);
+                    let target = opt.sampler.target(i, opt.cloud_steps.get(), maximal_target);
                     let matrix = rand_fixed_sum(
                         top.len(), 
                         opt.cloud_m, 
                         target, 
                         0.0, 
                         1.0, 
+                        points.as_mut(),
                         &mut rng
                     );
-                    for random_export_fracs in matrix.iter(){
-                        let exports = top.iter()
-                            .zip(random_export_fracs.iter())
-                            .map(
-                                |(id, frac)|
-                                {
-                                    ExportShockItem{
-                                        export_frac: *frac,
-                                        export_id: *id
-                                    }
-                                }
-                            ).collect_vec();
-    
-                        let job = CalcShockMultiJob::new_exporter(
-                            exports, 
-                            opt.iterations, 
-                            &export_without_unconnected, 
-                            &original_imports,
-                            &original_imports_recip,
-                            &original_exports,
-                            &original_exports_recip
-                        );
+                    for random_fracs in matrix.iter(){
+                        let job = match opt.shock_type{
+                            ShockType::ExportRestriction => {
+                                let exports = top.iter()
+                                    .zip(random_fracs.iter())
+                                    .map(
+                                        |(id, frac)|
+                                        {
+                                            ExportShockItem{
+                                                export_frac: *frac,
+                                                export_id: *id
+                                            }
+                                        }
+                                    ).collect_vec();
+            
+                                CalcShockMultiJob::new_exporter(
+                                    exports, 
+                                    opt.iterations, 
+                                    &export_without_unconnected, 
+                                    &original_imports,
+                                    &original_imports_recip,
+                                    &original_exports,
+                                    &original_exports_recip
+                                )
+                            },
+                            ShockType::Production => {
+                                let shocks = top.iter()
+                                    .zip(random_fracs.iter())
+                                    .map(
+                                        |(id, frac)|
+                                        {
+                                            ProductionShockItem{
+                                                production_frac: *frac,
+                                                country_id: *id
+                                            }
+                                        }
+                                    ).collect_vec();
+                                CalcShockMultiJob::new_production(
+                                    shocks, 
+                                    &production,
+                                    opt.iterations, 
+                                    &export_without_unconnected, 
+                                    &original_imports,
+                                    &original_imports_recip,
+                                    &original_exports,
+                                    &original_exports_recip
+                                )
+                            }
+                        }.with_tolerance(opt.tolerance)
+                        .with_solver(opt.solver, &order)
+                        .with_adaptation(opt.adaptation);
     
                         let shock_result = multi_shock_distribution(&import_without_unconnected, &job);
+                        convergence_summary.add(&shock_result.convergence);
                 
-                        let remaining_export = top.iter()
-                            .map(|&idx| job.original_exports[idx] * shock_result.export_fracs[idx])
-                            .sum::<f64>();
-                
-                        let percent = remaining_export / total_export;
+                        let percent = match opt.shock_type{
+                            ShockType::ExportRestriction => {
+                                let remaining_export = top.iter()
+                                    .map(|&idx| job.original_exports[idx] * shock_result.export_fracs[idx])
+                                    .sum::<f64>();
+                                remaining_export / total_export
+                            },
+                            ShockType::Production => job.remaining_production_frac()
+                        };
                 
                         let (avail_after_shock, _) = calc_available(
                             &export_without_unconnected, 
                             enrich, 
                             &shock_result, 
-                            &node_info_map,
+                            &ctx,
                             quiet
                         );
                         let mut country_counter = 0;
@@ -1125,7 +1553,23 @@ pub fn random_cloud_shock_helper(
                                 country_counter += 1;
                             }
                         }
-                        writeln!(buf, "{percent:e} {country_counter}").unwrap();
+                        write!(
+                            buf, 
+                            "{percent:e} {country_counter} {} {:e} {}",
+                            shock_result.convergence.iterations,
+                            shock_result.convergence.residual,
+                            shock_result.convergence.flag()
+                        ).unwrap();
+                        if let Some(p) = population.as_ref(){
+                            p.impact(
+                                &countries_where_country_count_is_applicable,
+                                &no_shock,
+                                &avail_after_shock,
+                                opt.unstable_country_threshold
+                            ).write(&mut buf)
+                            .unwrap();
+                        }
+                        writeln!(buf).unwrap();
                         let idx = match hist.increment(percent){
                             Ok(idx) => idx,
                             Err(_) => {
@@ -1136,10 +1580,22 @@ pub fn random_cloud_shock_helper(
                         };
                         sum[idx] += country_counter;
                         sum_sq[idx] += country_counter * country_counter;
+                        if let Some((stats, boot_rng)) = distribution.as_mut(){
+                            stats[idx].add(country_counter as u32, boot_rng);
+                        }
                     }
                     
                 }
-                let mut hist_buf = create_buf_with_command_and_version(av_name);
+                if convergence_summary.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} - {} of {} shocks did not converge",
+                        convergence_summary.not_converged,
+                        convergence_summary.runs
+                    );
+                }
+                let mut hist_buf = create_buf_with_command_and_version(&av_name);
+                writeln!(hist_buf, "# sampler {}", opt.sampler.name()).unwrap();
+                convergence_summary.write_comment(&mut hist_buf).unwrap();
                 let header = [
                     "interval_left",
                     "interval_right",
@@ -1158,6 +1614,8 @@ pub fn random_cloud_shock_helper(
                 let mut norm = None;
 
                 let trading_norm_factor = (countries_where_country_count_is_applicable.len() as f64).recip();
+                let mut intervals = Vec::new();
+                let mut averages = Vec::new();
                 
                 for (((interval, hits), sum), sum_sq) in iter {
                     let average = sum as f64 / hits as f64;
@@ -1180,15 +1638,71 @@ pub fn random_cloud_shock_helper(
                         interval[0],
                         interval[1]
                     ).unwrap();
+                    intervals.push([interval[0], interval[1]]);
+                    averages.push(average);
+                }
+                if let (Some(d), Some((stats, _))) = (opt.distribution.as_ref(), distribution){
+                    let stub = av_name.trim_end_matches(".average");
+                    let quantile_name = format!("{stub}.quantiles");
+                    let mut buf = create_buf_with_command_and_version_and_header(
+                        &quantile_name,
+                        quantile_header(d)
+                    );
+                    files.push(quantile_name);
+                    writeln!(buf, "# bootstrap replicates {} confidence {}", d.bootstrap_samples, d.confidence).unwrap();
+                    for (interval, s) in intervals.iter().zip(stats.iter()){
+                        write_quantile_line(&mut buf, *interval, s, d).unwrap();
+                    }
+
+                    let header = [
+                        "interval_left",
+                        "interval_right",
+                        "unstable_countries",
+                        "hits",
+                        "probability"
+                    ];
+                    let count_name = format!("{stub}.count_hist");
+                    let mut buf = create_buf_with_command_and_version_and_header(
+                        &count_name,
+                        header
+                    );
+                    files.push(count_name);
+                    for (interval, s) in intervals.iter().zip(stats.iter()){
+                        let total = s.distribution.hits() as f64;
+                        for (count, hits) in s.distribution.iter(){
+                            writeln!(
+                                buf,
+                                "{} {} {count} {hits} {:e}",
+                                interval[0],
+                                interval[1],
+                                hits as f64 / total
+                            ).unwrap();
+                        }
+                    }
+                }
+                CloudAverages{
+                    year,
+                    intervals,
+                    averages,
+                    files
                 }
             }
-        );
-    Ok(())
+        ).collect();
+    Ok(averages)
 }
  
+type MultiShockOpts = either::Either<MeasureMultiShockOpts<Percentages>, MeasureMultiShockOpts<()>>;
+
+/// One line of the multi shock output: disrupting countries, disruption percent, number of countries
+pub type MultiShockRow = (u16, f64, u32);
+
+/// Runs the multi shock for every context. 
+/// If there is more than one context, the country counts of all modes
+/// are additionally written side by side into one file per year
 pub fn measure_multi_shock<P>(
     json: Option<P>, 
     which: ExportRestrictionType,
+    contexts: &[SimulationContext],
     out_stub: &str,
     quiet: bool,
     group_files: bool,
@@ -1196,7 +1710,7 @@ pub fn measure_multi_shock<P>(
 )
 where P: AsRef<Path>
 {
-    let opt = match which{
+    let opt: MultiShockOpts = match which{
         ExportRestrictionType::Percentages => {
             let opt: MeasureMultiShockOpts<Percentages> = crate::misc::parse_and_add_to_global(json);
             either::Either::Left(opt)
@@ -1206,6 +1720,72 @@ where P: AsRef<Path>
             either::Either::Right(opt)
         }
     };
+
+    let results = contexts.iter()
+        .map(
+            |ctx|
+            {
+                measure_multi_shock_helper(
+                    &opt, 
+                    which, 
+                    ctx, 
+                    out_stub, 
+                    quiet, 
+                    group_files, 
+                    compare_successive
+                )
+            }
+        ).collect_vec();
+    if contexts.len() < 2 {
+        return;
+    }
+
+    let common_opt = opt.as_ref()
+        .either(
+            |o| &o.common,
+            |o| &o.common
+        );
+    let x_name = match which{
+        ExportRestrictionType::Percentages => "percent".to_owned(),
+        ExportRestrictionType::WholeCountries => format!("Top{}", common_opt.top)
+    };
+    let mut header = vec![
+        "disrupting_countries".to_owned(),
+        "disruption_percent".to_owned()
+    ];
+    header.extend(
+        contexts.iter()
+            .map(|ctx| format!("num_countries_{}", ctx.mode_str()))
+    );
+
+    // The disruption does not depend on the mode, so the rows of all modes match
+    for (year, first) in results[0].iter(){
+        let name = format!(
+            "{out_stub}_Y{year}_Th{}_modes{}_{x_name}.dat", 
+            common_opt.unstable_country_threshold,
+            common_opt.shock_type.name_addition()
+        );
+        let mut buf = create_buf_with_command_and_version_and_header(name, &header);
+        for (i, (x, percent, _)) in first.iter().enumerate(){
+            write!(buf, "{x} {percent}").unwrap();
+            for (_, rows) in results.iter().map(|r| r.iter().find(|(y, _)| y == year).unwrap()){
+                write!(buf, " {}", rows[i].2).unwrap();
+            }
+            writeln!(buf).unwrap();
+        }
+    }
+}
+
+fn measure_multi_shock_helper(
+    opt: &MultiShockOpts,
+    which: ExportRestrictionType,
+    ctx: &SimulationContext,
+    out_stub: &str,
+    quiet: bool,
+    group_files: bool,
+    compare_successive: bool
+) -> Vec<(i32, Vec<MultiShockRow>)>
+{
     let common_opt = opt.as_ref()
         .either(
             |o| &o.common,
@@ -1221,14 +1801,29 @@ where P: AsRef<Path>
     );
     lazy_enrichments.assure_availability();
     let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
-    let node_info_map = lazy_enrichments.extra_info_idmap_unchecked();
-
-    let mode_str = global_simulation_mode_as_str();
-    let header = [
+    let ctx = ctx.with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+
+    let mode_str = ctx.mode_str();
+    let shock_type = common_opt.shock_type;
+    let shock_str = shock_type.name_addition();
+    let adapt_str = common_opt.adaptation
+        .map(|a| a.name_addition())
+        .unwrap_or_default();
+    let mut header = vec![
         "disrupting_countries",
         "disruption_percent",
-        "num_countries"
+        "num_countries",
+        "iterations",
+        "residual",
+        "converged"
     ];
+    if common_opt.adaptation.is_some(){
+        header.push("num_countries_without_rerouting");
+    }
+    if common_opt.population{
+        header.extend(PopulationImpact::HEADER);
+    }
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
 
 
     let mut original_avail_filter = common_opt.original_avail_filter;
@@ -1247,7 +1842,7 @@ where P: AsRef<Path>
             |year|
             {
                 let mut out_stub = format!(
-                    "{out_stub}_Y{year}_Th{}_{mode_str}_", 
+                    "{out_stub}_Y{year}_Th{}_{mode_str}{shock_str}{adapt_str}_", 
                     common_opt.unstable_country_threshold
                 );
                 let export_without_unconnected = lazy_networks
@@ -1265,6 +1860,41 @@ where P: AsRef<Path>
                 let original_exports_recip = calc_recip(&original_exports);
                 let original_imports =  calc_acc_trade(&import_without_unconnected);
                 let original_imports_recip = calc_recip(&original_imports);
+                let production = production_vec(&export_without_unconnected, enrich, &ctx);
+                // references are moved into the closure, so the jobs can outlive it
+                let (export_net, production_ref) = (&export_without_unconnected, production.as_slice());
+                let (oe, oe_recip) = (original_exports.as_slice(), original_exports_recip.as_slice());
+                let (oi, oi_recip) = (original_imports.as_slice(), original_imports_recip.as_slice());
+                let new_job = move |ids: &[usize], frac: f64| {
+                    match shock_type{
+                        ShockType::ExportRestriction => {
+                            CalcShockMultiJob::new_const_export(
+                                ids, 
+                                frac, 
+                                common_opt.iterations, 
+                                export_net, 
+                                oe,
+                                oe_recip,
+                                oi,
+                                oi_recip
+                            )
+                        },
+                        ShockType::Production => {
+                            CalcShockMultiJob::new_const_production(
+                                ids, 
+                                frac, 
+                                production_ref,
+                                common_opt.iterations, 
+                                export_net, 
+                                oe,
+                                oe_recip,
+                                oi,
+                                oi_recip
+                            )
+                        }
+                    }.with_tolerance(common_opt.tolerance)
+                    .with_adaptation(common_opt.adaptation)
+                };
             
                 #[allow(clippy::type_complexity)]
                 let (mut iterate, mut job, mut x): (Box<dyn FnMut(&mut CalcShockMultiJob) -> Option<u16>>, _, u16) = match opt.as_ref()
@@ -1284,21 +1914,15 @@ where P: AsRef<Path>
                             match iter.next(){
                                 None =>  None,
                                 Some(val) => {
-                                    job.change_export_frac(val);
+                                    match shock_type{
+                                        ShockType::ExportRestriction => job.change_export_frac(val),
+                                        ShockType::Production => job.change_production_frac(val)
+                                    }
                                     Some(country_count)
                                 }
                             }
                         };
-                        let job = CalcShockMultiJob::new_const_export(
-                            &top, 
-                            first, 
-                            common_opt.iterations, 
-                            &export_without_unconnected, 
-                            &original_exports,
-                            &original_exports_recip,
-                            &original_imports,
-                            &original_imports_recip
-                        );
+                        let job = new_job(&top, first);
                         (Box::new(fun), job, country_count)
                     },
                     either::Either::Right(_) => {
@@ -1312,21 +1936,19 @@ where P: AsRef<Path>
                             } else {
                                 let to_add;
                                 (to_add, slice) = slice.split_first().unwrap();
-                                job.add_exporter(ExportShockItem{export_id: *to_add, export_frac: 0.0});
+                                match shock_type{
+                                    ShockType::ExportRestriction => {
+                                        job.add_exporter(ExportShockItem{export_id: *to_add, export_frac: 0.0});
+                                    },
+                                    ShockType::Production => {
+                                        job.add_production_shock(ProductionShockItem{country_id: *to_add, production_frac: 0.0});
+                                    }
+                                }
                                 count += 1;
                                 Some(count)
                             }
                         };
-                        let job = CalcShockMultiJob::new_const_export(
-                            first, 
-                            0.0, 
-                            common_opt.iterations, 
-                            &export_without_unconnected, 
-                            &original_exports,
-                            &original_exports_recip,
-                            &original_imports,
-                            &original_imports_recip
-                        );
+                        let job = new_job(first, 0.0);
                         (Box::new(fun), job, 1)
                     }
                 };
@@ -1334,16 +1956,12 @@ where P: AsRef<Path>
             
 
                 let (no_shock, flow_status) = {
-                    let one = vec![1.0; import_without_unconnected.node_count()];
-                    let no_shock = ShockRes{
-                        import_fracs: one.clone(),
-                        export_fracs: one
-                    };
+                    let no_shock = ShockRes::no_shock(import_without_unconnected.node_count());
                     calc_available(
                         &export_without_unconnected, 
                         enrich, 
                         &no_shock, 
-                        &node_info_map,
+                        &ctx,
                         quiet
                     )
                 };
@@ -1357,33 +1975,65 @@ where P: AsRef<Path>
                     }
                 );
                 out_stub = format!("{flow_status_name_addition}{out_stub}.dat");
-                let mut buf = create_buf_with_command_and_version_and_header(&out_stub, header);
+                let mut buf = create_buf_with_command_and_version_and_header(&out_stub, &header);
 
                 // filter out countries that have a very small total of the item in question
+                // and the countries that are disrupted themselves
                 let countries_where_country_count_is_applicable = job.unrestricted_node_idxs
                     .iter()
                     .copied()
                     .filter(|idx| no_shock[*idx] >= original_avail_filter)
+                    .filter(|idx| !job.production_shocks.iter().any(|s| s.country_id == *idx))
                     .collect_vec();
+                let population = common_opt.population.then(
+                    ||
+                    {
+                        let p = Population::new(&export_without_unconnected, enrich, &node_map);
+                        p.write_missing(
+                            format!("{out_stub}.missing_population"), 
+                            &export_without_unconnected, 
+                            &countries_where_country_count_is_applicable
+                        );
+                        p
+                    }
+                );
             
                 let total_export = top.iter()
                     .map(|&idx| job.original_exports[idx])
                     .sum::<f64>();
+                let total_production = top.iter()
+                    .map(|&idx| production[idx].max(0.0))
+                    .sum::<f64>();
+                let mut rows = Vec::new();
+                let mut convergence_summary = ConvergenceSummary::default();
             
                 loop{
                     let shock_result = multi_shock_distribution(&import_without_unconnected, &job);
             
-                    let remaining_export = top.iter()
-                        .map(|&idx| job.original_exports[idx] * shock_result.export_fracs[idx])
-                        .sum::<f64>();
-            
-                    let percent = remaining_export / total_export;
+                    let percent = match shock_type{
+                        ShockType::ExportRestriction => {
+                            let remaining_export = top.iter()
+                                .map(|&idx| job.original_exports[idx] * shock_result.export_fracs[idx])
+                                .sum::<f64>();
+                            remaining_export / total_export
+                        },
+                        ShockType::Production => {
+                            let remaining_production = top.iter()
+                                .map(|&idx| production[idx].max(0.0) - job.production_loss(idx))
+                                .sum::<f64>();
+                            if total_production > 0.0 {
+                                remaining_production / total_production
+                            } else {
+                                1.0
+                            }
+                        }
+                    };
             
                     let (avail_after_shock, _) = calc_available(
                         &export_without_unconnected, 
                         enrich, 
                         &shock_result, 
-                        &node_info_map,
+                        &ctx,
                         quiet
                     );
                     let mut country_counter = 0;
@@ -1408,7 +2058,44 @@ where P: AsRef<Path>
                             }
                         }
                     }
-                    writeln!(buf, "{} {percent} {country_counter}", x).unwrap();
+                    convergence_summary.add(&shock_result.convergence);
+                    write!(
+                        buf, 
+                        "{} {percent} {country_counter} {} {:e} {}", 
+                        x,
+                        shock_result.convergence.iterations,
+                        shock_result.convergence.residual,
+                        shock_result.convergence.flag()
+                    ).unwrap();
+                    if job.adaptation.is_some(){
+                        // same shock with the fixed topology of the classic model, for comparison
+                        let adaptation = job.adaptation.take();
+                        let fixed_result = multi_shock_distribution(&import_without_unconnected, &job);
+                        job.adaptation = adaptation;
+                        let (avail_fixed, _) = calc_available(
+                            &export_without_unconnected, 
+                            enrich, 
+                            &fixed_result, 
+                            &ctx,
+                            quiet
+                        );
+                        let fixed_counter = countries_where_country_count_is_applicable
+                            .iter()
+                            .filter(|&&idx| avail_fixed[idx] / no_shock[idx] < common_opt.unstable_country_threshold)
+                            .count();
+                        write!(buf, " {fixed_counter}").unwrap();
+                    }
+                    if let Some(p) = population.as_ref(){
+                        p.impact(
+                            &countries_where_country_count_is_applicable,
+                            &no_shock,
+                            &avail_after_shock,
+                            common_opt.unstable_country_threshold
+                        ).write(&mut buf)
+                        .unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                    rows.push((x, percent, country_counter));
                     match iterate(&mut job) {
                         None => break,
                         Some(d) => {
@@ -1416,7 +2103,15 @@ where P: AsRef<Path>
                         }
                     }
                 }
-                (group_out_name, year)
+                if convergence_summary.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} - {} of {} multi shocks did not converge",
+                        convergence_summary.not_converged,
+                        convergence_summary.runs
+                    );
+                }
+                convergence_summary.write_comment(&mut buf).unwrap();
+                (group_out_name, year, rows)
             }
         ).collect();
     
@@ -1451,7 +2146,7 @@ where P: AsRef<Path>
         }
         
         let paths = files.iter()
-            .map(|(p, _)| p.as_ref())
+            .map(|(p, _, _)| p.as_ref())
             .collect_vec();
         
         let name = format!(
@@ -1467,6 +2162,9 @@ where P: AsRef<Path>
         );
         println!("{name}");
     }
+    files.into_iter()
+        .map(|(_, year, rows)| (year, rows))
+        .collect()
 }
 
 pub fn shock_avail<P>(opt: ShockAvailOpts, in_file: P)
@@ -1480,9 +2178,31 @@ where P: AsRef<Utf8Path>
         TopSpecifier::Id(opt.top_id), 
         opt.export, 
         opt.iterations, 
-        &mut lazy_enrichment
+        opt.tolerance,
+        &mut lazy_enrichment,
+        &SimulationContext::default()
     );
     
+    let population = opt.population.then(
+        ||
+        {
+            let population = res.population(&lazy_enrichment, opt.year);
+            let countries = res.population_countries();
+            population.write_missing(
+                format!("{}.missing_population", opt.out),
+                &res.network,
+                &countries
+            );
+            let impact = population.impact(
+                &countries,
+                &res.available_before_shock,
+                &res.available_after_shock,
+                opt.unstable_country_threshold
+            );
+            (population, impact)
+        }
+    );
+
     let available_before_shock = res.available_before_shock;
     let avail_after_shock = res.available_after_shock;
     let focus = res.focus_index;
@@ -1506,17 +2226,32 @@ where P: AsRef<Utf8Path>
         .unwrap();
     let mut buf = BufWriter::new(file);
     write_commands_and_version(&mut buf).unwrap();
-    writeln!(buf, "#idx before_shock after_shock country").unwrap();
+    if let Some((_, impact)) = population.as_ref(){
+        write!(buf, "# {}:", PopulationImpact::HEADER.join(" ")).unwrap();
+        impact.write(&mut buf).unwrap();
+        writeln!(buf).unwrap();
+        writeln!(buf, "#idx before_shock after_shock country per_capita_loss").unwrap();
+    } else {
+        writeln!(buf, "#idx before_shock after_shock country").unwrap();
+    }
 
     for (idx, n) in res.network.nodes.iter().enumerate()
     {
-        writeln!(buf, 
+        write!(buf, 
             "{idx} {} {} {} {}",
             available_before_shock[idx],
             avail_after_shock[idx],
             available_before_shock[idx] - avail_after_shock[idx],
             n.identifier
         ).unwrap();
+        if let Some((p, _)) = population.as_ref(){
+            write!(
+                buf,
+                " {:e}",
+                p.per_capita_loss(idx, &available_before_shock, &avail_after_shock)
+            ).unwrap();
+        }
+        writeln!(buf).unwrap();
     }
 
 }
@@ -1580,7 +2315,9 @@ where P: AsRef<Utf8Path>
                 s.clone(), 
                 e, 
                 opt.iterations, 
-                &mut lazy_enrichments
+                opt.tolerance,
+                &mut lazy_enrichments,
+                &SimulationContext::default()
             );
 
             let focus = res.focus_index;
@@ -1752,6 +2489,34 @@ where P: AsRef<Utf8Path>
     write_header(&mut buf_import_totals);
     write_header(&mut buf_top0_dist);
 
+    let mut population = opt.population.then(
+        ||
+        {
+            let population = Population::new(
+                &export_without_unconnected,
+                lazy_enrichments.get_year_unchecked(opt.year),
+                &lazy_enrichments.extra_info_idmap_unchecked()
+            );
+            let all = (0..export_without_unconnected.node_count()).collect_vec();
+            population.write_missing(
+                format!("{stub}missing_population.dat"),
+                &export_without_unconnected,
+                &all
+            );
+            let per_capita_name = format!("{stub}per_capita_loss.dat");
+            let mut buf_per_capita = create_buf_with_command_and_version(per_capita_name);
+            write_header(&mut buf_per_capita);
+            let header = ["Export_frac", "specifier"]
+                .into_iter()
+                .chain(PopulationImpact::HEADER);
+            let buf_impact = create_buf_with_command_and_version_and_header(
+                format!("{stub}population.dat"),
+                header
+            );
+            (population, buf_per_capita, buf_impact)
+        }
+    );
+
     let export_diff = opt.export_end - opt.export_start;
     let export_delta = export_diff / (opt.export_samples - 1) as f64;
 
@@ -1789,9 +2554,10 @@ where P: AsRef<Utf8Path>
         let mut max = vec![f64::NEG_INFINITY; sum.len()];
         let mut min = vec![f64::INFINITY; sum.len()];
         let mut after_shock_avail_total = vec![0.0; sum.len()];
+        let mut per_capita_sum = vec![0.0; sum.len()];
         let mut is_top = true;
-        // Keep the warning for unused variable as reminder that
-        let mut flow_status = FlowStatus::AllGood;
+        // The variable is kept, even though it is never read, as reminder that
+        let mut _flow_status = FlowStatus::AllGood;
         // CURRENTLY FLOW STATUS IS IGNORED HERE!
         for (s_index, s) in specifiers.iter().enumerate(){
             let res = calc_shock(
@@ -1800,7 +2566,9 @@ where P: AsRef<Utf8Path>
                 s.clone(), 
                 e, 
                 opt.iterations, 
-                &mut lazy_enrichments
+                opt.tolerance,
+                &mut lazy_enrichments,
+                &SimulationContext::default()
             );
 
             let iter = res
@@ -1856,6 +2624,21 @@ where P: AsRef<Utf8Path>
                 .zip(after_shock_avail_total.iter_mut())
                 .for_each(|(v, acc)| *acc += v);
 
+            if let Some((p, _, buf_impact)) = population.as_mut(){
+                for (idx, acc) in per_capita_sum.iter_mut().enumerate(){
+                    *acc += p.per_capita_loss(idx, &res.available_before_shock, &res.available_after_shock);
+                }
+                write!(buf_impact, "{e} {}", s.get_string()).unwrap();
+                p.impact(
+                    &res.population_countries(),
+                    &res.available_before_shock,
+                    &res.available_after_shock,
+                    opt.unstable_country_threshold
+                ).write(&mut *buf_impact)
+                .unwrap();
+                writeln!(buf_impact).unwrap();
+            }
+
             if is_first{
                 foci.push(res.focus_index);
             }
@@ -1883,7 +2666,7 @@ where P: AsRef<Utf8Path>
                     }
                 );
             write_res(&mut buf_import, e, import_iter);
-            flow_status = res.flow_status;
+            _flow_status = res.flow_status;
         }
         is_first = false;
         sum.iter_mut()
@@ -1967,6 +2750,9 @@ where P: AsRef<Utf8Path>
         write_res(&mut buf_max, e, max);
         write_res(&mut buf_min, e, min);
         write_res(&mut buf_import_totals, e, after_shock_avail_total);
+        if let Some((_, buf_per_capita, _)) = population.as_mut(){
+            write_res(buf_per_capita, e, per_capita_sum.iter().map(|v| v * len_recip));
+        }
     }
 
     let write_focus = |buf: &mut BufWriter<File>|
@@ -2001,7 +2787,14 @@ where P: AsRef<Utf8Path>
     let original_dists = export_without_unconnected.distance_from_index(foci[0]);
     let extra = lazy_enrichments.get_year_unchecked(opt.year);
     let focus_id = &export_without_unconnected.nodes[foci[0]].identifier;
-    let flow = flow_calc(&export_without_unconnected, focus_id, opt.iterations, extra);
+    let flow = flow_calc(
+        &export_without_unconnected,
+        focus_id,
+        opt.iterations,
+        opt.tolerance,
+        extra,
+        &SimulationContext::default()
+    );
 
     let production_u8 = GLOBAL_NODE_INFO_MAP.deref().get(PRODUCTION);
 
@@ -2275,6 +3068,21 @@ where P: AsRef<Utf8Path>
         ).collect();
 
     let enrich_item_name_string = lazy_enrichment.item_codes_as_string_unchecked();
+    let mut population_buf = opt.population.then(
+        ||
+        {
+            let name = format!(
+                "{}_{}_y{}.population",
+                opt.top.get_string(),
+                enrich_item_name_string,
+                opt.year
+            );
+            let header = ["specifier", "export"]
+                .into_iter()
+                .chain(PopulationImpact::HEADER);
+            create_buf_with_command_and_version_and_header(name, header)
+        }
+    );
     for s in specifiers.iter(){
         let mut v = Vec::new();
         
@@ -2286,7 +3094,9 @@ where P: AsRef<Utf8Path>
                 s.clone(), 
                 e, 
                 opt.iterations, 
-                &mut lazy_enrichment
+                opt.tolerance,
+                &mut lazy_enrichment,
+                &SimulationContext::default()
             );
 
             let mut flow_status_name_addition = "";
@@ -2303,6 +3113,37 @@ where P: AsRef<Utf8Path>
                 opt.year
             );
 
+            if let Some(population_buf) = population_buf.as_mut(){
+                let population = res.population(&lazy_enrichment, opt.year);
+                let countries = res.population_countries();
+                let stub = name_stub.strip_suffix(".dat").unwrap();
+                population.write_missing(
+                    format!("{stub}.missing_population"),
+                    &res.network,
+                    &countries
+                );
+                let buf = create_buf_with_command_and_version_and_header(
+                    format!("{stub}.per_capita"),
+                    Population::per_capita_header(&res.network.unit)
+                );
+                population.write_per_capita(
+                    buf,
+                    &res.network,
+                    &countries,
+                    &res.available_before_shock,
+                    &res.available_after_shock
+                ).unwrap();
+                write!(population_buf, "{} {e}", s.get_string()).unwrap();
+                population.impact(
+                    &countries,
+                    &res.available_before_shock,
+                    &res.available_after_shock,
+                    opt.unstable_country_threshold
+                ).write(&mut *population_buf)
+                .unwrap();
+                writeln!(population_buf).unwrap();
+            }
+
             v.push(name_stub);
             let name = v.last().unwrap();
             if is_first{
@@ -2533,7 +3374,7 @@ pub enum FlowStatus{
 }
 
 impl FlowStatus{
-    fn name_addition(&self) -> &'static str
+    pub fn name_addition(&self) -> &'static str
     {
         match self{
             Self::AllGood => {
@@ -2546,16 +3387,42 @@ impl FlowStatus{
     }
 }
 
-fn calc_available(
+/// Production of every country of the network, 0 if unknown
+pub fn production_vec(
+    network: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    ctx: &SimulationContext
+) -> Vec<f64>
+{
+    let unit_tester = UNIT_TESTER.deref();
+    network.nodes
+        .iter()
+        .map(
+            |node|
+            {
+                enrich.get(node.identifier.as_str())
+                    .and_then(|extra| extra.map.get(&ctx.availability.production_id))
+                    .map_or(
+                        0.0,
+                        |p|
+                        {
+                            assert!(unit_tester.is_equiv(&p.unit, &network.unit));
+                            p.amount
+                        }
+                    )
+            }
+        ).collect()
+}
+
+pub(crate) fn calc_available(
     network: &Network,
     enrich: &BTreeMap<String, ExtraInfo>,
     shock: &ShockRes,
-    node_map: &ExtraInfoMap,
+    ctx: &SimulationContext,
     quiet: bool
 ) -> (Vec<f64>, FlowStatus)
 {
-    let mode_lock = MODE.read().unwrap();
-    let mode = mode_lock.deref();
+    let mode = &ctx.mode;
     let inverted = network.invert();
     let (import, export) = match network.direction{
         Direction::ExportTo => (&inverted, network),
@@ -2566,9 +3433,9 @@ fn calc_available(
     let original_export = calc_acc_trade(export);
 
 
-    let production_id = node_map.get(PRODUCTION);
-    let stock_id = node_map.get(STOCK);
-    let stock_variation_id = node_map.get(STOCK_VARIATION);
+    let production_id = ctx.availability.production_id;
+    let stock_id = ctx.availability.stock_id;
+    let stock_variation_id = ctx.availability.stock_variation_id;
     let mut at_least_some_stock = false;
     let mut at_least_some_stock_variation = false;
     let mut at_least_some_production = false;
@@ -2592,6 +3459,10 @@ fn calc_available(
                         assert!(unit_tester.is_equiv(unit, &production.unit));
                         total += production.amount;
                         at_least_some_production = true;
+                        // lost production due to production shocks
+                        if let Some(loss) = shock.production_loss.get(i){
+                            total -= loss;
+                        }
                     }
 
                     match mode {
@@ -2660,7 +3531,6 @@ fn calc_available(
         }
     }
 
-    drop(mode_lock);
     let status = if missing.is_empty(){
         FlowStatus::AllGood
     } else {
@@ -2672,12 +3542,13 @@ fn calc_available(
 
 // uses: https://de.mathworks.com/matlabcentral/fileexchange/9700-random-vectors-with-fixed-sum
 // see also: https://www.cs.york.ac.uk/rts/static/papers/R:Emberson:2010a.pdf
-fn rand_fixed_sum<R>(
+pub(crate) fn rand_fixed_sum<R>(
     n: usize, 
     m: NonZeroUsize, 
     sum: f64, 
     a: f64, 
     b: f64,
+    points: Option<&mut PointSource>,
     mut rng: R
 ) -> Vec<Vec<f64>> 
 where R: Rng
@@ -2690,7 +3561,6 @@ where R: Rng
         return vec![vec![1.0; n]; m.get()];
     }
     let b_minus_a = b - a;
-    let dist = Uniform::new(0.0, 1.0);
     let rescale = (sum-n as f64 * a)/(b_minus_a);
     let k = (rescale.floor() as isize).min((n-1) as isize).max(0);
     let s = rescale.min((k + 1) as f64).max(k as f64);
@@ -2758,20 +3628,44 @@ where R: Rng
     }
 
     let mut x = vec![vec![0.0; m.get()]; n];
-    let mut gen_rand = |len: usize|
-    {
-        (0..len)
-        .map(
-            |_|
+    let (rt, rs) = match points{
+        Some(points) => {
+            // the first n-1 coordinates of every point choose the transitions,
+            // the remaining ones the simplex coordinates
+            let p = points.points(m.get(), &mut rng);
+            let transposed = |offset: usize|
             {
-                dist.sample_iter(&mut rng)
-                    .take(m.get())
-                    .collect_vec()
-            }
-        ).collect_vec()
+                (offset..offset + n - 1)
+                    .map(
+                        |d|
+                        {
+                            p.iter()
+                                .map(|point| point[d])
+                                .collect_vec()
+                        }
+                    ).collect_vec()
+            };
+            (transposed(0), transposed(n - 1))
+        },
+        None => {
+            let dist = Uniform::new(0.0, 1.0);
+            let mut gen_rand = |len: usize|
+            {
+                (0..len)
+                .map(
+                    |_|
+                    {
+                        dist.sample_iter(&mut rng)
+                            .take(m.get())
+                            .collect_vec()
+                    }
+                ).collect_vec()
+            };
+            let rt = gen_rand(n-1);
+            let rs = gen_rand(n-1);
+            (rt, rs)
+        }
     };
-    let rt = gen_rand(n-1);
-    let rs = gen_rand(n-1);
     let mut s = vec![s; m.get()];
     let mut j = vec![(k+1) as usize; m.get()];
     let mut sm = vec![0.0; m.get()];
@@ -2862,16 +3756,16 @@ pub fn check_quick_and_dirty(
     network: &Network, 
     enrich: &BTreeMap<String, ExtraInfo>,
     product_id: &str,
-    year: i32
+    year: i32,
+    ctx: &SimulationContext
 ) -> bool
 {
-    let mode_lock = MODE.read().unwrap();
-    let mode = mode_lock.deref();
+    let mode = &ctx.mode;
     eprint!("Y {year} - ");
     let import_dir = network.get_network_with_direction(Direction::ImportFrom);
-    let production_key = GLOBAL_NODE_INFO_MAP.get(PRODUCTION);
-    let stock_key = GLOBAL_NODE_INFO_MAP.get(STOCK);
-    let stock_variation_key = GLOBAL_NODE_INFO_MAP.get(STOCK_VARIATION);
+    let production_key = ctx.availability.production_id;
+    let stock_key = ctx.availability.stock_id;
+    let stock_variation_key = ctx.availability.stock_variation_id;
     let mut is_good = true;
     for idx in top{
         let id = network.nodes[*idx]
@@ -2922,7 +3816,6 @@ pub fn check_quick_and_dirty(
             is_good = false;
         }
     }
-    drop(mode_lock);
     if is_good{
         eprintln!("Product {product_id} is good");
     }
@@ -7,6 +7,7 @@ use std::{
     num::*
 };
 use serde::{Serialize, Deserialize};
+use super::{shock_solver::SolverBackend, rerouting::Adaptation, samplers::Sampler, cloud_stats::CloudDistribution, ledger::fnv1a};
 
 pub fn calc_acc_trade(network: &Network) -> Vec<f64>
 {
@@ -25,12 +26,138 @@ pub fn calc_recip(original: &[f64]) -> Vec<f64>
         .collect()
 }
 
+/// Default for the tolerance of the iterative shock propagation
+pub const DEFAULT_TOLERANCE: f64 = 1e-12;
+
+pub(crate) fn default_tolerance() -> f64
+{
+    DEFAULT_TOLERANCE
+}
+
+/// How the iteration of a shock propagation ended
+#[derive(Debug, Clone, Copy)]
+pub struct Convergence{
+    /// Number of performed sweeps
+    pub iterations: usize,
+    /// Largest change of any fraction during the last sweep
+    pub residual: f64,
+    pub converged: bool
+}
+
+impl Default for Convergence{
+    fn default() -> Self {
+        Self{
+            iterations: 0,
+            residual: f64::INFINITY,
+            converged: false
+        }
+    }
+}
+
+impl Convergence{
+    /// Record a sweep. Returns true if the iteration can stop
+    #[inline]
+    pub fn update(&mut self, residual: f64, tolerance: f64) -> bool
+    {
+        self.iterations += 1;
+        self.residual = residual;
+        self.converged = residual <= tolerance;
+        self.converged
+    }
+
+    /// Converged: 1, not converged: 0
+    #[inline]
+    pub fn flag(&self) -> u8
+    {
+        self.converged as u8
+    }
+
+    pub fn warn_if_not_converged(&self, what: &str)
+    {
+        if !self.converged {
+            eprintln!(
+                "WARNING: {what} did not converge after {} iterations, residual {:e}",
+                self.iterations,
+                self.residual
+            );
+        }
+    }
+}
+
+/// Accumulates the convergence of many runs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConvergenceSummary{
+    pub runs: usize,
+    pub not_converged: usize,
+    pub max_iterations: usize,
+    pub max_residual: f64
+}
+
+impl ConvergenceSummary{
+    pub fn add(&mut self, c: &Convergence)
+    {
+        self.runs += 1;
+        if !c.converged {
+            self.not_converged += 1;
+        }
+        self.max_iterations = self.max_iterations.max(c.iterations);
+        self.max_residual = self.max_residual.max(c.residual);
+    }
+
+    pub fn merge(&mut self, other: &Self)
+    {
+        self.runs += other.runs;
+        self.not_converged += other.not_converged;
+        self.max_iterations = self.max_iterations.max(other.max_iterations);
+        self.max_residual = self.max_residual.max(other.max_residual);
+    }
+
+    pub fn write_comment<W: std::io::Write>(&self, mut w: W) -> std::io::Result<()>
+    {
+        writeln!(
+            w,
+            "# runs {} not_converged {} max_iterations {} max_residual {:e}",
+            self.runs,
+            self.not_converged,
+            self.max_iterations,
+            self.max_residual
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct ExportShockItem{
     pub export_id: usize,
     pub export_frac: f64
 }
 
+#[derive(Debug)]
+pub struct ProductionShockItem{
+    pub country_id: usize,
+    /// fraction of the original production that remains
+    pub production_frac: f64
+}
+
+/// What is disrupted
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ShockType{
+    /// Countries restrict their exports
+    #[default]
+    ExportRestriction,
+    /// The production of countries drops, the deficit is passed downstream
+    Production
+}
+
+impl ShockType{
+    pub fn name_addition(self) -> &'static str
+    {
+        match self{
+            Self::ExportRestriction => "",
+            Self::Production => "_Prod"
+        }
+    }
+}
+
 pub struct CalcShockMultiJob<'a>{
     pub exporter: Vec<ExportShockItem>,
     pub unrestricted_node_idxs: Vec<usize>,
@@ -38,7 +165,20 @@ pub struct CalcShockMultiJob<'a>{
     pub original_imports_recip: &'a [f64],
     pub original_exports: &'a [f64],
     pub original_exports_recip: &'a [f64],
-    pub iterations: usize
+    /// Maximal number of iterations
+    pub iterations: usize,
+    /// Stop once no fraction changes by more than this during one sweep
+    pub tolerance: f64,
+    pub solver: SolverBackend,
+    /// Node order for Gauss-Seidel, natural order if empty
+    pub sweep_order: &'a [usize],
+    pub production_shocks: Vec<ProductionShockItem>,
+    /// Original production of all countries, empty if there are no production shocks
+    pub production: &'a [f64],
+    /// Lost production of all countries, empty if there are no production shocks
+    pub production_loss: Vec<f64>,
+    /// Importers reroute their demand if this is set
+    pub adaptation: Option<Adaptation>
 }
 
 
@@ -84,6 +224,13 @@ impl<'a> CalcShockMultiJob<'a>{
             exporter, 
             unrestricted_node_idxs: free_ids, 
             iterations, 
+            tolerance: DEFAULT_TOLERANCE,
+            solver: SolverBackend::Jacobi,
+            sweep_order: &[],
+            production_shocks: Vec::new(),
+            production: &[],
+            production_loss: Vec::new(),
+            adaptation: None,
             original_exports, 
             original_imports,
             original_imports_recip,
@@ -140,13 +287,159 @@ impl<'a> CalcShockMultiJob<'a>{
             exporter, 
             unrestricted_node_idxs: free_ids, 
             iterations, 
+            tolerance: DEFAULT_TOLERANCE,
+            solver: SolverBackend::Jacobi,
+            sweep_order: &[],
+            production_shocks: Vec::new(),
+            production: &[],
+            production_loss: Vec::new(),
+            adaptation: None,
+            original_exports, 
+            original_imports,
+            original_imports_recip,
+            original_exports_recip
+        }
+    }
+
+    /// Nobody restricts exports, instead the production of the 
+    /// given countries drops
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_production(
+        production_shocks: Vec<ProductionShockItem>,
+        production: &'a [f64],
+        iterations: usize,
+        export_network: &Network,
+        original_imports: &'a[f64],
+        original_imports_recip: &'a[f64],
+        original_exports: &'a[f64],
+        original_exports_recip: &'a[f64],
+    ) -> Self
+    {
+        assert_eq!(production.len(), export_network.node_count());
+        let mut job = Self { 
+            exporter: Vec::new(), 
+            unrestricted_node_idxs: (0..export_network.node_count()).collect(), 
+            iterations, 
+            tolerance: DEFAULT_TOLERANCE,
+            solver: SolverBackend::Jacobi,
+            sweep_order: &[],
+            production_shocks,
+            production,
+            production_loss: vec![0.0; production.len()],
+            adaptation: None,
             original_exports, 
             original_imports,
             original_imports_recip,
             original_exports_recip
+        };
+        job.update_production_loss();
+        job
+    }
+
+    /// Production shock for the given countries, all with the same remaining fraction
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_const_production(
+        ids: &[usize],
+        production_frac: f64,
+        production: &'a [f64],
+        iterations: usize,
+        export_network: &Network,
+        original_exports: &'a [f64],
+        original_exports_recip: &'a [f64],
+        original_imports: &'a [f64],
+        original_imports_recip: &'a [f64]
+    ) -> Self
+    {
+        let shocks = ids.iter()
+            .map(|&country_id| ProductionShockItem{country_id, production_frac})
+            .collect();
+        Self::new_production(
+            shocks, 
+            production, 
+            iterations, 
+            export_network, 
+            original_imports, 
+            original_imports_recip, 
+            original_exports, 
+            original_exports_recip
+        )
+    }
+
+    fn update_production_loss(&mut self)
+    {
+        self.production_loss
+            .iter_mut()
+            .for_each(|l| *l = 0.0);
+        for shock in self.production_shocks.iter(){
+            let p = self.production[shock.country_id].max(0.0);
+            self.production_loss[shock.country_id] = p * (1.0 - shock.production_frac);
         }
     }
 
+    /// Lost production of the country, 0 if there are no production shocks
+    #[inline]
+    pub fn production_loss(&self, index: usize) -> f64
+    {
+        self.production_loss
+            .get(index)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn change_production_frac(&mut self, production_frac: f64)
+    {
+        self.production_shocks
+            .iter_mut()
+            .for_each(|s| s.production_frac = production_frac);
+        self.update_production_loss();
+    }
+
+    pub fn add_production_shock(&mut self, shock: ProductionShockItem)
+    {
+        self.production_shocks.push(shock);
+        self.update_production_loss();
+    }
+
+    /// Remaining production of the shocked countries divided by their original production.
+    /// 1 if the shocked countries do not produce anything, as nothing can be lost
+    pub fn remaining_production_frac(&self) -> f64
+    {
+        let (remaining, total) = self.production_shocks
+            .iter()
+            .fold(
+                (0.0, 0.0),
+                |(remaining, total), s|
+                {
+                    let p = self.production[s.country_id].max(0.0);
+                    (remaining + p * s.production_frac, total + p)
+                }
+            );
+        if total > 0.0 {
+            remaining / total
+        } else {
+            1.0
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self
+    {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_adaptation(mut self, adaptation: Option<Adaptation>) -> Self
+    {
+        self.adaptation = adaptation;
+        self
+    }
+
+    pub fn with_solver(mut self, solver: SolverBackend, sweep_order: &'a [usize]) -> Self
+    {
+        self.solver = solver;
+        self.sweep_order = sweep_order;
+        self
+    }
+
     pub fn change_export_frac(&mut self, export_frac: f64)
     {
         self.exporter
@@ -205,6 +498,42 @@ pub struct ShockCloud
     #[derivative(Default(value="10000"))]
     pub iterations: usize,    
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// iterations is then only the upper limit
+    #[serde(default = "default_tolerance")]
+    #[derivative(Default(value="DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm used for the shock propagation.
+    /// Jacobi, GaussSeidel or Anderson
+    #[serde(default)]
+    pub solver: SolverBackend,
+
+    /// Restrict the exports or reduce the production of the countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// If given, importers try to replace missing imports
+    /// by importing more from their other partners.
+    /// Only works with the Jacobi solver
+    #[serde(default)]
+    pub adaptation: Option<Adaptation>,
+
+    /// Also report the number of affected people and population weighted losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[serde(default)]
+    pub population: bool,
+
+    /// How the export fractions of the cloud are sampled.
+    /// Random, Halton, Sobol, LatinHypercube or Importance
+    #[serde(default)]
+    pub sampler: Sampler,
+
+    /// If given, per bin quantiles, the count histogram, exceedance probabilities
+    /// and bootstrap confidence intervals of the averages are written as well
+    #[serde(default)]
+    pub distribution: Option<CloudDistribution>,
+
     /// Item code, e.g. 27 for Rice
     pub item_code: Option<String>,
 
@@ -238,6 +567,16 @@ pub struct ShockCloud
     pub hist_bins: NonZeroUsize
 }
 
+impl ShockCloud{
+    /// Hash of the configuration and the simulated modes.
+    /// Used to detect changed settings when a run is resumed
+    pub fn config_hash(&self, modes: &str) -> u64
+    {
+        let json = serde_json::to_string(self).unwrap();
+        fnv1a(format!("{json} {modes}").as_bytes())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Derivative)]
 #[derivative(Default)]
 pub struct ShockCloudAll
@@ -256,6 +595,42 @@ pub struct ShockCloudAll
     #[derivative(Default(value="10000"))]
     pub iterations: usize,    
 
+    /// Stop iterating once no fraction changes by more than this during one sweep.
+    /// iterations is then only the upper limit
+    #[serde(default = "default_tolerance")]
+    #[derivative(Default(value="DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm used for the shock propagation.
+    /// Jacobi, GaussSeidel or Anderson
+    #[serde(default)]
+    pub solver: SolverBackend,
+
+    /// Restrict the exports or reduce the production of the countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// If given, importers try to replace missing imports
+    /// by importing more from their other partners.
+    /// Only works with the Jacobi solver
+    #[serde(default)]
+    pub adaptation: Option<Adaptation>,
+
+    /// Also report the number of affected people and population weighted losses.
+    /// Needs TOTAL_POPULATION in the enrichment file
+    #[serde(default)]
+    pub population: bool,
+
+    /// How the export fractions of the cloud are sampled.
+    /// Random, Halton, Sobol, LatinHypercube or Importance
+    #[serde(default)]
+    pub sampler: Sampler,
+
+    /// If given, per bin quantiles, the count histogram, exceedance probabilities
+    /// and bootstrap confidence intervals of the averages are written as well
+    #[serde(default)]
+    pub distribution: Option<CloudDistribution>,
+
     /// how many countrys should restrict their exports?
     #[derivative(Default(value="5"))]
     pub top: usize,
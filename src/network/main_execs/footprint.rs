@@ -0,0 +1,462 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::{Deref, RangeInclusive}
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *},
+    UNIT_TESTER
+};
+use super::{
+    flow_helper::*,
+    origin::*,
+    production_vec,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct FootprintOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "footprint")]
+    pub out_stub: String,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FootprintItem{
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>
+}
+
+/// A resource that is used to produce the items
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Resource{
+    /// Area harvested per unit of production.
+    /// Uses the Yield if the area is missing
+    Cropland,
+    /// Amount of any enrichment entry per unit of production
+    Enrichment(String),
+    /// Whitespace separated table with the lines `country intensity`
+    /// or `item_code country intensity`, where the intensity is the
+    /// amount of the resource per unit of production.
+    /// Item specific lines take precedence. Lines starting with # are ignored
+    Table{
+        name: String,
+        file: Utf8PathBuf
+    }
+}
+
+impl Resource{
+    pub fn name(&self) -> &str
+    {
+        match self{
+            Self::Cropland => "cropland",
+            Self::Enrichment(e) => e.as_str(),
+            Self::Table{name, ..} => name.as_str()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct Footprint{
+    /// The footprints are calculated for every item and summed up afterwards
+    #[derivative(Default(value = "vec![FootprintItem::default()]"))]
+    pub items: Vec<FootprintItem>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    #[derivative(Default(value = "vec![Resource::Cropland]"))]
+    pub resources: Vec<Resource>,
+
+    /// Maximal number of iterations of the origin attribution
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no share changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64
+}
+
+/// User supplied intensities of one resource
+#[derive(Default)]
+pub struct IntensityTable{
+    all_items: BTreeMap<String, f64>,
+    per_item: BTreeMap<(String, String), f64>
+}
+
+impl IntensityTable{
+    pub fn read(file: &Utf8PathBuf) -> Self
+    {
+        let mut table = Self::default();
+        for line in open_as_unwrapped_lines(file){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entries = line.split_whitespace().collect_vec();
+            let parse = |s: &str| -> f64 {
+                s.parse()
+                    .unwrap_or_else(|_| panic!("Invalid intensity {s} in {file}"))
+            };
+            match entries.as_slice(){
+                [country, intensity] => {
+                    table.all_items.insert(country.to_string(), parse(intensity));
+                },
+                [item, country, intensity] => {
+                    table.per_item.insert((item.to_string(), country.to_string()), parse(intensity));
+                },
+                _ => panic!("Invalid line in {file}: {line}")
+            }
+        }
+        table
+    }
+
+    pub fn get(&self, item: Option<&str>, country: &str) -> f64
+    {
+        item.and_then(|i| self.per_item.get(&(i.to_owned(), country.to_owned())))
+            .or_else(|| self.all_items.get(country))
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+/// Conversion of the yield unit to tonnes per hectare
+fn yield_to_tonnes_per_ha(unit: &str) -> Option<f64>
+{
+    match unit{
+        "hg/ha" | "100 g/ha" => Some(1e-4),
+        "kg/ha" => Some(1e-3),
+        "t/ha" | "tonnes/ha" => Some(1.0),
+        _ => None
+    }
+}
+
+/// Harvested area per unit of production.
+/// If the area is missing it is calculated from the yield, which requires the network to be in tonnes
+pub fn cropland_intensity(
+    network: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    node_map: &ExtraInfoMap,
+    ctx: &SimulationContext
+) -> Vec<f64>
+{
+    let from_area = resource_intensity(network, enrich, node_map, ctx, AREA_HARVESTED);
+    let yield_id = node_map.get(YIELD);
+    let in_tonnes = UNIT_TESTER.deref().is_equiv(&network.unit, "t");
+    from_area.into_iter()
+        .zip(network.nodes.iter())
+        .map(
+            |(area, node)|
+            {
+                if area > 0.0 || !in_tonnes {
+                    return area;
+                }
+                enrich.get(node.identifier.as_str())
+                    .and_then(|e| e.map.get(&yield_id))
+                    .filter(|y| y.amount > 0.0)
+                    .and_then(|y| yield_to_tonnes_per_ha(&y.unit).map(|f| (y.amount * f).recip()))
+                    .unwrap_or(0.0)
+            }
+        ).collect()
+}
+
+/// Footprints of all countries for one item and year
+struct ItemFootprint{
+    countries: Vec<String>,
+    /// supply minus exports
+    consumption: Vec<f64>,
+    /// [resource][country] resource embodied in the consumption
+    footprint: Vec<Vec<f64>>,
+    /// [resource][country] resource used for the domestic production
+    domestic_use: Vec<Vec<f64>>,
+    /// exporter, importer, amount, embodied resources
+    bilateral: Vec<(usize, usize, f64, Vec<f64>)>,
+    convergence: Convergence
+}
+
+fn item_footprint(
+    json: &Footprint,
+    item: &FootprintItem,
+    tables: &[Option<IntensityTable>],
+    network: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    node_map: &ExtraInfoMap,
+    ctx: &SimulationContext
+) -> ItemFootprint
+{
+    let export = network.without_unconnected_nodes();
+    let import = export.invert();
+    let domestic = domestic_supply(&export, enrich, ctx);
+    let production = production_vec(&export, enrich, ctx);
+    let origins = origin_shares(&import, &domestic, json.iterations, json.tolerance);
+    let original_exports = calc_acc_trade(&export);
+    let consumption = origins.supply
+        .iter()
+        .zip(original_exports.iter())
+        .map(|(s, e)| (s - e).max(0.0))
+        .collect_vec();
+
+    let intensities = json.resources
+        .iter()
+        .zip(tables.iter())
+        .map(
+            |(resource, table)|
+            {
+                match resource{
+                    Resource::Cropland => cropland_intensity(&export, enrich, node_map, ctx),
+                    Resource::Enrichment(e) => resource_intensity(&export, enrich, node_map, ctx, e),
+                    Resource::Table{..} => {
+                        let table = table.as_ref().unwrap();
+                        export.nodes
+                            .iter()
+                            .map(|n| table.get(item.item_code.as_deref(), &n.identifier))
+                            .collect()
+                    }
+                }
+            }
+        ).collect_vec();
+
+    let embodied = intensities.iter()
+        .map(|i| origins.embodied_per_unit(i))
+        .collect_vec();
+    let footprint = embodied.iter()
+        .map(
+            |e|
+            {
+                e.iter()
+                    .zip(consumption.iter())
+                    .map(|(e, c)| e * c)
+                    .collect()
+            }
+        ).collect();
+    let domestic_use = intensities.iter()
+        .map(
+            |i|
+            {
+                i.iter()
+                    .zip(production.iter())
+                    .map(|(i, p)| i * p.max(0.0))
+                    .collect()
+            }
+        ).collect();
+    let bilateral = virtual_flows(&export, &embodied[0])
+        .map(
+            |(i, j, amount, _)|
+            {
+                let virt = embodied.iter()
+                    .map(|e| amount * e[i])
+                    .collect();
+                (i, j, amount, virt)
+            }
+        ).collect();
+
+    ItemFootprint{
+        countries: export.nodes
+            .iter()
+            .map(|n| n.identifier.clone())
+            .collect(),
+        consumption,
+        footprint,
+        domestic_use,
+        bilateral,
+        convergence: origins.convergence
+    }
+}
+
+pub fn footprint(opt: FootprintOpts)
+{
+    let json: Footprint = parse_and_add_to_global(opt.json);
+    assert!(!json.resources.is_empty(), "At least one resource is required");
+    let tables = json.resources
+        .iter()
+        .map(
+            |r|
+            {
+                match r{
+                    Resource::Table{file, ..} => Some(IntensityTable::read(file)),
+                    _ => None
+                }
+            }
+        ).collect_vec();
+
+    let lazy = json.items
+        .iter()
+        .map(
+            |item|
+            {
+                let mut lazy_networks = LazyNetworks::Filename(item.network_file.clone());
+                lazy_networks.assure_availability();
+                let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+                    item.enrich_file.clone(),
+                    item.item_code.clone()
+                );
+                lazy_enrichments.assure_availability();
+                (lazy_networks, lazy_enrichments)
+            }
+        ).collect_vec();
+    let mode_str = opt.mode.as_str();
+    let resource_names = json.resources
+        .iter()
+        .map(|r| r.name().replace(' ', "_"))
+        .collect_vec();
+
+    json.years
+        .clone()
+        .into_par_iter()
+        .for_each(
+            |year|
+            {
+                // country -> [consumption footprints..., domestic uses...]
+                let mut total: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+                let mut total_bilateral: BTreeMap<(String, String), Vec<f64>> = BTreeMap::new();
+                let resource_count = json.resources.len();
+                let mut skipped = Vec::new();
+
+                for (item, (lazy_networks, lazy_enrichments)) in json.items.iter().zip(lazy.iter()){
+                    let item_name = item.item_code.as_deref().unwrap_or("?");
+                    let network = match lazy_networks.export_networks_unchecked()
+                        .iter()
+                        .find(|n| n.year == year)
+                    {
+                        Some(n) => n,
+                        None => {
+                            println!("Year {year} missing for item {item_name} - SKIPPING ITEM");
+                            skipped.push(item_name);
+                            continue;
+                        }
+                    };
+                    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+                    let ctx = SimulationContext::new(opt.mode)
+                        .with_node_map(&node_map);
+                    let enrich = lazy_enrichments.enrichment_infos_unchecked().get_year(year);
+                    let fp = item_footprint(&json, item, &tables, network, enrich, &node_map, &ctx);
+                    fp.convergence.warn_if_not_converged("footprint");
+
+                    let item_str = item.item_code
+                        .as_deref()
+                        .map(|i| format!("_Item{i}"))
+                        .unwrap_or_default();
+                    let stub = format!("{}{item_str}_Y{year}_{mode_str}", opt.out_stub);
+
+                    let header = ["country", "consumption"]
+                        .into_iter()
+                        .map(str::to_owned)
+                        .chain(resource_names.iter().map(|r| format!("{r}_footprint")))
+                        .chain(resource_names.iter().map(|r| format!("{r}_domestic_use")));
+                    let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.footprint"), header);
+                    for (c, country) in fp.countries.iter().enumerate(){
+                        write!(buf, "{country} {:e}", fp.consumption[c]).unwrap();
+                        let entry = total.entry(country.clone())
+                            .or_insert_with(|| vec![0.0; 2 * resource_count]);
+                        for (r, (f, d)) in fp.footprint.iter().zip(fp.domestic_use.iter()).enumerate(){
+                            entry[r] += f[c];
+                            entry[resource_count + r] += d[c];
+                        }
+                        for f in fp.footprint.iter().chain(fp.domestic_use.iter()){
+                            write!(buf, " {:e}", f[c]).unwrap();
+                        }
+                        writeln!(buf).unwrap();
+                    }
+
+                    let header = ["exporter", "importer", "amount"]
+                        .into_iter()
+                        .map(str::to_owned)
+                        .chain(resource_names.iter().cloned());
+                    let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.bilateral"), header);
+                    for (i, j, amount, virt) in fp.bilateral.iter(){
+                        let exporter = &fp.countries[*i];
+                        let importer = &fp.countries[*j];
+                        write!(buf, "{exporter} {importer} {amount:e}").unwrap();
+                        for v in virt.iter(){
+                            write!(buf, " {v:e}").unwrap();
+                        }
+                        writeln!(buf).unwrap();
+                        let entry = total_bilateral.entry((exporter.clone(), importer.clone()))
+                            .or_insert_with(|| vec![0.0; resource_count]);
+                        for (e, v) in entry.iter_mut().zip(virt.iter()){
+                            *e += v;
+                        }
+                    }
+                }
+
+                // sum over all items
+                let stub = format!("{}_Y{year}_{mode_str}_total", opt.out_stub);
+                let header = std::iter::once("country".to_owned())
+                    .chain(resource_names.iter().map(|r| format!("{r}_footprint")))
+                    .chain(resource_names.iter().map(|r| format!("{r}_domestic_use")))
+                    .chain(resource_names.iter().map(|r| format!("{r}_net_import")));
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.footprint"), header);
+                writeln!(
+                    buf,
+                    "# items {}",
+                    json.items.iter()
+                        .filter_map(|i| i.item_code.as_deref())
+                        .filter(|i| !skipped.contains(i))
+                        .join(",")
+                ).unwrap();
+                if !skipped.is_empty(){
+                    writeln!(buf, "# skipped items without network in year {year}: {}", skipped.join(",")).unwrap();
+                }
+                for (country, values) in total.iter(){
+                    write!(buf, "{country}").unwrap();
+                    for v in values.iter(){
+                        write!(buf, " {v:e}").unwrap();
+                    }
+                    let (footprint, domestic_use) = values.split_at(resource_count);
+                    for (f, d) in footprint.iter().zip(domestic_use.iter()){
+                        write!(buf, " {:e}", f - d).unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                }
+
+                let header = ["exporter", "importer"]
+                    .into_iter()
+                    .map(str::to_owned)
+                    .chain(resource_names.iter().cloned());
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.bilateral"), header);
+                for ((exporter, importer), values) in total_bilateral.iter(){
+                    write!(buf, "{exporter} {importer}").unwrap();
+                    for v in values.iter(){
+                        write!(buf, " {v:e}").unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yield_units() {
+        // 50000 hg/ha are 5 t/ha, i.e. 0.2 ha per tonne
+        let f = yield_to_tonnes_per_ha("hg/ha").unwrap();
+        assert!(((50000.0 * f).recip() - 0.2).abs() < 1e-12);
+        assert!(yield_to_tonnes_per_ha("bushels").is_none());
+    }
+}
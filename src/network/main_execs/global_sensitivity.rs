@@ -0,0 +1,450 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    samplers::Sampler,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    production_vec,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct GlobalSensitivityOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "global_sensitivity")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool
+}
+
+/// Range a parameter is sampled from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ParameterRange{
+    pub min: f64,
+    pub max: f64,
+    /// Sample uniformly in log space, both bounds need to be positive
+    #[serde(default)]
+    pub log: bool
+}
+
+impl ParameterRange{
+    /// Panics if the range cannot be sampled
+    pub fn validate(&self, name: &str)
+    {
+        assert!(
+            self.min <= self.max,
+            "{name}: min {} is larger than max {}",
+            self.min,
+            self.max
+        );
+        assert!(
+            !self.log || self.min > 0.0,
+            "{name}: log ranges need positive bounds"
+        );
+    }
+
+    /// Maps u in [0,1) onto the range
+    pub fn map(&self, u: f64) -> f64
+    {
+        if self.log {
+            let (min, max) = (self.min.ln(), self.max.ln());
+            (min + u * (max - min)).exp()
+        } else {
+            self.min + u * (self.max - self.min)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct GlobalSensitivity{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    #[derivative(Default(value = "ParameterRange{min: 0.5, max: 0.9, log: false}"))]
+    pub unstable_country_threshold: ParameterRange,
+
+    #[derivative(Default(value = "ParameterRange{min: 1e-9, max: 1e3, log: true}"))]
+    pub original_avail_filter: ParameterRange,
+
+    /// Remaining fraction of the exports or production of each top country
+    #[derivative(Default(value = "ParameterRange{min: 0.0, max: 1.0, log: false}"))]
+    pub reducing_factor: ParameterRange,
+
+    /// Modes that are sampled with equal probability
+    #[derivative(Default(value = "vec![SimulationMode::Classic, SimulationMode::WithStockVariation, SimulationMode::OnlyStock]"))]
+    pub modes: Vec<SimulationMode>,
+
+    /// Number of top exporters that are shocked, sampled with equal probability
+    #[derivative(Default(value = "1..=10"))]
+    pub top: RangeInclusive<usize>,
+
+    /// Restrict the exports or reduce the production of the top countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// Number of base samples of the Saltelli design.
+    /// The model is evaluated base_samples * (parameters + 2) times per year
+    #[derivative(Default(value = "256"))]
+    pub base_samples: usize,
+
+    pub seed: u64,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend
+}
+
+const PARAMETER_NAMES: [&str; 5] = [
+    "unstable_country_threshold",
+    "original_avail_filter",
+    "reducing_factor",
+    "mode",
+    "top"
+];
+
+const OUTPUT_NAMES: [&str; 3] = [
+    "unstable_countries",
+    "unstable_fraction",
+    "missing_supply"
+];
+
+/// One point of the parameter space
+#[derive(Debug, Clone, Copy)]
+struct Parameters{
+    threshold: f64,
+    avail_filter: f64,
+    reducing_factor: f64,
+    mode: SimulationMode,
+    top: usize
+}
+
+impl GlobalSensitivity{
+    fn parameters(&self, u: &[f64]) -> Parameters
+    {
+        let pick = |u: f64, len: usize| ((u * len as f64) as usize).min(len - 1);
+        let tops = self.top.clone().collect_vec();
+        Parameters{
+            threshold: self.unstable_country_threshold.map(u[0]),
+            avail_filter: self.original_avail_filter.map(u[1]),
+            reducing_factor: self.reducing_factor.map(u[2]).clamp(0.0, 1.0),
+            mode: self.modes[pick(u[3], self.modes.len())],
+            top: tops[pick(u[4], tops.len())]
+        }
+    }
+}
+
+/// Saltelli estimator of the first order index and Jansen estimator of the total index
+/// of every parameter. f_ab[i] are the evaluations of A with column i taken from B
+pub fn sobol_indices(f_a: &[f64], f_b: &[f64], f_ab: &[Vec<f64>]) -> Vec<(f64, f64)>
+{
+    let n = f_a.len() as f64;
+    let all = f_a.iter().chain(f_b.iter());
+    let mean = all.clone().sum::<f64>() / (2.0 * n);
+    let variance = all.map(|f| (f - mean) * (f - mean)).sum::<f64>() / (2.0 * n);
+    f_ab.iter()
+        .map(
+            |f_ab_i|
+            {
+                let mut first = 0.0;
+                let mut total = 0.0;
+                for ((a, b), ab) in f_a.iter().zip(f_b.iter()).zip(f_ab_i.iter()){
+                    first += b * (ab - a);
+                    total += (a - ab) * (a - ab);
+                }
+                (first / (n * variance), total / (2.0 * n * variance))
+            }
+        ).collect()
+}
+
+pub fn global_sensitivity(opt: GlobalSensitivityOpts)
+{
+    let json: GlobalSensitivity = parse_and_add_to_global(opt.json);
+    assert!(!json.modes.is_empty(), "modes must not be empty");
+    assert!(!json.top.is_empty(), "top must not be empty");
+    json.unstable_country_threshold.validate("unstable_country_threshold");
+    json.original_avail_filter.validate("original_avail_filter");
+    json.reducing_factor.validate("reducing_factor");
+    assert!(
+        json.reducing_factor.min >= 0.0 && json.reducing_factor.max <= 1.0,
+        "reducing_factor has to be in range 0.0..=1.0"
+    );
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let contexts = json.modes
+        .iter()
+        .map(|&mode| SimulationContext::new(mode).with_node_map(&node_map))
+        .collect_vec();
+    let shock_str = json.shock_type.name_addition();
+
+    // Saltelli design: A, B and the d matrices A_B^i
+    let d = PARAMETER_NAMES.len();
+    let mut rng = Pcg64::seed_from_u64(json.seed);
+    let points = Sampler::Sobol
+        .point_source(2 * d, &mut rng)
+        .unwrap()
+        .points(json.base_samples, &mut rng);
+    let a = points.iter()
+        .map(|p| p[..d].to_vec())
+        .collect_vec();
+    let b = points.iter()
+        .map(|p| p[d..].to_vec())
+        .collect_vec();
+    let mut design = a.clone();
+    design.extend(b.iter().cloned());
+    for i in 0..d{
+        design.extend(
+            a.iter()
+                .zip(b.iter())
+                .map(
+                    |(a_row, b_row)|
+                    {
+                        let mut row = a_row.clone();
+                        row[i] = b_row[i];
+                        row
+                    }
+                )
+        );
+    }
+    let design = design.iter()
+        .map(|u| json.parameters(u))
+        .collect_vec();
+
+    json.years
+        .clone()
+        .into_par_iter()
+        .for_each(
+            |year|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+
+                let original_exports = calc_acc_trade(&export);
+                let original_exports_recip = calc_recip(&original_exports);
+                let original_imports = calc_acc_trade(&import);
+                let original_imports_recip = calc_recip(&original_imports);
+                let order = if json.solver.needs_order(){
+                    sweep_order(&import)
+                } else {
+                    Vec::new()
+                };
+                let no_shock = ShockRes::no_shock(export.node_count());
+                // only the mode changes the unshocked availability
+                let per_mode = contexts.iter()
+                    .map(
+                        |ctx|
+                        {
+                            let (baseline, _) = calc_available(&export, enrich, &no_shock, ctx, opt.quiet);
+                            let production = production_vec(&export, enrich, ctx);
+                            (ctx, baseline, production)
+                        }
+                    ).collect_vec();
+                let ranked = get_top_k_ids(&export, *json.top.end());
+
+                let evaluate = |p: &Parameters| -> ([f64; 3], Convergence) {
+                    let (ctx, baseline, production) = per_mode.iter()
+                        .find(|(ctx, ..)| ctx.mode == p.mode)
+                        .unwrap();
+                    let top = &ranked[..p.top.min(ranked.len())];
+                    let job = match json.shock_type{
+                        ShockType::ExportRestriction => {
+                            CalcShockMultiJob::new_const_export(
+                                top,
+                                p.reducing_factor,
+                                json.iterations,
+                                &export,
+                                &original_exports,
+                                &original_exports_recip,
+                                &original_imports,
+                                &original_imports_recip
+                            )
+                        },
+                        ShockType::Production => {
+                            CalcShockMultiJob::new_const_production(
+                                top,
+                                p.reducing_factor,
+                                production,
+                                json.iterations,
+                                &export,
+                                &original_exports,
+                                &original_exports_recip,
+                                &original_imports,
+                                &original_imports_recip
+                            )
+                        }
+                    }.with_tolerance(json.tolerance)
+                    .with_solver(json.solver, &order);
+                    let res = multi_shock_distribution(&import, &job);
+                    let (avail, _) = calc_available(&export, enrich, &res, ctx, true);
+                    let counted = (0..export.node_count())
+                        .filter(|idx| !top.contains(idx))
+                        .filter(|&idx| baseline[idx] >= p.avail_filter)
+                        .collect_vec();
+                    let unstable = counted.iter()
+                        .filter(|&&idx| avail[idx] / baseline[idx] < p.threshold)
+                        .count() as f64;
+                    let total: f64 = counted.iter()
+                        .map(|&idx| baseline[idx])
+                        .sum();
+                    let missing: f64 = counted.iter()
+                        .map(|&idx| (baseline[idx] - avail[idx]).max(0.0))
+                        .sum();
+                    let outputs = [
+                        unstable,
+                        if counted.is_empty() { 0.0 } else { unstable / counted.len() as f64 },
+                        if total > 0.0 { missing / total } else { 0.0 }
+                    ];
+                    (outputs, res.convergence)
+                };
+
+                let results: Vec<([f64; 3], Convergence)> = design
+                    .par_iter()
+                    .map(evaluate)
+                    .collect();
+                let mut convergence = ConvergenceSummary::default();
+                results.iter()
+                    .for_each(|(_, c)| convergence.add(c));
+                if convergence.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} - {} of {} evaluations did not converge",
+                        convergence.not_converged,
+                        convergence.runs
+                    );
+                }
+
+                let stub = format!("{}_Y{year}{shock_str}", opt.out_stub);
+                let header = PARAMETER_NAMES
+                    .into_iter()
+                    .chain(OUTPUT_NAMES);
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.samples"), header);
+                for (p, (outputs, _)) in design.iter().zip(results.iter()){
+                    writeln!(
+                        buf,
+                        "{} {:e} {} {} {} {} {:e} {:e}",
+                        p.threshold,
+                        p.avail_filter,
+                        p.reducing_factor,
+                        p.mode.as_str(),
+                        p.top,
+                        outputs[0],
+                        outputs[1],
+                        outputs[2]
+                    ).unwrap();
+                }
+
+                let n = json.base_samples;
+                let header = [
+                    "output",
+                    "parameter",
+                    "first_order",
+                    "total"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.sobol"), header);
+                writeln!(buf, "# Saltelli design with {n} base samples").unwrap();
+                convergence.write_comment(&mut buf).unwrap();
+                for (o, output_name) in OUTPUT_NAMES.iter().enumerate(){
+                    let column = results.iter()
+                        .map(|(outputs, _)| outputs[o])
+                        .collect_vec();
+                    let (f_a, rest) = column.split_at(n);
+                    let (f_b, rest) = rest.split_at(n);
+                    let f_ab = rest.chunks(n)
+                        .map(<[f64]>::to_vec)
+                        .collect_vec();
+                    for ((first, total), name) in sobol_indices(f_a, f_b, &f_ab).into_iter().zip(PARAMETER_NAMES){
+                        writeln!(buf, "{output_name} {name} {first:e} {total:e}").unwrap();
+                    }
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn additive_model_indices() {
+        // f = 2 x0 + x1, x2 has no influence. S0 = 0.8, S1 = 0.2
+        let f = |x: &[f64]| 2.0 * x[0] + x[1];
+        let n = 4096;
+        let mut rng = Pcg64::seed_from_u64(1);
+        let points = Sampler::Sobol.point_source(6, &mut rng).unwrap().points(n, &mut rng);
+        let f_a = points.iter().map(|p| f(&p[..3])).collect_vec();
+        let f_b = points.iter().map(|p| f(&p[3..])).collect_vec();
+        let f_ab = (0..3)
+            .map(
+                |i|
+                {
+                    points.iter()
+                        .map(
+                            |p|
+                            {
+                                let mut row = p[..3].to_vec();
+                                row[i] = p[3 + i];
+                                f(&row)
+                            }
+                        ).collect_vec()
+                }
+            ).collect_vec();
+        let indices = sobol_indices(&f_a, &f_b, &f_ab);
+        let expected = [0.8, 0.2, 0.0];
+        for ((first, total), e) in indices.into_iter().zip(expected){
+            assert!((first - e).abs() < 0.02, "{first} vs {e}");
+            assert!((total - e).abs() < 0.02, "{total} vs {e}");
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::{
+    collections::BTreeMap,
+    io::Write
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use itertools::Itertools;
+use crate::{
+    misc::*,
+    network::*
+};
+use super::flow_helper::calc_acc_trade;
+
+#[derive(Debug, Clone, Parser)]
+pub struct GravityOpts{
+    /// Path to network
+    #[arg(long, short)]
+    pub network_file: Utf8PathBuf,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "gravity")]
+    pub out_stub: String,
+
+    /// Optional CSV with country pair covariates.
+    /// Header: exporter,importer,name_1,name_2,...
+    /// Pairs that are missing in the file are not used for the estimation
+    #[arg(long, short)]
+    pub covariates: Option<Utf8PathBuf>,
+
+    /// Covariates that should enter as logarithm, e.g., distance
+    #[arg(long, short)]
+    pub log_columns: Vec<String>,
+
+    /// Convergence criterion for the fixed effects and coefficients
+    #[arg(long, short, default_value_t = 1e-9)]
+    pub tolerance: f64,
+
+    /// Maximal number of iterations
+    #[arg(long, short, default_value_t = 10000)]
+    pub max_iterations: usize,
+
+    /// Predicted edges with a weight below this are not written to the predicted network
+    #[arg(long, default_value_t = 0.0)]
+    pub min_weight: f64
+}
+
+struct Covariates{
+    names: Vec<String>,
+    pairs: BTreeMap<(String, String), Vec<f64>>
+}
+
+impl Covariates{
+    fn read(path: &Utf8PathBuf, log_columns: &[String]) -> Self
+    {
+        let mut lines = open_as_unwrapped_lines_filter_comments(path);
+        let header = lines.next()
+            .expect("Covariate file is empty");
+        let names = header.split(',')
+            .skip(2)
+            .map(|s| s.trim().to_owned())
+            .collect_vec();
+        for l in log_columns{
+            assert!(names.contains(l), "Unknown log column {l}");
+        }
+        let is_log = names.iter()
+            .map(|n| log_columns.contains(n))
+            .collect_vec();
+        let pairs = lines
+            .filter(|l| !l.trim().is_empty())
+            .map(
+                |line|
+                {
+                    let mut iter = line.split(',').map(str::trim);
+                    let exporter = iter.next().unwrap().to_owned();
+                    let importer = iter.next()
+                        .expect("Missing importer in covariate file")
+                        .to_owned();
+                    let values = iter.zip(is_log.iter())
+                        .map(
+                            |(v, &log)|
+                            {
+                                let v: f64 = v.parse().unwrap_or(f64::NAN);
+                                if log { v.ln() } else { v }
+                            }
+                        ).collect_vec();
+                    assert_eq!(values.len(), names.len(), "Wrong number of columns in covariate file");
+                    ((exporter, importer), values)
+                }
+            ).collect();
+        Self{names, pairs}
+    }
+}
+
+struct Observation{
+    exporter: usize,
+    importer: usize,
+    y: f64,
+    x: Vec<f64>
+}
+
+pub struct GravityFit{
+    pub coefficients: Vec<f64>,
+    pub std_errors: Vec<f64>,
+    pub exporter_effects: Vec<f64>,
+    pub importer_effects: Vec<f64>,
+    pub observations: usize,
+    pub iterations: usize,
+    pub converged: bool
+}
+
+/// Inverts the k times k matrix. Panics if the matrix is singular
+#[allow(clippy::needless_range_loop)]
+fn invert(mut m: Vec<Vec<f64>>) -> Vec<Vec<f64>>
+{
+    let k = m.len();
+    let mut inv = (0..k)
+        .map(|i| (0..k).map(|j| if i == j { 1.0 } else { 0.0 }).collect_vec())
+        .collect_vec();
+    for col in 0..k{
+        let pivot = (col..k)
+            .max_by(|a, b| m[*a][col].abs().total_cmp(&m[*b][col].abs()))
+            .unwrap();
+        assert!(m[pivot][col].abs() > 0.0, "Singular matrix - covariates are collinear with the fixed effects");
+        m.swap(col, pivot);
+        inv.swap(col, pivot);
+        let recip = m[col][col].recip();
+        for j in 0..k{
+            m[col][j] *= recip;
+            inv[col][j] *= recip;
+        }
+        for row in 0..k{
+            if row != col {
+                let factor = m[row][col];
+                for j in 0..k{
+                    m[row][j] -= factor * m[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+    }
+    inv
+}
+
+/// Removes the (weighted) exporter and importer means from every covariate
+fn partial_out_fixed_effects(
+    obs: &[Observation],
+    mu: &[f64],
+    node_count: usize,
+    tolerance: f64
+) -> Vec<Vec<f64>>
+{
+    let k = obs.first().map_or(0, |o| o.x.len());
+    let mut exporter_weight = vec![0.0; node_count];
+    let mut importer_weight = vec![0.0; node_count];
+    for (o, m) in obs.iter().zip(mu){
+        exporter_weight[o.exporter] += *m;
+        importer_weight[o.importer] += *m;
+    }
+    let mut residuals = obs.iter()
+        .map(|o| o.x.clone())
+        .collect_vec();
+    for c in 0..k{
+        for _ in 0..10000{
+            let mut exporter_mean = vec![0.0; node_count];
+            for ((o, m), r) in obs.iter().zip(mu).zip(residuals.iter()){
+                exporter_mean[o.exporter] += m * r[c];
+            }
+            for ((o, _), r) in obs.iter().zip(mu).zip(residuals.iter_mut()){
+                r[c] -= exporter_mean[o.exporter] / exporter_weight[o.exporter];
+            }
+            let mut importer_mean = vec![0.0; node_count];
+            for ((o, m), r) in obs.iter().zip(mu).zip(residuals.iter()){
+                importer_mean[o.importer] += m * r[c];
+            }
+            let mut change: f64 = 0.0;
+            for (o, r) in obs.iter().zip(residuals.iter_mut()){
+                let shift = importer_mean[o.importer] / importer_weight[o.importer];
+                r[c] -= shift;
+                change = change.max(shift.abs());
+            }
+            if change < tolerance {
+                break;
+            }
+        }
+    }
+    residuals
+}
+
+/// Poisson pseudo maximum likelihood with exporter and importer fixed effects.
+/// The fixed effects are updated by iterative proportional fitting,
+/// the coefficients by Newton steps on the fixed effect residualized covariates
+#[allow(clippy::needless_range_loop)]
+fn ppml(obs: &[Observation], node_count: usize, opt: &GravityOpts) -> GravityFit
+{
+    let k = obs.first().map_or(0, |o| o.x.len());
+    let mut row_sum = vec![0.0; node_count];
+    let mut col_sum = vec![0.0; node_count];
+    for o in obs{
+        row_sum[o.exporter] += o.y;
+        col_sum[o.importer] += o.y;
+    }
+    let total: f64 = row_sum.iter().sum();
+    let mut a = row_sum.iter()
+        .map(|s: &f64| s.ln())
+        .collect_vec();
+    let mut b = col_sum.iter()
+        .map(|s| (s / total).ln())
+        .collect_vec();
+    let mut beta = vec![0.0; k];
+
+    let linear = |o: &Observation, beta: &[f64]| -> f64 {
+        o.x.iter().zip(beta).map(|(x, b)| x * b).sum()
+    };
+    let log_likelihood = |a: &[f64], b: &[f64], beta: &[f64]| -> f64 {
+        obs.iter()
+            .map(
+                |o|
+                {
+                    let eta = a[o.exporter] + b[o.importer] + linear(o, beta);
+                    o.y * eta - eta.exp()
+                }
+            ).sum()
+    };
+
+    let mut converged = false;
+    let mut iterations = 0;
+    while iterations < opt.max_iterations{
+        iterations += 1;
+        // fixed effects
+        let mut denominator = vec![0.0; node_count];
+        for o in obs{
+            denominator[o.exporter] += (b[o.importer] + linear(o, &beta)).exp();
+        }
+        let mut change: f64 = 0.0;
+        for (i, d) in denominator.iter().enumerate(){
+            if row_sum[i] > 0.0 {
+                let new = row_sum[i].ln() - d.ln();
+                change = change.max((new - a[i]).abs());
+                a[i] = new;
+            }
+        }
+        let mut denominator = vec![0.0; node_count];
+        for o in obs{
+            denominator[o.importer] += (a[o.exporter] + linear(o, &beta)).exp();
+        }
+        for (j, d) in denominator.iter().enumerate(){
+            if col_sum[j] > 0.0 {
+                let new = col_sum[j].ln() - d.ln();
+                change = change.max((new - b[j]).abs());
+                b[j] = new;
+            }
+        }
+
+        if k > 0 {
+            let mu = obs.iter()
+                .map(|o| (a[o.exporter] + b[o.importer] + linear(o, &beta)).exp())
+                .collect_vec();
+            let residualized = partial_out_fixed_effects(obs, &mu, node_count, opt.tolerance);
+            let mut hessian = vec![vec![0.0; k]; k];
+            let mut score = vec![0.0; k];
+            for ((o, m), x) in obs.iter().zip(mu.iter()).zip(residualized.iter()){
+                for r in 0..k{
+                    score[r] += x[r] * (o.y - m);
+                    for c in 0..k{
+                        hessian[r][c] += m * x[r] * x[c];
+                    }
+                }
+            }
+            let inv = invert(hessian);
+            let step = (0..k)
+                .map(|r| (0..k).map(|c| inv[r][c] * score[c]).sum::<f64>())
+                .collect_vec();
+            // step halving guards against overshooting
+            let old_ll = log_likelihood(&a, &b, &beta);
+            let mut factor = 1.0;
+            let new_beta = loop {
+                let candidate = beta.iter()
+                    .zip(step.iter())
+                    .map(|(b, s)| b + factor * s)
+                    .collect_vec();
+                if log_likelihood(&a, &b, &candidate) >= old_ll || factor < 1e-10 {
+                    break candidate;
+                }
+                factor *= 0.5;
+            };
+            for (old, new) in beta.iter().zip(new_beta.iter()){
+                change = change.max((old - new).abs());
+            }
+            beta = new_beta;
+        }
+        if change < opt.tolerance {
+            converged = true;
+            break;
+        }
+    }
+
+    let std_errors = if k > 0 {
+        let mu = obs.iter()
+            .map(|o| (a[o.exporter] + b[o.importer] + linear(o, &beta)).exp())
+            .collect_vec();
+        let residualized = partial_out_fixed_effects(obs, &mu, node_count, opt.tolerance);
+        let mut bread = vec![vec![0.0; k]; k];
+        let mut meat = vec![vec![0.0; k]; k];
+        for ((o, m), x) in obs.iter().zip(mu.iter()).zip(residualized.iter()){
+            let res_sq = (o.y - m).powi(2);
+            for r in 0..k{
+                for c in 0..k{
+                    bread[r][c] += m * x[r] * x[c];
+                    meat[r][c] += res_sq * x[r] * x[c];
+                }
+            }
+        }
+        let bread = invert(bread);
+        // heteroskedasticity robust sandwich estimator
+        (0..k)
+            .map(
+                |r|
+                {
+                    let mut var = 0.0;
+                    for i in 0..k{
+                        for j in 0..k{
+                            var += bread[r][i] * meat[i][j] * bread[j][r];
+                        }
+                    }
+                    var.sqrt()
+                }
+            ).collect()
+    } else {
+        Vec::new()
+    };
+
+    GravityFit{
+        coefficients: beta,
+        std_errors,
+        exporter_effects: a,
+        importer_effects: b,
+        observations: obs.len(),
+        iterations,
+        converged
+    }
+}
+
+pub fn gravity(opt: GravityOpts)
+{
+    let covariates = opt.covariates
+        .as_ref()
+        .map(|path| Covariates::read(path, &opt.log_columns));
+
+    let mut lazy_networks = LazyNetworks::Filename(opt.network_file.clone());
+    lazy_networks.assure_availability();
+
+    let mut coef_buf = create_buf_with_command_and_version_and_header(
+        format!("{}.coef", opt.out_stub),
+        ["year", "name", "coefficient", "std_error", "z", "observations", "iterations", "converged"]
+    );
+
+    let mut expected_networks = Vec::new();
+
+    for export in lazy_networks.export_networks_unchecked(){
+        let export = export.without_unconnected_nodes();
+        let year = export.year;
+        let exports = calc_acc_trade(&export);
+        let imports = calc_acc_trade(&export.invert());
+        let node_count = export.node_count();
+
+        let mut weights = vec![BTreeMap::new(); node_count];
+        for (i, node) in export.nodes.iter().enumerate(){
+            for e in node.adj.iter(){
+                weights[i].insert(e.index, e.amount);
+            }
+        }
+
+        let mut skipped = 0_usize;
+        let mut obs = Vec::new();
+        for i in (0..node_count).filter(|i| exports[*i] > 0.0){
+            for j in (0..node_count).filter(|j| imports[*j] > 0.0 && *j != i){
+                let x = match &covariates{
+                    None => Vec::new(),
+                    Some(cov) => {
+                        let key = (
+                            export.nodes[i].identifier.clone(),
+                            export.nodes[j].identifier.clone()
+                        );
+                        match cov.pairs.get(&key){
+                            Some(x) if x.iter().all(|v| v.is_finite()) => x.clone(),
+                            _ => {
+                                skipped += 1;
+                                continue;
+                            }
+                        }
+                    }
+                };
+                let y = weights[i].get(&j).copied().unwrap_or(0.0);
+                obs.push(Observation{exporter: i, importer: j, y, x});
+            }
+        }
+        // fixed effects of countries without any positive observation would diverge
+        let mut row_sum = vec![0.0; node_count];
+        let mut col_sum = vec![0.0; node_count];
+        for o in obs.iter(){
+            row_sum[o.exporter] += o.y;
+            col_sum[o.importer] += o.y;
+        }
+        obs.retain(|o| row_sum[o.exporter] > 0.0 && col_sum[o.importer] > 0.0);
+        if skipped > 0 {
+            println!("Year {year}: {skipped} pairs without covariates are ignored");
+        }
+        if obs.is_empty(){
+            println!("Year {year}: no observations - SKIPPING");
+            continue;
+        }
+
+        let fit = ppml(&obs, node_count, &opt);
+        if !fit.converged {
+            eprintln!("Year {year}: PPML did not converge within {} iterations", fit.iterations);
+        }
+        let names = covariates.as_ref()
+            .map(|c| c.names.as_slice())
+            .unwrap_or_default();
+        for ((name, coef), se) in names.iter().zip(fit.coefficients.iter()).zip(fit.std_errors.iter()){
+            writeln!(
+                coef_buf,
+                "{year} {name} {coef} {se} {} {} {} {}",
+                coef / se,
+                fit.observations,
+                fit.iterations,
+                fit.converged
+            ).unwrap();
+        }
+
+        let mut edge_buf = create_buf_with_command_and_version_and_header(
+            format!("{}_Y{year}.edges", opt.out_stub),
+            ["exporter", "importer", "observed", "expected"]
+        );
+        let mut nodes = export.nodes
+            .iter()
+            .map(|n| Node::new(n.identifier.clone()))
+            .collect_vec();
+        for o in obs.iter(){
+            let eta = fit.exporter_effects[o.exporter]
+                + fit.importer_effects[o.importer]
+                + o.x.iter().zip(fit.coefficients.iter()).map(|(x, b)| x * b).sum::<f64>();
+            let expected = eta.exp();
+            writeln!(
+                edge_buf,
+                "{} {} {:e} {expected:e}",
+                export.nodes[o.exporter].identifier,
+                export.nodes[o.importer].identifier,
+                o.y
+            ).unwrap();
+            if expected > opt.min_weight {
+                nodes[o.exporter].adj.push(Edge{index: o.importer, amount: expected});
+            }
+        }
+        expected_networks.push(
+            Network{
+                nodes,
+                ..export
+            }
+        );
+    }
+
+    let buf = create_buf(format!("{}_expected.bincode", opt.out_stub));
+    bincode::serialize_into(buf, &expected_networks)
+        .expect("bincode serialization issue");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::needless_range_loop)]
+    fn ppml_recovers_coefficient() {
+        let m = vec![vec![4.0, 1.0, 2.0], vec![1.0, 3.0, 0.0], vec![2.0, 0.0, 5.0]];
+        let inv = invert(m.clone());
+        for i in 0..3{
+            for j in 0..3{
+                let prod: f64 = (0..3).map(|l| m[i][l] * inv[l][j]).sum();
+                assert!((prod - if i == j { 1.0 } else { 0.0 }).abs() < 1e-12);
+            }
+        }
+
+        // trade generated from known fixed effects and one covariate, without noise
+        let n = 6;
+        let exporter_effect = [1.0, 0.5, -0.3, 2.0, 0.0, 1.2];
+        let importer_effect = [0.2, -1.0, 0.7, 0.0, 1.5, -0.4];
+        let beta = 0.8;
+        let obs = (0..n)
+            .cartesian_product(0..n)
+            .filter(|(i, j)| i != j)
+            .map(
+                |(exporter, importer)|
+                {
+                    let x = ((exporter * 7 + importer * 3) % 5) as f64 * 0.5;
+                    let y = (exporter_effect[exporter] + importer_effect[importer] + beta * x).exp();
+                    Observation{exporter, importer, y, x: vec![x]}
+                }
+            ).collect_vec();
+
+        // the partialled covariate has no weighted mean left for any exporter or importer
+        let mu = obs.iter().map(|o| o.y).collect_vec();
+        let residualized = partial_out_fixed_effects(&obs, &mu, n, 1e-14);
+        for idx in 0..n{
+            let exporter_mean: f64 = obs.iter()
+                .zip(mu.iter().zip(residualized.iter()))
+                .filter(|(o, _)| o.exporter == idx)
+                .map(|(_, (m, r))| m * r[0])
+                .sum();
+            let importer_mean: f64 = obs.iter()
+                .zip(mu.iter().zip(residualized.iter()))
+                .filter(|(o, _)| o.importer == idx)
+                .map(|(_, (m, r))| m * r[0])
+                .sum();
+            assert!(exporter_mean.abs() < 1e-9);
+            assert!(importer_mean.abs() < 1e-9);
+        }
+
+        let opt = GravityOpts{
+            network_file: Utf8PathBuf::new(),
+            out_stub: String::new(),
+            covariates: None,
+            log_columns: Vec::new(),
+            tolerance: 1e-10,
+            max_iterations: 10000,
+            min_weight: 0.0
+        };
+        let fit = ppml(&obs, n, &opt);
+        assert!(fit.converged);
+        assert_eq!(fit.observations, n * (n - 1));
+        assert!((fit.coefficients[0] - beta).abs() < 1e-6, "{}", fit.coefficients[0]);
+    }
+}
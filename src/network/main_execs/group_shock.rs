@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    num::NonZeroUsize,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    samplers::Sampler,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    multi_shock_distribution,
+    production_vec,
+    rand_fixed_sum,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct GroupShockOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "group")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+/// How the disruption is distributed among the members of a group
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub enum GroupDisruption{
+    /// Every member keeps the same fraction of its exports or production
+    #[derivative(Default)]
+    Uniform{
+        #[derivative(Default(value = "(0..=20).map(|i| i as f64 / 20.0).collect()"))]
+        remaining_fracs: Vec<f64>
+    },
+    /// The remaining fractions of the members are drawn with a fixed sum,
+    /// like in the shock cloud
+    Cloud{
+        cloud_steps: NonZeroUsize,
+        cloud_m: NonZeroUsize,
+        seed: u64,
+        #[serde(default)]
+        sampler: Sampler
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct GroupShock{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// One group per line: the name of the group followed by the ids of its members,
+    /// separated by whitespace. Lines starting with # are ignored
+    pub group_file: Utf8PathBuf,
+
+    /// Names of the groups that are disrupted one after another. If empty, all groups of the file are used
+    pub groups: Vec<String>,
+
+    /// Restrict the exports or reduce the production of the members
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    pub disruption: GroupDisruption,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock are not counted as unstable
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend
+}
+
+/// Reads the groups, name -> member ids
+pub fn read_groups(path: &Utf8PathBuf) -> BTreeMap<String, Vec<String>>
+{
+    let mut groups = BTreeMap::new();
+    for line in open_as_unwrapped_lines_filter_comments(path){
+        let mut iter = line.split_whitespace();
+        let name = match iter.next(){
+            Some(name) => name.to_owned(),
+            None => continue
+        };
+        let members = iter.map(str::to_owned).collect_vec();
+        assert!(!members.is_empty(), "Group {name} has no members");
+        let old = groups.insert(name, members);
+        assert!(old.is_none(), "Group names have to be unique");
+    }
+    groups
+}
+
+pub fn group_shock(opt: GroupShockOpts)
+{
+    let json: GroupShock = parse_and_add_to_global(opt.json);
+    let mut groups = read_groups(&json.group_file);
+    if !json.groups.is_empty(){
+        groups = json.groups
+            .iter()
+            .map(
+                |name|
+                {
+                    let members = groups.remove(name)
+                        .unwrap_or_else(|| panic!("Group {name} is not in {}", json.group_file));
+                    (name.clone(), members)
+                }
+            ).collect();
+    }
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+    let mode_str = ctx.mode_str();
+    let shock_str = json.shock_type.name_addition();
+
+    let jobs = json.years
+        .clone()
+        .cartesian_product(groups.iter())
+        .collect_vec();
+
+    jobs.into_par_iter()
+        .for_each(
+            |(year, (group, member_ids))|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+
+                let (members, missing): (Vec<_>, Vec<_>) = member_ids.iter()
+                    .partition(|id| export.get_index(id).is_some());
+                if members.is_empty(){
+                    if !opt.quiet{
+                        eprintln!("Y{year}: no member of {group} trades, skipping");
+                    }
+                    return;
+                }
+                let member_idxs = members.iter()
+                    .map(|id| export.get_index(id).unwrap())
+                    .collect_vec();
+
+                let original_exports = calc_acc_trade(&export);
+                let original_exports_recip = calc_recip(&original_exports);
+                let original_imports = calc_acc_trade(&import);
+                let original_imports_recip = calc_recip(&original_imports);
+                let production = production_vec(&export, enrich, &ctx);
+                let order = if json.solver.needs_order(){
+                    sweep_order(&import)
+                } else {
+                    Vec::new()
+                };
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, flow_status) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+                let counted = (0..export.node_count())
+                    .filter(|idx| !member_idxs.contains(idx))
+                    .filter(|&idx| baseline[idx] >= json.original_avail_filter)
+                    .collect_vec();
+                let total_export: f64 = member_idxs.iter()
+                    .map(|&idx| original_exports[idx])
+                    .sum();
+
+                let mut convergence = ConvergenceSummary::default();
+                // returns the remaining fraction of the group and the number of unstable countries
+                let mut shock = |fracs: &[f64]| -> (f64, usize, Convergence) {
+                    let job = match json.shock_type{
+                        ShockType::ExportRestriction => {
+                            let exports = member_idxs.iter()
+                                .zip(fracs)
+                                .map(|(&export_id, &export_frac)| ExportShockItem{export_frac, export_id})
+                                .collect_vec();
+                            CalcShockMultiJob::new_exporter(
+                                exports,
+                                json.iterations,
+                                &export,
+                                &original_imports,
+                                &original_imports_recip,
+                                &original_exports,
+                                &original_exports_recip
+                            )
+                        },
+                        ShockType::Production => {
+                            let shocks = member_idxs.iter()
+                                .zip(fracs)
+                                .map(|(&country_id, &production_frac)| ProductionShockItem{production_frac, country_id})
+                                .collect_vec();
+                            CalcShockMultiJob::new_production(
+                                shocks,
+                                &production,
+                                json.iterations,
+                                &export,
+                                &original_imports,
+                                &original_imports_recip,
+                                &original_exports,
+                                &original_exports_recip
+                            )
+                        }
+                    }.with_tolerance(json.tolerance)
+                    .with_solver(json.solver, &order);
+                    let res = multi_shock_distribution(&import, &job);
+                    convergence.add(&res.convergence);
+                    let remaining = match json.shock_type{
+                        ShockType::ExportRestriction => {
+                            member_idxs.iter()
+                                .map(|&idx| original_exports[idx] * res.export_fracs[idx])
+                                .sum::<f64>() / total_export
+                        },
+                        ShockType::Production => job.remaining_production_frac()
+                    };
+                    let (avail, _) = calc_available(&export, enrich, &res, &ctx, opt.quiet);
+                    let unstable = counted.iter()
+                        .filter(|&&idx| avail[idx] / baseline[idx] < json.unstable_country_threshold)
+                        .count();
+                    (remaining, unstable, res.convergence)
+                };
+
+                let (kind, rows) = match &json.disruption{
+                    GroupDisruption::Uniform{remaining_fracs} => {
+                        let rows = remaining_fracs.iter()
+                            .map(
+                                |&frac|
+                                {
+                                    let fracs = vec![frac; member_idxs.len()];
+                                    let (remaining, unstable, c) = shock(&fracs);
+                                    (remaining, unstable, c, fracs)
+                                }
+                            ).collect_vec();
+                        ("uniform", rows)
+                    },
+                    GroupDisruption::Cloud{cloud_steps, cloud_m, seed, sampler} => {
+                        let mut rng = Pcg64::seed_from_u64(*seed ^ year as u64);
+                        let n = member_idxs.len();
+                        let mut points = sampler.point_source(2 * n.saturating_sub(1), &mut rng);
+                        let mut rows = Vec::new();
+                        for i in 0..cloud_steps.get(){
+                            let target = sampler.target(i, cloud_steps.get(), n as f64);
+                            let matrix = rand_fixed_sum(n, *cloud_m, target, 0.0, 1.0, points.as_mut(), &mut rng);
+                            for fracs in matrix{
+                                let (remaining, unstable, c) = shock(&fracs);
+                                rows.push((remaining, unstable, c, fracs));
+                            }
+                        }
+                        ("cloud", rows)
+                    }
+                };
+
+                if convergence.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} {group} - {} of {} shocks did not converge",
+                        convergence.not_converged,
+                        convergence.runs
+                    );
+                }
+
+                let name = format!(
+                    "{}{}_{group}_Y{year}_Th{}_{mode_str}{shock_str}.{kind}",
+                    flow_status.name_addition(),
+                    opt.out_stub,
+                    json.unstable_country_threshold
+                );
+                let header = [
+                    format!("{group}_remaining_frac"),
+                    "unstable_countries".to_owned(),
+                    "iterations".to_owned(),
+                    "residual".to_owned()
+                ].into_iter()
+                    .chain(members.iter().map(|id| format!("frac_{id}")));
+                let mut buf = create_buf_with_command_and_version_and_header(name, header);
+                writeln!(buf, "# group {group}: {}", members.iter().join(",")).unwrap();
+                if !missing.is_empty(){
+                    writeln!(buf, "# members without trade in Y{year}: {}", missing.iter().join(",")).unwrap();
+                }
+                convergence.write_comment(&mut buf).unwrap();
+                for (remaining, unstable, c, fracs) in rows{
+                    write!(buf, "{remaining:e} {unstable} {} {:e}", c.iterations, c.residual).unwrap();
+                    for frac in fracs{
+                        write!(buf, " {frac}").unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                }
+            }
+        );
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex
+};
+use camino::Utf8Path;
+use crate::misc::*;
+
+/// FNV-1a hash. Stable across runs, platforms and compiler versions,
+/// unlike the hasher of the standard library
+pub fn fnv1a(bytes: &[u8]) -> u64
+{
+    bytes.iter()
+        .fold(
+            0xcbf29ce484222325,
+            |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        )
+}
+
+/// None if the file cannot be read
+pub fn hash_file<P>(path: P) -> Option<u64>
+where P: AsRef<Utf8Path>
+{
+    std::fs::read(path.as_ref())
+        .ok()
+        .map(|bytes| fnv1a(&bytes))
+}
+
+/// A finished unit of work together with the files it wrote
+#[derive(Debug, Clone, PartialEq)]
+pub struct LedgerEntry{
+    pub item: String,
+    pub year: i32,
+    /// Hash of the configuration the unit was calculated with
+    pub config: u64,
+    pub files: Vec<(String, u64)>
+}
+
+impl LedgerEntry{
+    /// Line format: item year config [file hash]*.
+    /// Returns None for lines that were only partially written
+    pub fn parse(line: &str) -> Option<Self>
+    {
+        let mut iter = line.split_whitespace();
+        let item = iter.next()?.to_owned();
+        let year = iter.next()?.parse().ok()?;
+        let config = u64::from_str_radix(iter.next()?, 16).ok()?;
+        let rest: Vec<&str> = iter.collect();
+        if !rest.len().is_multiple_of(2) {
+            return None;
+        }
+        let files = rest.chunks_exact(2)
+            .map(|pair| Some((pair[0].to_owned(), u64::from_str_radix(pair[1], 16).ok()?)))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self{item, year, config, files})
+    }
+
+    pub fn line(&self) -> String
+    {
+        let mut line = format!("{} {} {:016x}", self.item, self.year, self.config);
+        for (file, hash) in self.files.iter(){
+            line.push_str(&format!(" {file} {hash:016x}"));
+        }
+        line
+    }
+
+    /// All files still exist and are unchanged
+    pub fn is_intact(&self) -> bool
+    {
+        self.files
+            .iter()
+            .all(|(file, hash)| hash_file(file) == Some(*hash))
+    }
+}
+
+/// Records which (item, year) units are done, so that an interrupted run can be resumed.
+/// Every entry is flushed right away, a crash can at most leave a partial last line
+pub struct Ledger{
+    done: BTreeMap<(String, i32), LedgerEntry>,
+    file: Mutex<File>
+}
+
+impl Ledger{
+    /// Starts a new ledger, or continues the existing one if resume is true.
+    /// Only entries whose files are intact are kept
+    pub fn open<P>(path: P, resume: bool) -> Self
+    where P: AsRef<Utf8Path>
+    {
+        let path = path.as_ref();
+        let mut done = BTreeMap::new();
+        if resume && path.exists() {
+            for line in open_as_unwrapped_lines_filter_comments(path){
+                match LedgerEntry::parse(&line){
+                    Some(entry) if entry.is_intact() => {
+                        done.insert((entry.item.clone(), entry.year), entry);
+                    },
+                    Some(entry) => {
+                        println!("Output of item {} year {} is damaged - will be recalculated", entry.item, entry.year);
+                    },
+                    None => {
+                        println!("Ignoring incomplete ledger line: {line}");
+                    }
+                }
+            }
+        }
+        let file = if resume {
+            // a partial last line has no newline, the next entry must not be appended to it
+            let needs_newline = std::fs::read(path)
+                .is_ok_and(|bytes| bytes.last().is_some_and(|&b| b != b'\n'));
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("unable to open ledger");
+            if needs_newline {
+                writeln!(file).unwrap();
+            }
+            file
+        } else {
+            let mut file = File::create(path)
+                .expect("unable to create ledger");
+            writeln!(file, "# item year config [file hash]*").unwrap();
+            file
+        };
+        Self{
+            done,
+            file: Mutex::new(file)
+        }
+    }
+
+    /// The unit was finished with the same configuration and its files are intact
+    pub fn is_done(&self, item: &str, year: i32, config: u64) -> bool
+    {
+        self.done
+            .get(&(item.to_owned(), year))
+            .is_some_and(|entry| entry.config == config)
+    }
+
+    pub fn record(&self, item: &str, year: i32, config: u64, files: &[String])
+    {
+        let entry = LedgerEntry{
+            item: item.to_owned(),
+            year,
+            config,
+            files: files.iter()
+                .map(
+                    |f|
+                    {
+                        let hash = hash_file(f)
+                            .unwrap_or_else(|| panic!("unable to read {f} for the ledger"));
+                        (f.clone(), hash)
+                    }
+                ).collect()
+        };
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", entry.line()).unwrap();
+        file.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv_and_ledger_lines() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        let entry = LedgerEntry{
+            item: "27".to_owned(),
+            year: 2010,
+            config: 12345,
+            files: vec![("27/a_Y2010.dat".to_owned(), 1), ("27/a_Y2010.average".to_owned(), u64::MAX)]
+        };
+        assert_eq!(LedgerEntry::parse(&entry.line()), Some(entry.clone()));
+        // a line that was cut off while writing
+        let line = entry.line();
+        assert_eq!(LedgerEntry::parse(&line[..line.len() - 20]), None);
+    }
+
+    #[test]
+    fn resume_after_truncated_line() {
+        let path = std::env::temp_dir()
+            .join(format!("trade_networks_ledger_test_{}.ledger", std::process::id()));
+        let path = Utf8Path::from_path(&path).unwrap();
+
+        let ledger = Ledger::open(path, false);
+        ledger.record("27", 2010, 1, &[]);
+        drop(ledger);
+        // simulate a crash while writing the second entry
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        write!(file, "27 2011").unwrap();
+        drop(file);
+
+        let ledger = Ledger::open(path, true);
+        assert!(ledger.is_done("27", 2010, 1));
+        assert!(!ledger.is_done("27", 2011, 1));
+        ledger.record("27", 2011, 1, &[]);
+        drop(ledger);
+
+        let ledger = Ledger::open(path, true);
+        assert!(ledger.is_done("27", 2010, 1));
+        assert!(ledger.is_done("27", 2011, 1));
+        drop(ledger);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -49,7 +49,7 @@ fn create_matches_helper(glob: &str) -> BTreeMap<u16, MatchItem>
                 let canon = p.canonicalize_utf8().unwrap();
                 let parent = canon.parent().unwrap();
                 let dir = parent.components()
-                    .last()
+                    .next_back()
                     .unwrap()
                     .as_str();
                 let item_code: u16 = regex_first_match_parsed(&re, dir);
@@ -214,7 +214,7 @@ fn get_vals_trading(line: &str) -> (f64, f64)
         .map(|s| s.parse::<f64>().unwrap());
     let left = iter.next().unwrap();
     let right = iter.next().unwrap();
-    let normed_average = iter.last().unwrap();
+    let normed_average = iter.next_back().unwrap();
     ((left + right) * 0.5, normed_average)
 }
 
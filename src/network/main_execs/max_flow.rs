@@ -0,0 +1,304 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    ops::Deref
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use itertools::Itertools;
+use ordered_float::OrderedFloat;
+use std::cmp::Reverse;
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *},
+    UNIT_TESTER
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct MaxFlowOpts{
+    /// Path to network
+    #[arg(long, short)]
+    pub network_file: Utf8PathBuf,
+
+    /// Path to enrichment file
+    #[arg(long, short)]
+    pub enrich_file: String,
+
+    /// Item code, e.g. 27 for Rice
+    #[arg(long, short)]
+    pub item_code: Option<String>,
+
+    /// Which year to check
+    #[arg(long, short)]
+    pub year: i32,
+
+    /// Id of the importing country
+    #[arg(long, short)]
+    pub target: String,
+
+    /// Treat stocks like production, i.e., as additional source capacity
+    #[arg(long)]
+    pub include_stock: bool,
+
+    /// Name of output file
+    #[arg(long, short, default_value = "max_flow.dat")]
+    pub out: Utf8PathBuf
+}
+
+struct FlowEdge{
+    to: usize,
+    rev: usize,
+    cap: f64
+}
+
+/// Residual graph for Dinic's algorithm
+pub struct FlowGraph{
+    adj: Vec<Vec<FlowEdge>>,
+    /// from, to, capacity
+    original: Vec<(usize, usize, f64)>
+}
+
+impl FlowGraph{
+    pub fn new(node_count: usize) -> Self
+    {
+        Self{
+            adj: (0..node_count).map(|_| Vec::new()).collect(),
+            original: Vec::new()
+        }
+    }
+
+    pub fn add_edge(&mut self, from: usize, to: usize, cap: f64)
+    {
+        let rev_from = self.adj[to].len();
+        let rev_to = self.adj[from].len();
+        self.adj[from].push(FlowEdge{to, rev: rev_from, cap});
+        self.adj[to].push(FlowEdge{to: from, rev: rev_to, cap: 0.0});
+        self.original.push((from, to, cap));
+    }
+
+    fn levels(&self, source: usize, eps: f64) -> Vec<Option<usize>>
+    {
+        let mut level = vec![None; self.adj.len()];
+        level[source] = Some(0);
+        let mut queue = VecDeque::from([source]);
+        while let Some(v) = queue.pop_front(){
+            let next = level[v].unwrap() + 1;
+            for e in self.adj[v].iter(){
+                if e.cap > eps && level[e.to].is_none(){
+                    level[e.to] = Some(next);
+                    queue.push_back(e.to);
+                }
+            }
+        }
+        level
+    }
+
+    fn push(
+        &mut self,
+        v: usize,
+        sink: usize,
+        pushed: f64,
+        level: &[Option<usize>],
+        next_edge: &mut [usize],
+        eps: f64
+    ) -> f64
+    {
+        if v == sink {
+            return pushed;
+        }
+        while next_edge[v] < self.adj[v].len(){
+            let idx = next_edge[v];
+            let (to, cap) = (self.adj[v][idx].to, self.adj[v][idx].cap);
+            let is_next_level = matches!(
+                (level[v], level[to]),
+                (Some(a), Some(b)) if b == a + 1
+            );
+            if cap > eps && is_next_level {
+                let flow = self.push(to, sink, pushed.min(cap), level, next_edge, eps);
+                if flow > 0.0 {
+                    let rev = self.adj[v][idx].rev;
+                    self.adj[v][idx].cap -= flow;
+                    self.adj[to][rev].cap += flow;
+                    return flow;
+                }
+            }
+            next_edge[v] += 1;
+        }
+        0.0
+    }
+
+    fn eps(&self) -> f64
+    {
+        let total: f64 = self.original
+            .iter()
+            .map(|(_, _, cap)| cap)
+            .sum();
+        total * 1e-13
+    }
+
+    /// Calculates the maximum flow. Afterwards the graph contains the residual capacities
+    pub fn max_flow(&mut self, source: usize, sink: usize) -> f64
+    {
+        let eps = self.eps();
+        let mut flow = 0.0;
+        loop {
+            let level = self.levels(source, eps);
+            if level[sink].is_none(){
+                return flow;
+            }
+            let mut next_edge = vec![0; self.adj.len()];
+            loop {
+                let pushed = self.push(source, sink, f64::INFINITY, &level, &mut next_edge, eps);
+                if pushed <= eps {
+                    break;
+                }
+                flow += pushed;
+            }
+        }
+    }
+
+    /// Only valid after max_flow was called.
+    /// Returns the edges (from, to, capacity) of the minimum cut
+    pub fn min_cut(&self, source: usize) -> Vec<(usize, usize, f64)>
+    {
+        let reachable = self.levels(source, self.eps());
+        self.original
+            .iter()
+            .copied()
+            .filter(|(from, to, _)| reachable[*from].is_some() && reachable[*to].is_none())
+            .collect()
+    }
+}
+
+pub fn max_flow_exec(opt: MaxFlowOpts)
+{
+    let mut lazy_networks = LazyNetworks::Filename(opt.network_file.clone());
+    lazy_networks.assure_availability();
+    let export = lazy_networks
+        .get_export_network_unchecked(opt.year)
+        .without_unconnected_nodes();
+    let target = export.get_index(&opt.target)
+        .expect("Target country does not trade in the specified year");
+
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        opt.enrich_file.clone(),
+        opt.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrich = lazy_enrichments.get_year_unchecked(opt.year);
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let production_id = node_map.get(PRODUCTION);
+    let stock_id = node_map.get(STOCK);
+    let unit_tester = UNIT_TESTER.deref();
+
+    let source_capacity = export.nodes
+        .iter()
+        .map(
+            |node|
+            {
+                let extra = match enrich.get(node.identifier.as_str()){
+                    None => return 0.0,
+                    Some(extra) => extra
+                };
+                let mut capacity = 0.0;
+                let mut ids = vec![production_id];
+                if opt.include_stock{
+                    ids.push(stock_id);
+                }
+                for id in ids{
+                    if let Some(e) = extra.map.get(&id){
+                        assert!(unit_tester.is_equiv(&e.unit, &export.unit));
+                        capacity += e.amount;
+                    }
+                }
+                capacity.max(0.0)
+            }
+        ).collect_vec();
+
+    // last node is the super source
+    let source = export.node_count();
+    let mut graph = FlowGraph::new(source + 1);
+    for (idx, node) in export.nodes.iter().enumerate(){
+        if idx == target {
+            // flow leaving the target cannot reach the target again in a useful way
+            continue;
+        }
+        if source_capacity[idx] > 0.0 {
+            graph.add_edge(source, idx, source_capacity[idx]);
+        }
+        for e in node.adj.iter(){
+            graph.add_edge(idx, e.index, e.amount);
+        }
+    }
+
+    let max_flow = graph.max_flow(source, target);
+    let imports: f64 = export.nodes
+        .iter()
+        .flat_map(|n| n.adj.iter())
+        .filter(|e| e.index == target)
+        .map(|e| e.amount)
+        .sum();
+    let supply = source_capacity[target] + imports;
+
+    let mut buf = create_buf_with_command_and_version(&opt.out);
+    writeln!(buf, "# target {} year {}", opt.target, opt.year).unwrap();
+    writeln!(buf, "# own_production {:e}", source_capacity[target]).unwrap();
+    writeln!(buf, "# reported_imports {imports:e}").unwrap();
+    writeln!(buf, "# max_flow {max_flow:e}").unwrap();
+    writeln!(buf, "# max_flow_share_of_supply {}", max_flow / supply).unwrap();
+    // Cut edges starting at PRODUCTION mean that the production
+    // of the country in the 'to' column is the bottleneck
+    let header = [
+        "from",
+        "to",
+        "capacity",
+        "share_of_supply"
+    ];
+    write_slice_head(&mut buf, header).unwrap();
+
+    let name = |idx: usize| {
+        if idx == source {
+            "PRODUCTION"
+        } else {
+            export.nodes[idx].identifier.as_str()
+        }
+    };
+
+    let cut = graph.min_cut(source)
+        .into_iter()
+        .sorted_by_key(|(_, _, cap)| Reverse(OrderedFloat(*cap)));
+    for (from, to, cap) in cut {
+        writeln!(buf, "{} {} {cap:e} {}", name(from), name(to), cap / supply).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_flow_textbook_example() {
+        let mut graph = FlowGraph::new(6);
+        for (from, to, cap) in [
+            (0, 1, 16.0),
+            (0, 2, 13.0),
+            (1, 3, 12.0),
+            (2, 1, 4.0),
+            (2, 4, 14.0),
+            (3, 2, 9.0),
+            (3, 5, 20.0),
+            (4, 3, 7.0),
+            (4, 5, 4.0)
+        ]{
+            graph.add_edge(from, to, cap);
+        }
+        let flow = graph.max_flow(0, 5);
+        assert!((flow - 23.0).abs() < 1e-9);
+
+        let cut_capacity: f64 = graph.min_cut(0)
+            .iter()
+            .map(|(_, _, cap)| cap)
+            .sum();
+        assert!((cut_capacity - flow).abs() < 1e-9);
+    }
+}
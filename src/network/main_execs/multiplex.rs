@@ -0,0 +1,387 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use itertools::Itertools;
+use crate::{
+    correlation_coef::{pearson_correlation_coefficient, spearman_correlation_coefficent},
+    misc::*,
+    network::*,
+    UNIT_TESTER
+};
+use super::{flow::get_files, get_top_k_ids};
+
+#[derive(Debug, Clone, Parser)]
+pub struct MultiplexOpts{
+    /// Globbing for the network files. Every file is one layer,
+    /// the item code is the first number in the filename
+    #[arg(long, short)]
+    pub network_glob: String,
+
+    /// Year that should be analyzed
+    #[arg(long, short)]
+    pub year: i32,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "multiplex")]
+    pub out_stub: String,
+
+    /// A country counts as dominating a layer if it is
+    /// among the top k exporters of that layer
+    #[arg(long, short, default_value_t = 5)]
+    pub top: usize
+}
+
+pub struct Layer{
+    pub item_code: String,
+    /// Export network, indexed by the shared node index of the multiplex
+    pub network: Network
+}
+
+/// Several item networks of the same year on a shared node index.
+pub struct Multiplex{
+    pub year: i32,
+    pub identifiers: Vec<String>,
+    pub layers: Vec<Layer>
+}
+
+impl Multiplex{
+    /// Read all networks (one layer per file) of the specified year.
+    /// Files that do not contain the year are skipped
+    pub fn from_files<'a, I>(files: I, year: i32) -> Self
+    where I: IntoIterator<Item = (String, &'a Utf8PathBuf)>
+    {
+        let networks = files.into_iter()
+            .filter_map(
+                |(item_code, path)|
+                {
+                    let mut lazy_networks = LazyNetworks::Filename(path.clone());
+                    lazy_networks.assure_availability();
+                    let network = lazy_networks.export_networks_unchecked()
+                        .iter()
+                        .find(|n| n.year == year);
+                    match network{
+                        None => {
+                            println!("Year {year} missing for item {item_code} - SKIPPING LAYER");
+                            None
+                        },
+                        Some(n) => Some((item_code, n.without_unconnected_nodes()))
+                    }
+                }
+            ).collect_vec();
+
+        let identifiers = networks.iter()
+            .flat_map(|(_, n)| n.nodes.iter().map(|node| node.identifier.as_str()))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect_vec();
+        let shared_index: BTreeMap<&str, usize> = identifiers.iter()
+            .enumerate()
+            .map(|(idx, id)| (id.as_str(), idx))
+            .collect();
+
+        let layers = networks.into_iter()
+            .map(
+                |(item_code, network)|
+                {
+                    let mut nodes = identifiers.iter()
+                        .map(|id| Node::new(id.clone()))
+                        .collect_vec();
+                    for node in network.nodes.iter(){
+                        let this = shared_index[node.identifier.as_str()];
+                        nodes[this].adj = node.adj
+                            .iter()
+                            .map(
+                                |e|
+                                {
+                                    Edge{
+                                        index: shared_index[network.nodes[e.index].identifier.as_str()],
+                                        amount: e.amount
+                                    }
+                                }
+                            ).collect();
+                    }
+                    let network = Network{
+                        nodes,
+                        ..network
+                    };
+                    Layer{item_code, network}
+                }
+            ).collect();
+
+        Self{
+            year,
+            identifiers,
+            layers
+        }
+    }
+
+    #[inline]
+    pub fn node_count(&self) -> usize
+    {
+        self.identifiers.len()
+    }
+
+    #[inline]
+    pub fn layer_count(&self) -> usize
+    {
+        self.layers.len()
+    }
+
+    /// Number of distinct trading partners (imports and exports) in the layer
+    pub fn total_degrees(&self, layer: usize) -> Vec<usize>
+    {
+        let network = &self.layers[layer].network;
+        let mut partners = vec![BTreeSet::new(); self.node_count()];
+        for (idx, node) in network.nodes.iter().enumerate(){
+            for e in node.adj.iter(){
+                partners[idx].insert(e.index);
+                partners[e.index].insert(idx);
+            }
+        }
+        partners.iter()
+            .map(BTreeSet::len)
+            .collect()
+    }
+
+    /// Trade volume (imports plus exports) in the layer
+    pub fn total_strengths(&self, layer: usize) -> Vec<f64>
+    {
+        let network = &self.layers[layer].network;
+        let mut strength = vec![0.0; self.node_count()];
+        for (idx, node) in network.nodes.iter().enumerate(){
+            for e in node.adj.iter(){
+                strength[idx] += e.amount;
+                strength[e.index] += e.amount;
+            }
+        }
+        strength
+    }
+
+    fn edge_set(&self, layer: usize) -> BTreeSet<(usize, usize)>
+    {
+        self.layers[layer]
+            .network
+            .nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| node.adj.iter().map(move |e| (idx, e.index)))
+            .collect()
+    }
+
+    /// Number of shared directed edges and the Jaccard index of the edge sets of two layers
+    pub fn edge_overlap(&self, a: usize, b: usize) -> (usize, f64)
+    {
+        let set_a = self.edge_set(a);
+        let set_b = self.edge_set(b);
+        let shared = set_a.intersection(&set_b).count();
+        let union = set_a.len() + set_b.len() - shared;
+        (shared, shared as f64 / union as f64)
+    }
+
+    /// Multiplex participation coefficient of every node, calculated from the total degrees.
+    /// 1 means the links of the node are spread evenly across all layers,
+    /// 0 means they are all in one layer. NaN for nodes without links
+    pub fn participation_coefficients(&self) -> Vec<f64>
+    {
+        let m = self.layer_count() as f64;
+        let degrees = (0..self.layer_count())
+            .map(|layer| self.total_degrees(layer))
+            .collect_vec();
+        (0..self.node_count())
+            .map(
+                |idx|
+                {
+                    let overlapping: usize = degrees.iter()
+                        .map(|d| d[idx])
+                        .sum();
+                    let overlapping = overlapping as f64;
+                    let sum_sq: f64 = degrees.iter()
+                        .map(|d| (d[idx] as f64 / overlapping).powi(2))
+                        .sum();
+                    m / (m - 1.0) * (1.0 - sum_sq)
+                }
+            ).collect()
+    }
+
+    /// All layers share the same unit
+    pub fn has_common_unit(&self) -> bool
+    {
+        let first = &self.layers[0].network;
+        self.layers.iter()
+            .all(|l| UNIT_TESTER.is_equiv(&l.network.unit, &first.unit))
+    }
+
+    /// Sum of all layers. Only makes sense if all layers share the same unit
+    pub fn aggregated(&self) -> Network
+    {
+        assert!(
+            self.has_common_unit(),
+            "Layers have incompatible units, cannot aggregate"
+        );
+        let first = &self.layers[0].network;
+        self.combined(|e| e.amount, first.unit.clone())
+    }
+
+    /// Edge weight is the number of layers that contain the edge
+    pub fn projected(&self) -> Network
+    {
+        self.combined(|_| 1.0, "Layers".to_owned())
+    }
+
+    fn combined<F>(&self, weight: F, unit: String) -> Network
+    where F: Fn(&Edge) -> f64
+    {
+        let mut adj = vec![BTreeMap::new(); self.node_count()];
+        for layer in self.layers.iter(){
+            for (idx, node) in layer.network.nodes.iter().enumerate(){
+                for e in node.adj.iter(){
+                    *adj[idx].entry(e.index).or_insert(0.0) += weight(e);
+                }
+            }
+        }
+        let nodes = self.identifiers.iter()
+            .zip(adj)
+            .map(
+                |(id, map)|
+                {
+                    Node{
+                        identifier: id.clone(),
+                        adj: map.into_iter()
+                            .map(|(index, amount)| Edge{index, amount})
+                            .collect()
+                    }
+                }
+            ).collect();
+        let first = &self.layers[0].network;
+        Network{
+            direction: first.direction,
+            data_origin: first.data_origin,
+            unit,
+            nodes,
+            year: self.year,
+            sorted_item_codes: self.layers
+                .iter()
+                .map(|l| l.item_code.clone())
+                .collect()
+        }
+    }
+}
+
+pub fn multiplex(opt: MultiplexOpts)
+{
+    let files = get_files(&opt.network_glob);
+    let multiplex = Multiplex::from_files(
+        files.iter().map(|(item, path)| (item.to_string(), path)),
+        opt.year
+    );
+    assert!(
+        multiplex.layer_count() >= 2,
+        "A multiplex needs at least two layers"
+    );
+    let stub = format!("{}_Y{}", opt.out_stub, opt.year);
+
+    let degrees = (0..multiplex.layer_count())
+        .map(|layer| multiplex.total_degrees(layer))
+        .collect_vec();
+    let strengths = (0..multiplex.layer_count())
+        .map(|layer| multiplex.total_strengths(layer))
+        .collect_vec();
+
+    let header = [
+        "item_a",
+        "item_b",
+        "shared_edges",
+        "edge_jaccard",
+        "degree_pearson",
+        "degree_spearman",
+        "strength_pearson",
+        "strength_spearman"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(
+        format!("{stub}.overlap"),
+        header
+    );
+    for (a, b) in (0..multiplex.layer_count()).tuple_combinations(){
+        let (shared, jaccard) = multiplex.edge_overlap(a, b);
+        let degree_pairs = degrees[a].iter()
+            .zip(degrees[b].iter())
+            .map(|(x, y)| (*x as f64, *y as f64))
+            .collect_vec();
+        let strength_pairs = strengths[a].iter()
+            .zip(strengths[b].iter());
+        writeln!(
+            buf,
+            "{} {} {shared} {jaccard} {} {} {} {}",
+            multiplex.layers[a].item_code,
+            multiplex.layers[b].item_code,
+            pearson_correlation_coefficient(degree_pairs.iter().copied()),
+            spearman_correlation_coefficent(degree_pairs.iter().copied()),
+            pearson_correlation_coefficient(strength_pairs.clone()),
+            spearman_correlation_coefficent(strength_pairs)
+        ).unwrap();
+    }
+
+    let top_sets = multiplex.layers
+        .iter()
+        .map(|l| get_top_k_ids(&l.network, opt.top))
+        .collect_vec();
+    let participation = multiplex.participation_coefficients();
+    let header = [
+        "country",
+        "active_layers",
+        "summed_degree",
+        "participation_coefficient",
+        "top_exporter_in_layers",
+        "top_exporter_items"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(
+        format!("{stub}.participation"),
+        header
+    );
+    for (idx, id) in multiplex.identifiers.iter().enumerate(){
+        let active = degrees.iter()
+            .filter(|d| d[idx] > 0)
+            .count();
+        let summed: usize = degrees.iter()
+            .map(|d| d[idx])
+            .sum();
+        let dominated = top_sets.iter()
+            .zip(multiplex.layers.iter())
+            .filter(|(top, _)| top.contains(&idx))
+            .map(|(_, layer)| layer.item_code.as_str())
+            .collect_vec();
+        let items = if dominated.is_empty(){
+            "-".to_owned()
+        } else {
+            dominated.join(",")
+        };
+        writeln!(
+            buf,
+            "{id} {active} {summed} {} {} {items}",
+            participation[idx],
+            dominated.len()
+        ).unwrap();
+    }
+
+    if multiplex.has_common_unit(){
+        let aggregated = vec![multiplex.aggregated()];
+        let buf = create_buf(format!("{stub}_aggregated.bincode"));
+        bincode::serialize_into(buf, &aggregated)
+            .expect("bincode serialization issue");
+    } else {
+        let units = multiplex.layers
+            .iter()
+            .map(|l| format!("{}: {}", l.item_code, l.network.unit))
+            .join(", ");
+        eprintln!("WARNING: The layers have different units ({units}), skipping the aggregated network");
+    }
+    // the projection only counts layers, so it does not depend on the units
+    let projected = vec![multiplex.projected()];
+    let buf = create_buf(format!("{stub}_projected.bincode"));
+    bincode::serialize_into(buf, &projected)
+        .expect("bincode serialization issue");
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::{Deref, RangeInclusive}
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *},
+    UNIT_TESTER
+};
+use super::{
+    flow_helper::*,
+    production_vec,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct OriginOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "origin")]
+    pub out_stub: String,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct OriginAttribution{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// Enrichment entry whose amount per unit of production is embodied in the trade flows.
+    /// No virtual flows are calculated if None
+    #[derivative(Default(value = "Some(AREA_HARVESTED.to_owned())"))]
+    pub virtual_resource: Option<String>,
+
+    /// Maximal number of iterations
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no share changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64
+}
+
+/// Where the supply of every country originally comes from
+pub struct Origins{
+    /// shares[j][i] is the fraction of the supply of country j
+    /// that was originally produced (or taken out of the stock) in country i
+    pub shares: Vec<Vec<f64>>,
+    /// Domestic supply plus imports of every country
+    pub supply: Vec<f64>,
+    pub convergence: Convergence
+}
+
+/// Domestic contribution to the supply of every country, i.e. production
+/// and, depending on the mode, stocks. Negative contributions, e.g. countries that
+/// put more into their stock than they produce, are set to 0
+pub fn domestic_supply(
+    network: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    ctx: &SimulationContext
+) -> Vec<f64>
+{
+    let unit_tester = UNIT_TESTER.deref();
+    let extra_amount = |id: &str, extra_id: u8| -> f64 {
+        enrich.get(id)
+            .and_then(|e| e.map.get(&extra_id))
+            .map_or(
+                0.0,
+                |e|
+                {
+                    assert!(unit_tester.is_equiv(&e.unit, &network.unit));
+                    e.amount
+                }
+            )
+    };
+    production_vec(network, enrich, ctx)
+        .into_iter()
+        .zip(network.nodes.iter())
+        .map(
+            |(production, node)|
+            {
+                let id = node.identifier.as_str();
+                let own = match ctx.mode{
+                    SimulationMode::Classic => production,
+                    SimulationMode::WithStockVariation => {
+                        production - extra_amount(id, ctx.availability.stock_variation_id)
+                    },
+                    SimulationMode::OnlyStock => {
+                        production + extra_amount(id, ctx.availability.stock_id)
+                    }
+                };
+                own.max(0.0)
+            }
+        ).collect()
+}
+
+/// Solves for the origins of the supply of all countries at once.
+///
+/// The supply of a country is mixed homogeneously before it is re-exported, so
+/// shares_j = (domestic_j e_j + sum_k a_jk shares_k) / (domestic_j + sum_k a_jk).
+/// This is the same accounting `flow_calc` does for a single focus country
+pub fn origin_shares(
+    import_network: &Network,
+    domestic: &[f64],
+    iterations: usize,
+    tolerance: f64
+) -> Origins
+{
+    assert!(import_network.direction.is_import());
+    let n = domestic.len();
+    let supply: Vec<f64> = import_network.nodes
+        .iter()
+        .zip(domestic.iter())
+        .map(|(node, own)| own + node.trade_amount())
+        .collect();
+
+    let mut shares = (0..n)
+        .map(
+            |j|
+            {
+                let mut s = vec![0.0; n];
+                if domestic[j] > 0.0 {
+                    s[j] = 1.0;
+                }
+                s
+            }
+        ).collect_vec();
+    let mut convergence = Convergence::default();
+
+    for _ in 0..iterations{
+        let new_shares = import_network.nodes
+            .par_iter()
+            .enumerate()
+            .map(
+                |(j, node)|
+                {
+                    let mut s = vec![0.0; n];
+                    if supply[j] <= 0.0 {
+                        return s;
+                    }
+                    s[j] = domestic[j];
+                    for e in node.adj.iter(){
+                        for (val, share) in s.iter_mut().zip(shares[e.index].iter()){
+                            *val += e.amount * share;
+                        }
+                    }
+                    let recip = supply[j].recip();
+                    s.iter_mut()
+                        .for_each(|v| *v *= recip);
+                    s
+                }
+            ).collect::<Vec<_>>();
+        let residual = new_shares.iter()
+            .flatten()
+            .zip(shares.iter().flatten())
+            .fold(0.0, |acc: f64, (new, old)| acc.max((new - old).abs()));
+        shares = new_shares;
+        if convergence.update(residual, tolerance){
+            break;
+        }
+    }
+    Origins{
+        shares,
+        supply,
+        convergence
+    }
+}
+
+/// Amount of the resource per unit of production, 0 for countries without production
+pub fn resource_intensity(
+    network: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    node_map: &ExtraInfoMap,
+    ctx: &SimulationContext,
+    resource: &str
+) -> Vec<f64>
+{
+    let resource_id = node_map.get(resource);
+    production_vec(network, enrich, ctx)
+        .into_iter()
+        .zip(network.nodes.iter())
+        .map(
+            |(production, node)|
+            {
+                if production <= 0.0 {
+                    return 0.0;
+                }
+                enrich.get(node.identifier.as_str())
+                    .and_then(|e| e.map.get(&resource_id))
+                    .map_or(0.0, |r| r.amount / production)
+            }
+        ).collect()
+}
+
+impl Origins{
+    /// Resource embodied in one unit of the supply of every country
+    pub fn embodied_per_unit(&self, intensity: &[f64]) -> Vec<f64>
+    {
+        self.shares
+            .iter()
+            .map(
+                |s|
+                {
+                    s.iter()
+                        .zip(intensity.iter())
+                        .map(|(share, i)| share * i)
+                        .sum()
+                }
+            ).collect()
+    }
+}
+
+/// Resource embodied in every trade flow. Exports carry the origin mix of the exporter
+pub fn virtual_flows<'a>(
+    export_network: &'a Network,
+    embodied_per_unit: &'a [f64]
+) -> impl Iterator<Item = (usize, usize, f64, f64)> + 'a
+{
+    assert!(!export_network.direction.is_import());
+    export_network.nodes
+        .iter()
+        .enumerate()
+        .flat_map(
+            move |(i, node)|
+            {
+                node.adj
+                    .iter()
+                    .map(move |e| (i, e.index, e.amount, e.amount * embodied_per_unit[i]))
+            }
+        )
+}
+
+pub fn origin_attribution(opt: OriginOpts)
+{
+    let json: OriginAttribution = parse_and_add_to_global(opt.json);
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let node_map = lazy_enrichments.extra_info_idmap_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&node_map);
+    let mode_str = ctx.mode_str();
+
+    json.years
+        .clone()
+        .into_par_iter()
+        .for_each(
+            |year|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+
+                let domestic = domestic_supply(&export, enrich, &ctx);
+                let origins = origin_shares(&import, &domestic, json.iterations, json.tolerance);
+                origins.convergence.warn_if_not_converged("origin attribution");
+
+                // only countries with domestic supply can be origins
+                let origin_ids = (0..export.node_count())
+                    .filter(|&i| domestic[i] > 0.0)
+                    .collect_vec();
+                let stub = format!("{}_Y{year}_{mode_str}", opt.out_stub);
+                let mut buf = create_buf_with_command_and_version(format!("{stub}.origin"));
+                writeln!(buf, "# rows: fraction of the supply of the destination that originates in the column country").unwrap();
+                writeln!(
+                    buf,
+                    "# iterations {} residual {:e} converged {}",
+                    origins.convergence.iterations,
+                    origins.convergence.residual,
+                    origins.convergence.converged
+                ).unwrap();
+                let header = ["destination", "supply"]
+                    .into_iter()
+                    .chain(origin_ids.iter().map(|&i| export.nodes[i].identifier.as_str()));
+                write_slice_head(&mut buf, header).unwrap();
+                for (j, shares) in origins.shares.iter().enumerate(){
+                    write!(buf, "{} {:e}", export.nodes[j].identifier, origins.supply[j]).unwrap();
+                    for &i in origin_ids.iter(){
+                        write!(buf, " {:e}", shares[i]).unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                }
+
+                if let Some(resource) = json.virtual_resource.as_deref(){
+                    let intensity = resource_intensity(&export, enrich, &node_map, &ctx, resource);
+                    let embodied = origins.embodied_per_unit(&intensity);
+                    let header = [
+                        "exporter",
+                        "importer",
+                        "amount",
+                        "embodied"
+                    ];
+                    let mut buf = create_buf_with_command_and_version_and_header(
+                        format!("{stub}.virtual"),
+                        header
+                    );
+                    writeln!(buf, "# embodied {resource}").unwrap();
+                    for (i, j, amount, virt) in virtual_flows(&export, &embodied){
+                        writeln!(
+                            buf,
+                            "{} {} {:e} {:e}",
+                            export.nodes[i].identifier,
+                            export.nodes[j].identifier,
+                            amount,
+                            virt
+                        ).unwrap();
+                    }
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+
+    #[test]
+    fn shares_sum_to_one() {
+        // 0 produces, 1 produces and re-exports, 2 only imports
+        let amounts = [
+            (0, 1, 10.0),
+            (1, 2, 5.0),
+            (0, 2, 5.0)
+        ];
+        let export = test_export_network(3, &amounts);
+        let import = export.invert();
+        let domestic = [20.0, 10.0, 0.0];
+        let origins = origin_shares(&import, &domestic, 100, 1e-14);
+        assert!(origins.convergence.converged);
+        // supply of 1: 10 own + 10 from 0
+        assert!((origins.shares[1][0] - 0.5).abs() < 1e-12);
+        // supply of 2: 5 from 0 directly, 5 from 1 which is half from 0
+        assert!((origins.shares[2][0] - 0.75).abs() < 1e-12);
+        for s in origins.shares.iter(){
+            assert!((s.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        }
+    }
+}
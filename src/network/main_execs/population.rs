@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::Deref,
+    path::Path
+};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *},
+    UNIT_TESTER
+};
+
+/// Population of every node of a network in one year.
+/// Used to weight the impact of a shock by the number of people living in a country
+pub struct Population{
+    /// Number of persons, None if the enrichment has no population for the country
+    pub population: Vec<Option<f64>>
+}
+
+/// Number of persons that one unit of the population data stands for.
+/// FAO gives the population in 1000 persons
+pub fn persons_per_unit(unit: &str) -> f64
+{
+    let unit_tester = UNIT_TESTER.deref();
+    if unit_tester.is_equiv(unit, "1000 persons") {
+        1000.0
+    } else if unit_tester.is_equiv(unit, "persons") {
+        1.0
+    } else {
+        panic!("Unknown unit of {TOTAL_POPULATION}: {unit}")
+    }
+}
+
+/// Population weighted impact of one shock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PopulationImpact{
+    /// People living in countries that count as unstable
+    pub affected_people: f64,
+    /// Total availability loss divided by the total population,
+    /// i.e., in the unit of the network per person
+    pub per_capita_loss: f64,
+    /// Relative availability loss of the countries, weighted by their population
+    pub weighted_loss: f64
+}
+
+impl PopulationImpact{
+    pub const HEADER: [&'static str; 3] = [
+        "affected_people",
+        "per_capita_loss",
+        "population_weighted_loss"
+    ];
+
+    pub fn write<W: Write>(&self, mut w: W) -> std::io::Result<()>
+    {
+        write!(
+            w,
+            " {:e} {:e} {:e}",
+            self.affected_people,
+            self.per_capita_loss,
+            self.weighted_loss
+        )
+    }
+}
+
+impl Population{
+    pub fn new(
+        network: &Network,
+        enrich: &BTreeMap<String, ExtraInfo>,
+        node_map: &ExtraInfoMap
+    ) -> Self
+    {
+        let population_id = node_map.get(TOTAL_POPULATION);
+        let population = network.nodes
+            .iter()
+            .map(
+                |node|
+                {
+                    enrich.get(&node.identifier)
+                        .and_then(|e| e.map.get(&population_id))
+                        .map(|p| p.amount * persons_per_unit(&p.unit))
+                        .filter(|&p| p > 0.0)
+                }
+            ).collect();
+        Self{population}
+    }
+
+    /// Countries of the slice without population data. They are ignored by [Self::impact]
+    pub fn missing<'a>(&self, network: &'a Network, countries: &[usize]) -> Vec<&'a str>
+    {
+        countries.iter()
+            .filter(|&&idx| self.population[idx].is_none())
+            .map(|&idx| network.nodes[idx].identifier.as_str())
+            .collect()
+    }
+
+    /// Writes the countries without population data, one per line
+    pub fn write_missing<P>(&self, path: P, network: &Network, countries: &[usize])
+    where P: AsRef<Path>
+    {
+        let missing = self.missing(network, countries);
+        let mut buf = create_buf_with_command_and_version(path);
+        writeln!(buf, "# {} of {} countries without population", missing.len(), countries.len()).unwrap();
+        for id in missing{
+            writeln!(buf, "{id}").unwrap();
+        }
+    }
+
+    /// Availability loss per person of one country, NaN without population data
+    pub fn per_capita_loss(&self, idx: usize, baseline: &[f64], shocked: &[f64]) -> f64
+    {
+        match self.population[idx]{
+            Some(p) => (baseline[idx] - shocked[idx]) / p,
+            None => f64::NAN
+        }
+    }
+
+    /// Header of [Self::write_per_capita], unit is the unit of the network
+    pub fn per_capita_header(unit: &str) -> [String; 5]
+    {
+        let unit = unit.replace(' ', "_");
+        [
+            "country".to_owned(),
+            "population_persons".to_owned(),
+            format!("baseline_{unit}"),
+            format!("shocked_{unit}"),
+            format!("per_capita_loss_{unit}_per_person")
+        ]
+    }
+
+    /// Writes one line per country, see [Self::per_capita_header].
+    /// Countries without population data are listed with NaN
+    pub fn write_per_capita<W: Write>(
+        &self,
+        mut w: W,
+        network: &Network,
+        countries: &[usize],
+        baseline: &[f64],
+        shocked: &[f64]
+    ) -> std::io::Result<()>
+    {
+        for &idx in countries{
+            writeln!(
+                w,
+                "{} {:e} {:e} {:e} {:e}",
+                network.nodes[idx].identifier,
+                self.population[idx].unwrap_or(f64::NAN),
+                baseline[idx],
+                shocked[idx],
+                self.per_capita_loss(idx, baseline, shocked)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn impact(
+        &self,
+        countries: &[usize],
+        baseline: &[f64],
+        shocked: &[f64],
+        unstable_country_threshold: f64
+    ) -> PopulationImpact
+    {
+        let mut total_population = 0.0;
+        let mut affected_people = 0.0;
+        let mut loss = 0.0;
+        let mut weighted_loss = 0.0;
+        for &idx in countries{
+            let population = match self.population[idx]{
+                Some(p) => p,
+                None => continue
+            };
+            total_population += population;
+            let relative = shocked[idx] / baseline[idx];
+            if relative < unstable_country_threshold {
+                affected_people += population;
+            }
+            loss += baseline[idx] - shocked[idx];
+            weighted_loss += population * (1.0 - relative);
+        }
+        if total_population == 0.0 {
+            return PopulationImpact{
+                affected_people: 0.0,
+                per_capita_loss: f64::NAN,
+                weighted_loss: f64::NAN
+            };
+        }
+        PopulationImpact{
+            affected_people,
+            per_capita_loss: loss / total_population,
+            weighted_loss: weighted_loss / total_population
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_population_is_ignored() {
+        let population = Population{
+            population: vec![Some(10.0), None, Some(30.0)]
+        };
+        let baseline = [1.0, 1.0, 2.0];
+        let shocked = [0.5, 0.0, 2.0];
+        let impact = population.impact(&[0, 1, 2], &baseline, &shocked, 0.7);
+        assert_eq!(impact.affected_people, 10.0);
+        assert!((impact.per_capita_loss - 0.5 / 40.0).abs() < 1e-12);
+        assert!((impact.weighted_loss - 5.0 / 40.0).abs() < 1e-12);
+        assert_eq!(persons_per_unit("1000 persons"), 1000.0);
+        assert_eq!(persons_per_unit("persons"), 1.0);
+    }
+}
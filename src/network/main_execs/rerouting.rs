@@ -0,0 +1,228 @@
+use crate::network::Network;
+use serde::{Serialize, Deserialize};
+use derivative::Derivative;
+use super::{
+    flow_helper::{CalcShockMultiJob, Convergence},
+    shock_solver::SolverBackend,
+    ShockRes
+};
+
+/// Importers with a shortfall try to get more from their remaining partners
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Derivative, PartialEq)]
+#[derivative(Default)]
+pub struct Adaptation{
+    /// Fraction of the shortfall an importer tries to replace.
+    /// 0 is the fixed topology of the classic model
+    #[derivative(Default(value="0.5"))]
+    pub strength: f64,
+
+    /// Partners can export at most this fraction of their
+    /// current exports in addition
+    #[derivative(Default(value="0.2"))]
+    pub spare_capacity: f64
+}
+
+impl Adaptation{
+    pub fn name_addition(&self) -> String
+    {
+        format!("_Adapt{}_{}", self.strength, self.spare_capacity)
+    }
+}
+
+/// The rerouting sweeps are Jacobi sweeps with an additional request step.
+/// Gauss-Seidel and Anderson are not implemented for them, so instead of
+/// silently ignoring the chosen solver this panics
+pub fn assert_solver_supported(adaptation: Option<&Adaptation>, solver: SolverBackend)
+{
+    assert!(
+        adaptation.is_none() || solver == SolverBackend::Jacobi,
+        "adaptation only works with the Jacobi solver, but {solver:?} was chosen"
+    );
+}
+
+/// Shock propagation where importers reroute their demand.
+///
+/// In every sweep each importer requests strength times its shortfall from its
+/// remaining suppliers, proportional to what they still deliver.
+/// Suppliers that are not restricted grant these requests up to their spare capacity.
+/// Granted amounts count as imports for the importer and as additional exports for the supplier,
+/// i.e., the returned export fractions can be larger than 1.
+/// The granted amounts are taken from the spare capacity of the supplier.
+/// They only enter the returned export fractions and are not propagated further,
+/// i.e., they neither reduce what the supplier delivers to its other partners
+/// nor what it can grant in the next sweep
+#[allow(clippy::needless_range_loop)]
+pub fn adaptive_shock_distribution(
+    import_network: &Network,
+    job: &CalcShockMultiJob,
+    adaptation: &Adaptation
+) -> ShockRes
+{
+    assert!(import_network.direction.is_import());
+    let n = job.original_exports.len();
+    let mut current_export_frac = vec![1.0; n];
+    for e in job.exporter.iter(){
+        current_export_frac[e.export_id] = e.export_frac;
+    }
+    let mut is_free = vec![false; n];
+    for &idx in job.unrestricted_node_idxs.iter(){
+        is_free[idx] = true;
+    }
+
+    let mut reduced_import_frac: Vec<f64> = vec![1.0; n];
+    let mut base_import = vec![0.0; n];
+    let mut demand = vec![0.0; n];
+    let mut granted = vec![1.0; n];
+    let mut convergence = Convergence::default();
+
+    for _ in 0..job.iterations{
+        let mut residual: f64 = 0.0;
+
+        // requests of the importers
+        demand.iter_mut().for_each(|d| *d = 0.0);
+        for (index, node) in import_network.nodes.iter().enumerate(){
+            base_import[index] = node.adj
+                .iter()
+                .map(|e| e.amount * current_export_frac[e.index])
+                .sum();
+            let shortfall = (job.original_imports[index] - base_import[index]).max(0.0);
+            if shortfall <= 0.0 || base_import[index] <= 0.0 {
+                continue;
+            }
+            let factor = adaptation.strength * shortfall / base_import[index];
+            for e in node.adj.iter(){
+                demand[e.index] += factor * e.amount * current_export_frac[e.index];
+            }
+        }
+
+        // what the suppliers can grant
+        for j in 0..n{
+            let capacity = if is_free[j] {
+                adaptation.spare_capacity * job.original_exports[j] * current_export_frac[j]
+            } else {
+                0.0
+            };
+            granted[j] = if demand[j] > capacity {
+                capacity / demand[j]
+            } else {
+                1.0
+            };
+        }
+
+        for (index, node) in import_network.nodes.iter().enumerate(){
+            let old = reduced_import_frac[index];
+            if job.original_imports[index] == 0.0{
+                reduced_import_frac[index] = 0.0;
+                residual = residual.max(old.abs());
+                continue;
+            }
+            let shortfall = (job.original_imports[index] - base_import[index]).max(0.0);
+            let extra = if shortfall > 0.0 && base_import[index] > 0.0 {
+                let factor = adaptation.strength * shortfall / base_import[index];
+                node.adj
+                    .iter()
+                    .map(|e| factor * e.amount * current_export_frac[e.index] * granted[e.index])
+                    .sum()
+            } else {
+                0.0
+            };
+            let new = ((base_import[index] + extra) * job.original_imports_recip[index]).min(1.0);
+            residual = residual.max((new - old).abs());
+            reduced_import_frac[index] = new;
+        }
+
+        for &index in job.unrestricted_node_idxs.iter()
+        {
+            let missing_imports = (1.0 - reduced_import_frac[index]) * job.original_imports[index];
+            let available_for_export = job.original_exports[index]
+                - missing_imports
+                - job.production_loss(index);
+            let new_frac = if available_for_export <= 0.0 {
+                0.0
+            } else {
+                available_for_export * job.original_exports_recip[index]
+            };
+            residual = residual.max((new_frac - current_export_frac[index]).abs());
+            current_export_frac[index] = new_frac;
+        }
+        if convergence.update(residual, job.tolerance){
+            break;
+        }
+    }
+
+    // additional exports of the suppliers
+    let export_fracs = current_export_frac.iter()
+        .zip(demand.iter())
+        .zip(granted.iter())
+        .zip(job.original_exports_recip.iter())
+        .map(
+            |(((x, d), g), recip)|
+            {
+                if *d > 0.0 {
+                    x + d * g * recip
+                } else {
+                    *x
+                }
+            }
+        ).collect();
+
+    ShockRes{
+        import_fracs: reduced_import_frac,
+        export_fracs,
+        convergence,
+        production_loss: job.production_loss.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+    use super::super::{flow_helper::*, multi_shock_distribution};
+
+    #[test]
+    fn without_strength_like_jacobi() {
+        let export = test_export_network(
+            5,
+            &[(0, 1, 10.0), (1, 2, 7.0), (2, 0, 3.0), (2, 3, 5.0), (3, 1, 2.0), (0, 3, 4.0), (4, 0, 6.0)]
+        );
+        let import = export.invert();
+        let oe = calc_acc_trade(&export);
+        let oe_recip = calc_recip(&oe);
+        let oi = calc_acc_trade(&import);
+        let oi_recip = calc_recip(&oi);
+        let job = CalcShockMultiJob::new_const_export(&[0], 0.3, 10000, &export, &oe, &oe_recip, &oi, &oi_recip);
+        let fixed = multi_shock_distribution(&import, &job);
+        let adaptation = Adaptation{strength: 0.0, spare_capacity: 0.2};
+        let job = job.with_adaptation(Some(adaptation));
+        let adapted = multi_shock_distribution(&import, &job);
+        assert!(adapted.convergence.converged);
+        for (a, b) in adapted.export_fracs.iter().zip(fixed.export_fracs.iter()){
+            assert!((a - b).abs() < 1e-9);
+        }
+        for (a, b) in adapted.import_fracs.iter().zip(fixed.import_fracs.iter()){
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn granted_within_spare_capacity() {
+        // 2 imports from the restricted 0 and from 1, which has no imports itself
+        let export = test_export_network(3, &[(0, 2, 10.0), (1, 2, 5.0)]);
+        let import = export.invert();
+        let oe = calc_acc_trade(&export);
+        let oe_recip = calc_recip(&oe);
+        let oi = calc_acc_trade(&import);
+        let oi_recip = calc_recip(&oi);
+        let adaptation = Adaptation{strength: 1.0, spare_capacity: 0.2};
+        let job = CalcShockMultiJob::new_const_export(&[0], 0.2, 10000, &export, &oe, &oe_recip, &oi, &oi_recip)
+            .with_adaptation(Some(adaptation));
+        let res = multi_shock_distribution(&import, &job);
+        assert!(res.convergence.converged);
+        // 2 asks for more than 1 can spare, so 1 grants exactly its spare capacity
+        assert!((res.export_fracs[1] - (1.0 + adaptation.spare_capacity)).abs() < 1e-12);
+        // the restricted exporter grants nothing
+        assert!((res.export_fracs[0] - 0.2).abs() < 1e-12);
+        assert!((res.import_fracs[2] - (2.0 + 5.0 + 1.0) / 15.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,298 @@
+use itertools::Itertools;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+/// How the export fractions of the shock clouds are sampled.
+/// All samplers draw from the same fixed sum constraint
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum Sampler{
+    /// Independent uniform random numbers
+    #[default]
+    Random,
+    /// Halton sequence with a random shift
+    Halton,
+    /// Sobol sequence with a random digital shift.
+    /// Supports up to 11 disrupted countries
+    Sobol,
+    /// The samples of every cloud step are stratified in every dimension
+    LatinHypercube,
+    /// Uniform samples, but more cloud steps are spent on small export sums.
+    /// The targets are max * (step / (steps - 1))^exponent.
+    /// This only changes the schedule of the targets, the samples are not weighted.
+    /// The average of every single bin is unaffected, but the hits per bin are no longer uniform
+    /// and anything pooled over several bins is biased towards small export sums
+    Importance{
+        exponent: f64
+    }
+}
+
+impl Sampler{
+    pub fn name(&self) -> String
+    {
+        match self{
+            Self::Random => "Random".to_owned(),
+            Self::Halton => "Halton".to_owned(),
+            Self::Sobol => "Sobol".to_owned(),
+            Self::LatinHypercube => "LatinHypercube".to_owned(),
+            Self::Importance{exponent} => format!("Importance{exponent}")
+        }
+    }
+
+    /// Target sum of the export fractions of the given cloud step
+    pub fn target(&self, step: usize, steps: usize, max: f64) -> f64
+    {
+        match self{
+            Self::Importance{exponent} => {
+                let frac = if steps > 1 {
+                    step as f64 / (steps - 1) as f64
+                } else {
+                    0.0
+                };
+                (frac.powf(*exponent) * max).min(max)
+            },
+            _ => {
+                // same steps as the original shock cloud
+                let delta = max / (steps - 1) as f64;
+                (step as f64 * delta).min(max)
+            }
+        }
+    }
+
+    /// None for the samplers that draw independent uniform random numbers.
+    /// These keep the draws of the original shock cloud
+    pub fn point_source<R: Rng>(&self, dims: usize, rng: &mut R) -> Option<PointSource>
+    {
+        let kind = match self{
+            Self::Random | Self::Importance{..} => return None,
+            Self::LatinHypercube => PointKind::LatinHypercube,
+            Self::Halton => {
+                let bases = first_primes(dims);
+                let shift = (0..dims)
+                    .map(|_| rng.gen::<f64>())
+                    .collect();
+                PointKind::Halton{bases, shift}
+            },
+            Self::Sobol => {
+                let directions = sobol_directions(dims);
+                let shift = (0..dims)
+                    .map(|_| rng.gen::<u32>())
+                    .collect();
+                PointKind::Sobol{directions, shift}
+            }
+        };
+        Some(
+            PointSource{
+                kind,
+                dims,
+                index: 0
+            }
+        )
+    }
+}
+
+enum PointKind{
+    LatinHypercube,
+    Halton{
+        bases: Vec<u64>,
+        shift: Vec<f64>
+    },
+    Sobol{
+        directions: Vec<[u32; 32]>,
+        shift: Vec<u32>
+    }
+}
+
+/// Generates points in the unit hypercube.
+/// Quasi random sequences continue where the last batch stopped
+pub struct PointSource{
+    kind: PointKind,
+    dims: usize,
+    index: u64
+}
+
+impl PointSource{
+    /// m points of dimension dims
+    pub fn points<R: Rng>(&mut self, m: usize, rng: &mut R) -> Vec<Vec<f64>>
+    {
+        let dims = self.dims;
+        match &self.kind{
+            PointKind::LatinHypercube => {
+                let mut points = vec![vec![0.0; dims]; m];
+                let mut strata = (0..m).collect_vec();
+                let recip = (m as f64).recip();
+                for d in 0..dims{
+                    strata.shuffle(rng);
+                    for (point, stratum) in points.iter_mut().zip(strata.iter()){
+                        point[d] = (*stratum as f64 + rng.gen::<f64>()) * recip;
+                    }
+                }
+                points
+            },
+            PointKind::Halton{bases, shift} => {
+                let start = self.index;
+                self.index += m as u64;
+                (start..start + m as u64)
+                    .map(
+                        |i|
+                        {
+                            bases.iter()
+                                .zip(shift.iter())
+                                // index 0 would be the origin in every dimension
+                                .map(|(&b, s)| (radical_inverse(i + 1, b) + s).fract())
+                                .collect()
+                        }
+                    ).collect()
+            },
+            PointKind::Sobol{directions, shift} => {
+                let start = self.index;
+                self.index += m as u64;
+                let scale = 2.0_f64.powi(-32);
+                (start..start + m as u64)
+                    .map(
+                        |i|
+                        {
+                            let gray = i ^ (i >> 1);
+                            directions.iter()
+                                .zip(shift.iter())
+                                .map(
+                                    |(v, s)|
+                                    {
+                                        let x = (0..32)
+                                            .filter(|bit| gray & (1 << bit) != 0)
+                                            .fold(0, |acc, bit| acc ^ v[bit]);
+                                        (x ^ s) as f64 * scale
+                                    }
+                                ).collect()
+                        }
+                    ).collect()
+            }
+        }
+    }
+}
+
+fn radical_inverse(mut i: u64, base: u64) -> f64
+{
+    let recip = (base as f64).recip();
+    let mut f = recip;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * f;
+        i /= base;
+        f *= recip;
+    }
+    result
+}
+
+fn first_primes(n: usize) -> Vec<u64>
+{
+    let mut primes: Vec<u64> = Vec::with_capacity(n);
+    let mut candidate = 2;
+    while primes.len() < n {
+        if primes.iter().take_while(|&&p| p * p <= candidate).all(|p| candidate % p != 0) {
+            primes.push(candidate);
+        }
+        candidate += 1;
+    }
+    primes
+}
+
+/// Degree s, coefficients a and initial direction numbers m of the
+/// primitive polynomials, starting with dimension 2 (Joe and Kuo, new-joe-kuo-6.21201)
+const SOBOL_TABLE: [(u32, u32, &[u32]); 20] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+    (6, 19, &[1, 1, 1, 15, 7, 5]),
+    (6, 22, &[1, 3, 1, 15, 13, 25]),
+    (6, 25, &[1, 1, 5, 5, 19, 61]),
+    (7, 1, &[1, 3, 7, 11, 23, 15, 103]),
+    (7, 4, &[1, 3, 7, 13, 13, 15, 69])
+];
+
+fn sobol_directions(dims: usize) -> Vec<[u32; 32]>
+{
+    assert!(
+        dims <= SOBOL_TABLE.len() + 1,
+        "Sobol supports at most {} dimensions, i.e., {} countries. Use Halton instead",
+        SOBOL_TABLE.len() + 1,
+        SOBOL_TABLE.len().div_ceil(2) + 1
+    );
+    (0..dims)
+        .map(
+            |d|
+            {
+                let mut v = [0_u32; 32];
+                if d == 0 {
+                    for (k, val) in v.iter_mut().enumerate(){
+                        *val = 1 << (31 - k);
+                    }
+                    return v;
+                }
+                let (s, a, m) = SOBOL_TABLE[d - 1];
+                let s = s as usize;
+                for k in 0..32{
+                    v[k] = if k < s {
+                        m[k] << (31 - k)
+                    } else {
+                        let mut val = v[k - s] ^ (v[k - s] >> s);
+                        for l in 1..s{
+                            if (a >> (s - 1 - l)) & 1 == 1 {
+                                val ^= v[k - l];
+                            }
+                        }
+                        val
+                    };
+                }
+                v
+            }
+        ).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn latin_hypercube_is_stratified() {
+        let mut rng = Pcg64::seed_from_u64(12);
+        let mut source = Sampler::LatinHypercube.point_source(4, &mut rng).unwrap();
+        let points = source.points(10, &mut rng);
+        for d in 0..4{
+            let strata = points.iter()
+                .map(|p| (p[d] * 10.0) as usize)
+                .sorted()
+                .collect_vec();
+            assert_eq!(strata, (0..10).collect_vec());
+        }
+    }
+
+    #[test]
+    fn sobol_is_stratified() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let mut source = Sampler::Sobol.point_source(21, &mut rng).unwrap();
+        let points = source.points(16, &mut rng);
+        // every dimension of the first 16 points hits every interval of length 1/16 once,
+        // the digital shift only permutes the intervals
+        for d in 0..21{
+            let strata = points.iter()
+                .map(|p| (p[d] * 16.0) as usize)
+                .sorted()
+                .collect_vec();
+            assert_eq!(strata, (0..16).collect_vec());
+        }
+    }
+}
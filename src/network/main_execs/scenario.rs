@@ -0,0 +1,394 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    group_shock::read_groups,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    production_vec,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct ScenarioOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Name of the consolidated results table
+    #[arg(long, short, default_value = "scenarios.table")]
+    pub out: Utf8PathBuf,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool
+}
+
+/// Countries that are disrupted in a scenario
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub enum ScenarioTargets{
+    /// The top exporters of every year
+    #[derivative(Default)]
+    Top{
+        #[derivative(Default(value = "1"))]
+        top: usize
+    },
+    /// Countries given by their ids
+    Ids{
+        ids: Vec<String>
+    },
+    /// All members of a group of the group file, see GroupShock
+    Group{
+        group_file: Utf8PathBuf,
+        group: String
+    }
+}
+
+impl ScenarioTargets{
+    pub fn name(&self) -> String
+    {
+        match self{
+            Self::Top{top} => format!("Top{top}"),
+            Self::Ids{ids} => ids.iter().join(","),
+            Self::Group{group, ..} => group.clone()
+        }
+    }
+}
+
+/// One named scenario. Every year and every magnitude is simulated independently
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct Scenario{
+    /// Key of the scenario in the results table, has to be unique
+    #[derivative(Default(value = "\"baseline_rice\".to_owned()"))]
+    pub name: String,
+
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    pub targets: ScenarioTargets,
+
+    /// Remaining fractions of the exports or production of the targets
+    #[derivative(Default(value = "vec![0.0, 0.5]"))]
+    pub magnitudes: Vec<f64>,
+
+    /// Restrict the exports or reduce the production of the targets
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    #[serde(default)]
+    pub mode: SimulationMode,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock are not counted as unstable
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct ScenarioFile{
+    #[derivative(Default(value = "vec![Scenario::default()]"))]
+    pub scenarios: Vec<Scenario>
+}
+
+/// One line of the results table
+struct ScenarioRow{
+    scenario: usize,
+    year: i32,
+    magnitude: f64,
+    targets: String,
+    unstable: usize,
+    counted: usize,
+    missing_supply: f64,
+    convergence: Convergence
+}
+
+pub fn run_scenarios(opt: ScenarioOpts)
+{
+    let json: ScenarioFile = parse_and_add_to_global(opt.json);
+    let scenarios = &json.scenarios;
+    assert!(
+        scenarios.iter().map(|s| s.name.as_str()).all_unique(),
+        "Scenario names have to be unique"
+    );
+    assert!(
+        scenarios.iter().all(|s| !s.name.is_empty() && !s.name.contains(char::is_whitespace)),
+        "Scenario names must not be empty or contain whitespace"
+    );
+    for s in scenarios.iter(){
+        assert!(
+            s.magnitudes.iter().all(|m| (0.0..=1.0).contains(m)),
+            "{}: magnitudes have to be in range 0.0..=1.0",
+            s.name
+        );
+    }
+
+    // every file is only read once, even if many scenarios use it
+    let networks: BTreeMap<&Utf8PathBuf, LazyNetworks> = scenarios.iter()
+        .map(|s| &s.network_file)
+        .unique()
+        .map(
+            |file|
+            {
+                let mut lazy = LazyNetworks::Filename(file.clone());
+                lazy.assure_availability();
+                (file, lazy)
+            }
+        ).collect();
+    let enrichments: BTreeMap<(&str, Option<&str>), LazyEnrichmentInfos> = scenarios.iter()
+        .map(|s| (s.enrich_file.as_str(), s.item_code.as_deref()))
+        .unique()
+        .map(
+            |(file, item)|
+            {
+                let mut lazy = LazyEnrichmentInfos::Filename(file.to_owned(), item.map(str::to_owned));
+                lazy.assure_availability();
+                ((file, item), lazy)
+            }
+        ).collect();
+    let groups: BTreeMap<&Utf8PathBuf, BTreeMap<String, Vec<String>>> = scenarios.iter()
+        .filter_map(
+            |s|
+            {
+                match &s.targets{
+                    ScenarioTargets::Group{group_file, ..} => Some(group_file),
+                    _ => None
+                }
+            }
+        ).unique()
+        .map(|file| (file, read_groups(file)))
+        .collect();
+    let contexts = scenarios.iter()
+        .map(
+            |s|
+            {
+                let lazy = &enrichments[&(s.enrich_file.as_str(), s.item_code.as_deref())];
+                SimulationContext::new(s.mode)
+                    .with_node_map(&lazy.extra_info_idmap_unchecked())
+            }
+        ).collect_vec();
+
+    let jobs = scenarios.iter()
+        .enumerate()
+        .flat_map(|(i, s)| s.years.clone().map(move |year| (i, year)))
+        .collect_vec();
+
+    let mut rows: Vec<ScenarioRow> = jobs
+        .into_par_iter()
+        .flat_map_iter(
+            |(i, year)|
+            {
+                let s = &scenarios[i];
+                let ctx = &contexts[i];
+                let export = networks[&s.network_file]
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichments[&(s.enrich_file.as_str(), s.item_code.as_deref())]
+                    .enrichment_infos_unchecked()
+                    .get_year(year);
+
+                let targets = match &s.targets{
+                    ScenarioTargets::Top{top} => get_top_k_ids(&export, *top),
+                    ScenarioTargets::Ids{ids} => {
+                        ids.iter()
+                            .filter_map(|id| export.get_index(id))
+                            .collect_vec()
+                    },
+                    ScenarioTargets::Group{group_file, group} => {
+                        groups[group_file]
+                            .get(group)
+                            .unwrap_or_else(|| panic!("{}: group {group} is not in {group_file}", s.name))
+                            .iter()
+                            .filter_map(|id| export.get_index(id))
+                            .collect_vec()
+                    }
+                };
+                if targets.is_empty() && !opt.quiet{
+                    eprintln!("{} Y{year}: none of the targets trade", s.name);
+                }
+                let target_str = targets.iter()
+                    .map(|&idx| export.nodes[idx].identifier.as_str())
+                    .join(",");
+
+                let original_exports = calc_acc_trade(&export);
+                let original_exports_recip = calc_recip(&original_exports);
+                let original_imports = calc_acc_trade(&import);
+                let original_imports_recip = calc_recip(&original_imports);
+                let production = production_vec(&export, enrich, ctx);
+                let order = if s.solver.needs_order(){
+                    sweep_order(&import)
+                } else {
+                    Vec::new()
+                };
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, _) = calc_available(&export, enrich, &no_shock, ctx, opt.quiet);
+                let counted = (0..export.node_count())
+                    .filter(|idx| !targets.contains(idx))
+                    .filter(|&idx| baseline[idx] >= s.original_avail_filter)
+                    .collect_vec();
+                let total: f64 = counted.iter()
+                    .map(|&idx| baseline[idx])
+                    .sum();
+
+                s.magnitudes
+                    .iter()
+                    .map(
+                        |&magnitude|
+                        {
+                            let job = match s.shock_type{
+                                ShockType::ExportRestriction => {
+                                    CalcShockMultiJob::new_const_export(
+                                        &targets,
+                                        magnitude,
+                                        s.iterations,
+                                        &export,
+                                        &original_exports,
+                                        &original_exports_recip,
+                                        &original_imports,
+                                        &original_imports_recip
+                                    )
+                                },
+                                ShockType::Production => {
+                                    CalcShockMultiJob::new_const_production(
+                                        &targets,
+                                        magnitude,
+                                        &production,
+                                        s.iterations,
+                                        &export,
+                                        &original_exports,
+                                        &original_exports_recip,
+                                        &original_imports,
+                                        &original_imports_recip
+                                    )
+                                }
+                            }.with_tolerance(s.tolerance)
+                            .with_solver(s.solver, &order);
+                            let res = multi_shock_distribution(&import, &job);
+                            if !opt.quiet{
+                                res.convergence.warn_if_not_converged(&s.name);
+                            }
+                            let (avail, _) = calc_available(&export, enrich, &res, ctx, opt.quiet);
+                            let unstable = counted.iter()
+                                .filter(|&&idx| avail[idx] / baseline[idx] < s.unstable_country_threshold)
+                                .count();
+                            let missing: f64 = counted.iter()
+                                .map(|&idx| (baseline[idx] - avail[idx]).max(0.0))
+                                .sum();
+                            ScenarioRow{
+                                scenario: i,
+                                year,
+                                magnitude,
+                                targets: target_str.clone(),
+                                unstable,
+                                counted: counted.len(),
+                                // without counted countries nothing can be missing
+                                missing_supply: if total > 0.0 {
+                                    missing / total
+                                } else {
+                                    0.0
+                                },
+                                convergence: res.convergence
+                            }
+                        }
+                    ).collect_vec()
+            }
+        ).collect();
+    rows.sort_by(
+        |a, b|
+        {
+            a.scenario.cmp(&b.scenario)
+                .then(a.year.cmp(&b.year))
+                .then(a.magnitude.total_cmp(&b.magnitude))
+        }
+    );
+
+    let header = [
+        "scenario",
+        "item",
+        "year",
+        "mode",
+        "shock_type",
+        "targets",
+        "remaining_frac",
+        "threshold",
+        "unstable_countries",
+        "counted_countries",
+        "missing_supply",
+        "iterations",
+        "converged"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(&opt.out, header);
+    for s in scenarios.iter(){
+        writeln!(buf, "# {}: targets {}", s.name, s.targets.name()).unwrap();
+    }
+    for row in rows{
+        let s = &scenarios[row.scenario];
+        // the ids are joined with commas, so the table stays whitespace separated
+        let targets = if row.targets.is_empty(){
+            "-"
+        } else {
+            row.targets.as_str()
+        };
+        writeln!(
+            buf,
+            "{} {} {} {} {:?} {targets} {} {} {} {} {:e} {} {}",
+            s.name,
+            s.item_code.as_deref().unwrap_or("-"),
+            row.year,
+            s.mode.as_str(),
+            s.shock_type,
+            row.magnitude,
+            s.unstable_country_threshold,
+            row.unstable,
+            row.counted,
+            row.missing_supply,
+            row.convergence.iterations,
+            row.convergence.converged
+        ).unwrap();
+    }
+}
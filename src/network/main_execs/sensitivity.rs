@@ -0,0 +1,357 @@
+use std::{
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct SensitivityOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "sensitivity")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+/// How the derivatives are obtained
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Derivative, PartialEq)]
+#[derivative(Default)]
+pub enum SensitivityMethod{
+    /// Restrict every exporter to 1 - epsilon and propagate the shock.
+    /// Captures countries whose exports drop to zero
+    #[derivative(Default)]
+    FiniteDifference{
+        #[derivative(Default(value = "1e-3"))]
+        epsilon: f64
+    },
+    /// Linearize the propagation around the unshocked network.
+    /// Exact for infinitesimal restrictions, i.e., only countries without
+    /// exports absorb the shortfall, all others pass it on
+    Linearized
+}
+
+impl SensitivityMethod{
+    pub fn name(&self) -> &'static str
+    {
+        match self{
+            Self::FiniteDifference{..} => "FD",
+            Self::Linearized => "Lin"
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct Sensitivity{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// Only the columns of this many top exporters are calculated.
+    /// If None, all countries that export something are used
+    pub exporters: Option<usize>,
+
+    pub method: SensitivityMethod,
+
+    /// Length of the ranked list of critical suppliers of each importer
+    #[derivative(Default(value = "5"))]
+    pub ranked: usize,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation of the finite differences
+    #[serde(default)]
+    pub solver: SolverBackend,
+
+    /// Countries that have less than this amount of
+    /// product without shock are not listed as importers
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64
+}
+
+/// dAvailability_i / dExportFrac_j of all countries i for the exporter j,
+/// obtained from the propagation linearized around the unshocked network.
+///
+/// At the unshocked network every country with exports passes a shortfall
+/// completely on to its own exports, dx_i = sum_k a_ik dx_k / E_i,
+/// while countries without exports absorb it.
+/// If the resulting series diverges only finite differences are meaningful
+pub fn linearized_column(
+    import_network: &Network,
+    original_exports: &[f64],
+    exporter: usize,
+    iterations: usize,
+    tolerance: f64
+) -> (Vec<f64>, Convergence)
+{
+    assert!(import_network.direction.is_import());
+    let n = original_exports.len();
+    let mut dx = vec![0.0; n];
+    dx[exporter] = 1.0;
+    let mut next = dx.clone();
+    let incoming = |dx: &[f64], i: usize| -> f64 {
+        import_network.nodes[i]
+            .adj
+            .iter()
+            .map(|e| e.amount * dx[e.index])
+            .sum()
+    };
+    let mut convergence = Convergence::default();
+    for _ in 0..iterations{
+        let mut residual: f64 = 0.0;
+        for (i, val) in next.iter_mut().enumerate(){
+            if i == exporter || original_exports[i] == 0.0 {
+                continue;
+            }
+            *val = incoming(&dx, i) / original_exports[i];
+            residual = residual.max((*val - dx[i]).abs());
+        }
+        std::mem::swap(&mut dx, &mut next);
+        if convergence.update(residual, tolerance){
+            break;
+        }
+    }
+    let column = (0..n)
+        .map(|i| incoming(&dx, i) - original_exports[i] * dx[i])
+        .collect();
+    (column, convergence)
+}
+
+pub fn sensitivity_matrix(opt: SensitivityOpts)
+{
+    let json: Sensitivity = parse_and_add_to_global(opt.json);
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+    let method_str = json.method.name();
+    let mode_str = ctx.mode_str();
+
+    json.years
+        .clone()
+        .into_par_iter()
+        .for_each(
+            |year|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+
+                let original_exports = calc_acc_trade(&export);
+                let original_exports_recip = calc_recip(&original_exports);
+                let original_imports = calc_acc_trade(&import);
+                let original_imports_recip = calc_recip(&original_imports);
+                let order = if json.solver.needs_order(){
+                    sweep_order(&import)
+                } else {
+                    Vec::new()
+                };
+
+                let exporters = match json.exporters{
+                    Some(k) => get_top_k_ids(&export, k),
+                    None => {
+                        get_top_k_ids(&export, export.node_count())
+                            .into_iter()
+                            .filter(|&idx| original_exports[idx] > 0.0)
+                            .collect_vec()
+                    }
+                };
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, flow_status) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+
+                // one column per exporter
+                let columns: Vec<(Vec<f64>, Convergence)> = exporters
+                    .par_iter()
+                    .map(
+                        |&j|
+                        {
+                            match json.method{
+                                SensitivityMethod::Linearized => {
+                                    linearized_column(
+                                        &import,
+                                        &original_exports,
+                                        j,
+                                        json.iterations,
+                                        json.tolerance
+                                    )
+                                },
+                                SensitivityMethod::FiniteDifference{epsilon} => {
+                                    let job = CalcShockMultiJob::new_const_export(
+                                        &[j],
+                                        1.0 - epsilon,
+                                        json.iterations,
+                                        &export,
+                                        &original_exports,
+                                        &original_exports_recip,
+                                        &original_imports,
+                                        &original_imports_recip
+                                    ).with_tolerance(json.tolerance)
+                                    .with_solver(json.solver, &order);
+                                    let res = multi_shock_distribution(&import, &job);
+                                    let (avail, _) = calc_available(&export, enrich, &res, &ctx, opt.quiet);
+                                    let column = baseline.iter()
+                                        .zip(avail.iter())
+                                        .map(|(b, a)| (b - a) / epsilon)
+                                        .collect();
+                                    (column, res.convergence)
+                                }
+                            }
+                        }
+                    ).collect();
+
+                let mut convergence = ConvergenceSummary::default();
+                columns.iter()
+                    .for_each(|(_, c)| convergence.add(c));
+                if convergence.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} - {} of {} columns did not converge",
+                        convergence.not_converged,
+                        convergence.runs
+                    );
+                }
+
+                let importers = (0..export.node_count())
+                    .filter(|&i| baseline[i] >= json.original_avail_filter)
+                    .collect_vec();
+
+                let stub = format!(
+                    "{}{}_Y{year}_{method_str}_{mode_str}",
+                    flow_status.name_addition(),
+                    opt.out_stub
+                );
+                let mut buf = create_buf_with_command_and_version(format!("{stub}.matrix"));
+                writeln!(buf, "# dAvailability_i / dExportFrac_j, rows i are importers, columns j exporters").unwrap();
+                convergence.write_comment(&mut buf).unwrap();
+                let header = std::iter::once("importer")
+                    .chain(exporters.iter().map(|&j| export.nodes[j].identifier.as_str()));
+                write_slice_head(&mut buf, header).unwrap();
+                for &i in importers.iter(){
+                    write!(buf, "{}", export.nodes[i].identifier).unwrap();
+                    for (column, _) in columns.iter(){
+                        write!(buf, " {:e}", column[i]).unwrap();
+                    }
+                    writeln!(buf).unwrap();
+                }
+
+                let header = [
+                    "importer",
+                    "rank",
+                    "exporter",
+                    "sensitivity",
+                    "relative_sensitivity"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.critical"), header);
+                for &i in importers.iter(){
+                    let ranked = exporters.iter()
+                        .zip(columns.iter())
+                        .filter(|(&j, _)| j != i)
+                        .map(|(&j, (column, _))| (j, column[i]))
+                        .filter(|(_, s)| *s > 0.0)
+                        .sorted_by(|a, b| b.1.total_cmp(&a.1))
+                        .take(json.ranked);
+                    for (rank, (j, s)) in ranked.enumerate(){
+                        writeln!(
+                            buf,
+                            "{} {} {} {:e} {:e}",
+                            export.nodes[i].identifier,
+                            rank + 1,
+                            export.nodes[j].identifier,
+                            s,
+                            s / baseline[i]
+                        ).unwrap();
+                    }
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+
+    #[test]
+    fn linearized_matches_finite_difference() {
+        // 0 exports to 1, which re-exports half of it to 2 and 3
+        let amounts = [
+            (0, 1, 10.0),
+            (1, 2, 3.0),
+            (1, 3, 2.0),
+            (4, 2, 1.0)
+        ];
+        let export = test_export_network(5, &amounts);
+        let import = export.invert();
+        let oe = calc_acc_trade(&export);
+        let oe_recip = calc_recip(&oe);
+        let oi = calc_acc_trade(&import);
+        let oi_recip = calc_recip(&oi);
+        let ctx = SimulationContext::default();
+        let enrich = Default::default();
+        let (baseline, _) = calc_available(&export, &enrich, &ShockRes::no_shock(5), &ctx, true);
+
+        let (lin, convergence) = linearized_column(&import, &oe, 0, 1000, 1e-14);
+        assert!(convergence.converged);
+
+        let epsilon = 1e-4;
+        let job = CalcShockMultiJob::new_const_export(&[0], 1.0 - epsilon, 1000, &export, &oe, &oe_recip, &oi, &oi_recip);
+        let res = multi_shock_distribution(&import, &job);
+        let (avail, _) = calc_available(&export, &enrich, &res, &ctx, true);
+        for i in 0..5{
+            let fd = (baseline[i] - avail[i]) / epsilon;
+            assert!((fd - lin[i]).abs() < 1e-6, "{i}: {fd} vs {}", lin[i]);
+        }
+        assert!((lin[2] - 6.0).abs() < 1e-9);
+    }
+}
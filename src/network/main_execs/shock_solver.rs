@@ -0,0 +1,349 @@
+use crate::network::{Network, Node};
+use itertools::Itertools;
+use serde::{Serialize, Deserialize};
+use super::{
+    flow_helper::{CalcShockMultiJob, Convergence},
+    ShockRes
+};
+
+/// Which algorithm is used to find the steady state of the shock propagation.
+/// All of them give the same result within the tolerance
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SolverBackend{
+    /// Update all imports, then all exports
+    #[default]
+    Jacobi,
+    /// Update node by node, upstream nodes first,
+    /// using already updated values immediately
+    GaussSeidel,
+    /// Jacobi sweeps combined with Anderson acceleration
+    Anderson
+}
+
+impl SolverBackend{
+    /// Does the solver need a sweep order?
+    pub fn needs_order(self) -> bool
+    {
+        matches!(self, Self::GaussSeidel)
+    }
+}
+
+/// How many previous iterates are used for the Anderson acceleration
+const ANDERSON_DEPTH: usize = 5;
+
+/// Order in which Gauss-Seidel visits the nodes.
+/// Strongly connected components are ordered upstream first,
+/// so trade that enters a component was already updated in the same sweep.
+/// Accepts both directions of the network
+pub fn sweep_order(network: &Network) -> Vec<usize>
+{
+    // Tarjan returns the components in reverse topological order.
+    // In the import network the suppliers are downstream, so they come first
+    let inverted;
+    let import = if network.direction.is_import(){
+        network
+    } else {
+        inverted = network.invert();
+        &inverted
+    };
+    import.scc_recursive()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+#[inline]
+fn import_frac(job: &CalcShockMultiJob, node: &Node, index: usize, export_frac: &[f64]) -> f64
+{
+    if job.original_imports[index] == 0.0{
+        return 0.0;
+    }
+    let sum: f64 = node.adj
+        .iter()
+        .map(|e| e.amount * export_frac[e.index])
+        .sum();
+    sum * job.original_imports_recip[index]
+}
+
+#[inline]
+fn export_frac(job: &CalcShockMultiJob, index: usize, import_frac: f64) -> f64
+{
+    let missing_imports = (1.0 - import_frac) * job.original_imports[index];
+    let available_for_export = job.original_exports[index] 
+        - missing_imports 
+        - job.production_loss(index);
+    if available_for_export <= 0.0 {
+        0.0
+    } else {
+        available_for_export * job.original_exports_recip[index]
+    }
+}
+
+fn initial_export_fracs(job: &CalcShockMultiJob) -> Vec<f64>
+{
+    let mut current_export_frac = vec![1.0; job.original_exports.len()];
+    for e in job.exporter.iter(){
+        current_export_frac[e.export_id] = e.export_frac;
+    }
+    current_export_frac
+}
+
+/// Import fractions that belong to the final export fractions
+fn final_result(
+    import_network: &Network,
+    job: &CalcShockMultiJob,
+    export_fracs: Vec<f64>,
+    convergence: Convergence
+) -> ShockRes
+{
+    let import_fracs = import_network.nodes
+        .iter()
+        .enumerate()
+        .map(|(index, n)| import_frac(job, n, index, &export_fracs))
+        .collect();
+    ShockRes{
+        import_fracs,
+        export_fracs,
+        convergence,
+        production_loss: job.production_loss.clone()
+    }
+}
+
+/// Gauss-Seidel sweeps in the order of the job
+pub fn gauss_seidel(import_network: &Network, job: &CalcShockMultiJob) -> ShockRes
+{
+    let mut current_export_frac = initial_export_fracs(job);
+    let mut is_free = vec![false; current_export_frac.len()];
+    for &idx in job.unrestricted_node_idxs.iter(){
+        is_free[idx] = true;
+    }
+    let natural_order;
+    let order = if job.sweep_order.is_empty(){
+        natural_order = (0..current_export_frac.len()).collect_vec();
+        natural_order.as_slice()
+    } else {
+        job.sweep_order
+    };
+    let mut reduced_import_frac = vec![1.0; current_export_frac.len()];
+    let mut convergence = Convergence::default();
+
+    for _ in 0..job.iterations{
+        let mut residual: f64 = 0.0;
+        for &index in order{
+            let n = &import_network.nodes[index];
+            let new_import = import_frac(job, n, index, &current_export_frac);
+            residual = residual.max((new_import - reduced_import_frac[index]).abs());
+            reduced_import_frac[index] = new_import;
+            if is_free[index]{
+                let new_export = export_frac(job, index, new_import);
+                residual = residual.max((new_export - current_export_frac[index]).abs());
+                current_export_frac[index] = new_export;
+            }
+        }
+        if convergence.update(residual, job.tolerance){
+            break;
+        }
+    }
+    final_result(import_network, job, current_export_frac, convergence)
+}
+
+/// Solves the small least squares problem min |f - F gamma| via the normal equations.
+/// Returns None if the system is singular
+#[allow(clippy::needless_range_loop)]
+fn least_squares(delta_f: &[Vec<f64>], f: &[f64]) -> Option<Vec<f64>>
+{
+    let m = delta_f.len();
+    let mut a = vec![vec![0.0; m + 1]; m];
+    for i in 0..m{
+        for j in 0..m{
+            a[i][j] = delta_f[i].iter()
+                .zip(delta_f[j].iter())
+                .map(|(x, y)| x * y)
+                .sum();
+        }
+        a[i][m] = delta_f[i].iter()
+            .zip(f.iter())
+            .map(|(x, y)| x * y)
+            .sum();
+    }
+    // regularize slightly to cope with nearly linear dependent history
+    let trace: f64 = (0..m).map(|i| a[i][i]).sum();
+    let reg = trace * 1e-12;
+    for (i, row) in a.iter_mut().enumerate(){
+        row[i] += reg;
+    }
+    // Gaussian elimination with partial pivoting
+    for col in 0..m{
+        let pivot = (col..m)
+            .max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))
+            .unwrap();
+        if a[pivot][col].abs() <= f64::MIN_POSITIVE {
+            return None;
+        }
+        a.swap(col, pivot);
+        for row in col+1..m{
+            let factor = a[row][col] / a[col][col];
+            for k in col..=m{
+                a[row][k] -= factor * a[col][k];
+            }
+        }
+    }
+    let mut gamma = vec![0.0; m];
+    for row in (0..m).rev(){
+        let rest: f64 = (row+1..m)
+            .map(|k| a[row][k] * gamma[k])
+            .sum();
+        gamma[row] = (a[row][m] - rest) / a[row][row];
+    }
+    gamma.iter()
+        .all(|g| g.is_finite())
+        .then_some(gamma)
+}
+
+/// Jacobi map restricted to the unrestricted nodes
+fn jacobi_map(import_network: &Network, job: &CalcShockMultiJob, export_fracs: &mut [f64], free: &[f64])
+-> Vec<f64>
+{
+    for (&index, &val) in job.unrestricted_node_idxs.iter().zip(free){
+        export_fracs[index] = val;
+    }
+    let export_fracs: &[f64] = export_fracs;
+    job.unrestricted_node_idxs
+        .iter()
+        .map(
+            |&index|
+            {
+                let n = &import_network.nodes[index];
+                let y = import_frac(job, n, index, export_fracs);
+                export_frac(job, index, y)
+            }
+        ).collect()
+}
+
+/// Anderson accelerated Jacobi iteration on the export fractions of the unrestricted nodes.
+/// The accelerated iterate is projected back onto [0,1]
+pub fn anderson(import_network: &Network, job: &CalcShockMultiJob) -> ShockRes
+{
+    let mut current_export_frac = initial_export_fracs(job);
+    let mut x = job.unrestricted_node_idxs
+        .iter()
+        .map(|&idx| current_export_frac[idx])
+        .collect_vec();
+    let mut history_f: Vec<Vec<f64>> = Vec::with_capacity(ANDERSON_DEPTH + 1);
+    let mut history_g: Vec<Vec<f64>> = Vec::with_capacity(ANDERSON_DEPTH + 1);
+    let mut convergence = Convergence::default();
+
+    for _ in 0..job.iterations{
+        let g = jacobi_map(import_network, job, &mut current_export_frac, &x);
+        let f = g.iter()
+            .zip(x.iter())
+            .map(|(g, x)| g - x)
+            .collect_vec();
+        let residual = f.iter()
+            .fold(0.0, |acc: f64, v| acc.max(v.abs()));
+        if convergence.update(residual, job.tolerance){
+            x = g;
+            break;
+        }
+        history_f.push(f.clone());
+        history_g.push(g.clone());
+        if history_f.len() > ANDERSON_DEPTH + 1 {
+            history_f.remove(0);
+            history_g.remove(0);
+        }
+        if history_f.len() < 2 {
+            x = g;
+            continue;
+        }
+        let delta = |h: &[Vec<f64>]| -> Vec<Vec<f64>> {
+            h.iter()
+                .tuple_windows()
+                .map(
+                    |(a, b)|
+                    b.iter().zip(a.iter()).map(|(b, a)| b - a).collect()
+                ).collect()
+        };
+        let delta_f = delta(&history_f);
+        let delta_g = delta(&history_g);
+        x = match least_squares(&delta_f, &f){
+            Some(gamma) => {
+                let mut next = g;
+                for (gam, dg) in gamma.iter().zip(delta_g.iter()){
+                    for (n, d) in next.iter_mut().zip(dg.iter()){
+                        *n -= gam * d;
+                    }
+                }
+                next.iter_mut()
+                    .for_each(|v| *v = v.clamp(0.0, 1.0));
+                next
+            },
+            None => {
+                history_f.clear();
+                history_g.clear();
+                g
+            }
+        };
+    }
+    for (&index, &val) in job.unrestricted_node_idxs.iter().zip(x.iter()){
+        current_export_frac[index] = val;
+    }
+    final_result(import_network, job, current_export_frac, convergence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+    use super::super::{flow_helper::*, multi_shock_distribution};
+
+    fn ring_network() -> Network
+    {
+        let amounts = [
+            (0, 1, 10.0),
+            (1, 2, 7.0),
+            (2, 0, 3.0),
+            (2, 3, 5.0),
+            (3, 1, 2.0),
+            (0, 3, 4.0),
+            (4, 0, 6.0)
+        ];
+        test_export_network(5, &amounts)
+    }
+
+    #[test]
+    fn solvers_agree() {
+        let export = ring_network();
+        let import = export.invert();
+        let oe = calc_acc_trade(&export);
+        let oe_recip = calc_recip(&oe);
+        let oi = calc_acc_trade(&import);
+        let oi_recip = calc_recip(&oi);
+        let order = sweep_order(&export);
+        let results = [SolverBackend::Jacobi, SolverBackend::GaussSeidel, SolverBackend::Anderson]
+            .map(
+                |solver|
+                {
+                    let job = CalcShockMultiJob::new_const_export(
+                        &[0],
+                        0.3,
+                        10000,
+                        &export,
+                        &oe,
+                        &oe_recip,
+                        &oi,
+                        &oi_recip
+                    ).with_solver(solver, &order);
+                    multi_shock_distribution(&import, &job)
+                }
+            );
+        for res in results.iter(){
+            assert!(res.convergence.converged);
+            for (a, b) in res.export_fracs.iter().zip(results[0].export_fracs.iter()){
+                assert!((a - b).abs() < 1e-9);
+            }
+            for (a, b) in res.import_fracs.iter().zip(results[0].import_fracs.iter()){
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
+}
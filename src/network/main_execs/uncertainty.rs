@@ -0,0 +1,553 @@
+use std::{
+    collections::BTreeMap,
+    f64::consts::TAU,
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    production_vec,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct UncertaintyOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "uncertainty")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+/// How the reported data is perturbed in every replicate
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative, PartialEq)]
+#[derivative(Default)]
+pub enum ErrorModel{
+    /// Every edge weight and enrichment value is multiplied by independent,
+    /// mean preserving lognormal noise
+    #[derivative(Default)]
+    LogNormal{
+        #[derivative(Default(value = "0.1"))]
+        edge_sigma: f64,
+        #[derivative(Default(value = "0.05"))]
+        enrichment_sigma: f64
+    },
+    /// Every edge weight is drawn uniformly between the amount reported by the importer
+    /// and the amount reported by the exporter, which is read from the mirror network file,
+    /// i.e., the networks parsed with the other read type.
+    /// Edges that are only reported by one side get lognormal noise
+    Mirror{
+        mirror_network_file: Utf8PathBuf,
+        fallback_sigma: f64,
+        enrichment_sigma: f64
+    }
+}
+
+impl ErrorModel{
+    pub fn name(&self) -> &'static str
+    {
+        match self{
+            Self::LogNormal{..} => "LogN",
+            Self::Mirror{..} => "Mirror"
+        }
+    }
+
+    fn enrichment_sigma(&self) -> f64
+    {
+        match self{
+            Self::LogNormal{enrichment_sigma, ..} | Self::Mirror{enrichment_sigma, ..} => *enrichment_sigma
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct Uncertainty{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    pub error_model: ErrorModel,
+
+    /// Number of perturbed replicates per year
+    #[derivative(Default(value = "100"))]
+    pub replicates: usize,
+
+    /// Level of the reported confidence bands
+    #[derivative(Default(value = "0.9"))]
+    pub confidence: f64,
+
+    pub seed: u64,
+
+    /// How many top exporters are shocked. They are chosen from the unperturbed network
+    #[derivative(Default(value = "5"))]
+    pub top: usize,
+
+    /// Restrict the exports or reduce the production of the top countries
+    #[serde(default)]
+    pub shock_type: ShockType,
+
+    /// Remaining fractions of exports or production of the top countries,
+    /// one point of every output curve each
+    #[derivative(Default(value = "(0..10).map(|i| i as f64 / 10.0).collect()"))]
+    pub remaining_fracs: Vec<f64>,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock in the unperturbed network are not counted
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend
+}
+
+/// Standard normal random number (Box-Muller)
+fn standard_normal<R: Rng>(rng: &mut R) -> f64
+{
+    // 1 - u is in (0, 1], so the logarithm is finite
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos()
+}
+
+/// Lognormal factor with mean 1
+pub fn lognormal_factor<R: Rng>(sigma: f64, rng: &mut R) -> f64
+{
+    (sigma * standard_normal(rng) - 0.5 * sigma * sigma).exp()
+}
+
+/// Amounts of the mirror network, (exporter, importer) -> amount
+pub fn mirror_amounts(mirror_export: &Network) -> BTreeMap<(&str, &str), f64>
+{
+    assert!(!mirror_export.direction.is_import());
+    mirror_export.nodes
+        .iter()
+        .flat_map(
+            |node|
+            {
+                node.adj
+                    .iter()
+                    .map(
+                        |e|
+                        {
+                            let key = (
+                                node.identifier.as_str(),
+                                mirror_export.nodes[e.index].identifier.as_str()
+                            );
+                            (key, e.amount)
+                        }
+                    )
+            }
+        ).collect()
+}
+
+/// Copy of the export network with perturbed edge weights. The nodes and indices are unchanged
+pub fn perturb_network<R: Rng>(
+    export_network: &Network,
+    model: &ErrorModel,
+    mirror: Option<&BTreeMap<(&str, &str), f64>>,
+    rng: &mut R
+) -> Network
+{
+    assert!(!export_network.direction.is_import());
+    let mut perturbed = export_network.clone();
+    for node in perturbed.nodes.iter_mut(){
+        for e in node.adj.iter_mut(){
+            e.amount = match model{
+                ErrorModel::LogNormal{edge_sigma, ..} => e.amount * lognormal_factor(*edge_sigma, rng),
+                ErrorModel::Mirror{fallback_sigma, ..} => {
+                    let key = (
+                        node.identifier.as_str(),
+                        export_network.nodes[e.index].identifier.as_str()
+                    );
+                    match mirror.and_then(|m| m.get(&key)){
+                        Some(&other) => {
+                            let (low, high) = if other < e.amount {
+                                (other, e.amount)
+                            } else {
+                                (e.amount, other)
+                            };
+                            low + (high - low) * rng.gen::<f64>()
+                        },
+                        None => e.amount * lognormal_factor(*fallback_sigma, rng)
+                    }
+                }
+            };
+        }
+    }
+    perturbed
+}
+
+/// Copy of the enrichment with every amount multiplied by lognormal noise
+pub fn perturb_enrichment<R: Rng>(
+    enrich: &BTreeMap<String, ExtraInfo>,
+    sigma: f64,
+    rng: &mut R
+) -> BTreeMap<String, ExtraInfo>
+{
+    let mut perturbed = enrich.clone();
+    perturbed.values_mut()
+        .flat_map(|info| info.map.values_mut())
+        .for_each(|extra| extra.amount *= lognormal_factor(sigma, rng));
+    perturbed
+}
+
+/// Output curves of one replicate, one entry per remaining fraction
+struct Curves{
+    unstable: Vec<f64>,
+    missing_supply: Vec<f64>,
+    /// relative[f][c] is the relative availability of the counted country c
+    relative: Vec<Vec<f64>>,
+    convergence: ConvergenceSummary
+}
+
+fn shock_curves(
+    export: &Network,
+    enrich: &BTreeMap<String, ExtraInfo>,
+    top: &[usize],
+    counted: &[usize],
+    json: &Uncertainty,
+    ctx: &SimulationContext,
+    quiet: bool
+) -> Curves
+{
+    let import = export.invert();
+    let original_exports = calc_acc_trade(export);
+    let original_exports_recip = calc_recip(&original_exports);
+    let original_imports = calc_acc_trade(&import);
+    let original_imports_recip = calc_recip(&original_imports);
+    let production = production_vec(export, enrich, ctx);
+    let order = if json.solver.needs_order(){
+        sweep_order(&import)
+    } else {
+        Vec::new()
+    };
+    let no_shock = ShockRes::no_shock(export.node_count());
+    let (baseline, _) = calc_available(export, enrich, &no_shock, ctx, quiet);
+    let total_baseline: f64 = counted.iter()
+        .map(|&idx| baseline[idx])
+        .sum();
+
+    let mut curves = Curves{
+        unstable: Vec::with_capacity(json.remaining_fracs.len()),
+        missing_supply: Vec::with_capacity(json.remaining_fracs.len()),
+        relative: Vec::with_capacity(json.remaining_fracs.len()),
+        convergence: ConvergenceSummary::default()
+    };
+    for &frac in json.remaining_fracs.iter(){
+        let job = match json.shock_type{
+            ShockType::ExportRestriction => {
+                CalcShockMultiJob::new_const_export(
+                    top,
+                    frac,
+                    json.iterations,
+                    export,
+                    &original_exports,
+                    &original_exports_recip,
+                    &original_imports,
+                    &original_imports_recip
+                )
+            },
+            ShockType::Production => {
+                CalcShockMultiJob::new_const_production(
+                    top,
+                    frac,
+                    &production,
+                    json.iterations,
+                    export,
+                    &original_exports,
+                    &original_exports_recip,
+                    &original_imports,
+                    &original_imports_recip
+                )
+            }
+        }.with_tolerance(json.tolerance)
+        .with_solver(json.solver, &order);
+        let res = multi_shock_distribution(&import, &job);
+        curves.convergence.add(&res.convergence);
+        let (avail, _) = calc_available(export, enrich, &res, ctx, quiet);
+
+        // countries without availability in the perturbed data have no relative availability
+        let relative = counted.iter()
+            .map(|&idx| avail[idx] / baseline[idx])
+            .collect_vec();
+        let unstable = relative.iter()
+            .filter(|&&r| r < json.unstable_country_threshold)
+            .count();
+        let missing: f64 = counted.iter()
+            .map(|&idx| (baseline[idx] - avail[idx]).max(0.0))
+            .sum();
+        curves.unstable.push(unstable as f64);
+        curves.missing_supply.push(missing / total_baseline);
+        curves.relative.push(relative);
+    }
+    curves
+}
+
+/// Mean, lower bound, median and upper bound of the finite values
+fn band(values: impl Iterator<Item = f64>, confidence: f64) -> [f64; 4]
+{
+    let mut values = values
+        .filter(|v| v.is_finite())
+        .collect_vec();
+    if values.is_empty(){
+        return [f64::NAN; 4];
+    }
+    values.sort_unstable_by(f64::total_cmp);
+    let last = values.len() - 1;
+    let q = |q: f64| values[((q * last as f64).round() as usize).min(last)];
+    let alpha = (1.0 - confidence) / 2.0;
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    [mean, q(alpha), q(0.5), q(1.0 - alpha)]
+}
+
+pub fn uncertainty(opt: UncertaintyOpts)
+{
+    let json: Uncertainty = parse_and_add_to_global(opt.json);
+    assert!(
+        (0.0..1.0).contains(&json.confidence),
+        "confidence has to be in range 0.0..1.0"
+    );
+    assert!(
+        json.remaining_fracs.iter().all(|f| (0.0..=1.0).contains(f)),
+        "remaining_fracs have to be in range 0.0..=1.0"
+    );
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mirror_networks = match &json.error_model{
+        ErrorModel::Mirror{mirror_network_file, ..} => {
+            let mut lazy = LazyNetworks::Filename(mirror_network_file.clone());
+            lazy.assure_availability();
+            Some(lazy)
+        },
+        ErrorModel::LogNormal{..} => None
+    };
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+    let mode_str = ctx.mode_str();
+    let shock_str = json.shock_type.name_addition();
+    let model_str = json.error_model.name();
+    let enrichment_sigma = json.error_model.enrichment_sigma();
+
+    let mut rng = Pcg64::seed_from_u64(json.seed);
+    let years_and_rngs = json.years
+        .clone()
+        .map(|y| (y, Pcg64::from_rng(&mut rng).unwrap()))
+        .collect_vec();
+
+    years_and_rngs
+        .into_par_iter()
+        .for_each(
+            |(year, mut rng)|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let enrich = enrichment_infos.get_year(year);
+                let mirror = mirror_networks
+                    .as_ref()
+                    .map(|m| mirror_amounts(m.get_export_network_unchecked(year)));
+
+                let top = get_top_k_ids(&export, json.top);
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, flow_status) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+                let counted = (0..export.node_count())
+                    .filter(|idx| !top.contains(idx))
+                    .filter(|&idx| baseline[idx] >= json.original_avail_filter)
+                    .collect_vec();
+
+                let unperturbed = shock_curves(&export, enrich, &top, &counted, &json, &ctx, opt.quiet);
+
+                let replicate_rngs = (0..json.replicates)
+                    .map(|_| Pcg64::from_rng(&mut rng).unwrap())
+                    .collect_vec();
+                let replicates: Vec<Curves> = replicate_rngs
+                    .into_par_iter()
+                    .map(
+                        |mut rng|
+                        {
+                            let network = perturb_network(&export, &json.error_model, mirror.as_ref(), &mut rng);
+                            let enrich = perturb_enrichment(enrich, enrichment_sigma, &mut rng);
+                            shock_curves(&network, &enrich, &top, &counted, &json, &ctx, true)
+                        }
+                    ).collect();
+
+                let mut convergence = unperturbed.convergence;
+                replicates.iter()
+                    .for_each(|r| convergence.merge(&r.convergence));
+                if convergence.not_converged > 0 {
+                    eprintln!(
+                        "WARNING: Y{year} - {} of {} shocks did not converge",
+                        convergence.not_converged,
+                        convergence.runs
+                    );
+                }
+
+                let stub = format!(
+                    "{}{}_Y{year}_{model_str}_{mode_str}{shock_str}",
+                    flow_status.name_addition(),
+                    opt.out_stub
+                );
+                let write_info = |buf: &mut dyn Write| {
+                    writeln!(
+                        buf,
+                        "# {} replicates, error model {:?}, band level {}",
+                        json.replicates,
+                        json.error_model,
+                        json.confidence
+                    ).unwrap();
+                    writeln!(
+                        buf,
+                        "# shocked {}",
+                        top.iter()
+                            .map(|&idx| export.nodes[idx].identifier.as_str())
+                            .join(",")
+                    ).unwrap();
+                    convergence.write_comment(buf).unwrap();
+                };
+
+                let header = [
+                    "remaining_frac",
+                    "unstable",
+                    "unstable_mean",
+                    "unstable_low",
+                    "unstable_median",
+                    "unstable_high",
+                    "missing_supply",
+                    "missing_supply_mean",
+                    "missing_supply_low",
+                    "missing_supply_median",
+                    "missing_supply_high"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.bands"), header);
+                write_info(&mut buf);
+                for (f, frac) in json.remaining_fracs.iter().enumerate(){
+                    let unstable = band(replicates.iter().map(|r| r.unstable[f]), json.confidence);
+                    let missing = band(replicates.iter().map(|r| r.missing_supply[f]), json.confidence);
+                    writeln!(
+                        buf,
+                        "{frac} {} {:e} {:e} {:e} {:e} {:e} {:e} {:e} {:e} {:e}",
+                        unperturbed.unstable[f],
+                        unstable[0],
+                        unstable[1],
+                        unstable[2],
+                        unstable[3],
+                        unperturbed.missing_supply[f],
+                        missing[0],
+                        missing[1],
+                        missing[2],
+                        missing[3]
+                    ).unwrap();
+                }
+
+                let header = [
+                    "country",
+                    "remaining_frac",
+                    "relative_availability",
+                    "mean",
+                    "low",
+                    "median",
+                    "high"
+                ];
+                let mut buf = create_buf_with_command_and_version_and_header(format!("{stub}.country_bands"), header);
+                write_info(&mut buf);
+                for (c, &idx) in counted.iter().enumerate(){
+                    for (f, frac) in json.remaining_fracs.iter().enumerate(){
+                        let b = band(replicates.iter().map(|r| r.relative[f][c]), json.confidence);
+                        writeln!(
+                            buf,
+                            "{} {frac} {:e} {:e} {:e} {:e} {:e}",
+                            export.nodes[idx].identifier,
+                            unperturbed.relative[f][c],
+                            b[0],
+                            b[1],
+                            b[2],
+                            b[3]
+                        ).unwrap();
+                    }
+                }
+            }
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_export_network;
+
+    #[test]
+    fn mirror_stays_within_reports() {
+        let reported = test_export_network(3, &[(0, 1, 10.0), (1, 2, 4.0)]);
+        // the edge 1 -> 2 is only reported by the importer
+        let mirror_network = test_export_network(3, &[(0, 1, 6.0)]);
+        let mirror = mirror_amounts(&mirror_network);
+        let model = ErrorModel::Mirror{
+            mirror_network_file: Utf8PathBuf::new(),
+            fallback_sigma: 0.0,
+            enrichment_sigma: 0.0
+        };
+        let mut rng = Pcg64::seed_from_u64(4);
+        for _ in 0..100{
+            let perturbed = perturb_network(&reported, &model, Some(&mirror), &mut rng);
+            let amount = perturbed.nodes[0].adj[0].amount;
+            assert!((6.0..=10.0).contains(&amount));
+            assert!((perturbed.nodes[1].adj[0].amount - 4.0).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    ops::RangeInclusive
+};
+use camino::Utf8PathBuf;
+use clap::Parser;
+use derivative::Derivative;
+use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{
+    misc::*,
+    network::{enriched_digraph::*, *}
+};
+use super::{
+    flow_helper::*,
+    shock_solver::{sweep_order, SolverBackend},
+    calc_available,
+    get_top_k_ids,
+    multi_shock_distribution,
+    ShockRes,
+    SimulationContext,
+    SimulationMode
+};
+
+#[derive(Debug, Clone, Parser)]
+pub struct WorstCaseOpts{
+    /// Path to json file, if not given default config will be printed
+    #[arg(long, short)]
+    pub json: Option<Utf8PathBuf>,
+
+    /// Stub for the output files
+    #[arg(long, short, default_value = "worst_case")]
+    pub out_stub: String,
+
+    /// Surpress warnings
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Classic, only_stock or with_stock_variation
+    #[arg(long, short, default_value = "classic")]
+    pub mode: SimulationMode
+}
+
+/// What the search tries to maximize
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Objective{
+    /// Number of countries whose availability drops below the threshold
+    #[default]
+    UnstableCountries,
+    /// Sum of the availability that is lost in all countries
+    MissingSupply
+}
+
+/// How the worst disruption is searched
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum Search{
+    /// Add the unit that increases the objective the most, one after another
+    #[default]
+    Greedy,
+    /// Like greedy, but keep the best `width` partial solutions
+    Beam{
+        width: usize
+    },
+    /// Start at the top-k solution and move single units between countries
+    Annealing{
+        steps: usize,
+        start_temperature: f64,
+        end_temperature: f64
+    }
+}
+
+impl Search{
+    pub fn name(&self) -> &'static str
+    {
+        match self{
+            Self::Greedy => "Greedy",
+            Self::Beam{..} => "Beam",
+            Self::Annealing{..} => "Annealing"
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub struct WorstCase{
+    /// File with enrich infos
+    pub enrich_file: String,
+
+    /// File with the network data
+    pub network_file: Utf8PathBuf,
+
+    /// Item code, e.g. 27 for Rice
+    pub item_code: Option<String>,
+
+    /// Years that are searched independently
+    #[derivative(Default(value = "2000..=2019"))]
+    pub years: RangeInclusive<i32>,
+
+    /// Number of countries that are disrupted. Ignored if a budget is given
+    #[derivative(Default(value = "5"))]
+    pub k: usize,
+
+    /// Remaining fraction of the exports of the disrupted countries.
+    /// Ignored if a budget is given
+    pub export_frac: f64,
+
+    /// Total export reduction, i.e., sum over all countries of one minus their remaining export fraction.
+    /// If given, the search looks for the distribution of the reduction instead of a set of countries
+    pub budget: Option<f64>,
+
+    /// A budget is distributed in units of 1/resolution
+    #[derivative(Default(value = "10"))]
+    pub resolution: u32,
+
+    /// Only this many top exporters are candidates of the search
+    #[derivative(Default(value = "30"))]
+    pub candidates: usize,
+
+    pub objective: Objective,
+
+    pub search: Search,
+
+    /// Seed for the annealing
+    #[derivative(Default(value = "2958369"))]
+    pub seed: u64,
+
+    /// Maximal number of iterations of each shock propagation
+    #[derivative(Default(value = "10000"))]
+    pub iterations: usize,
+
+    /// Stop iterating once no fraction changes by more than this during one sweep
+    #[derivative(Default(value = "DEFAULT_TOLERANCE"))]
+    pub tolerance: f64,
+
+    /// Algorithm for the shock propagation
+    #[serde(default)]
+    pub solver: SolverBackend,
+
+    /// the fraction at which countries are counted as unstable
+    #[derivative(Default(value = "0.7"))]
+    pub unstable_country_threshold: f64,
+
+    /// Countries that have less than this amount of
+    /// product without shock are not counted
+    #[derivative(Default(value = "1e-9"))]
+    pub original_avail_filter: f64
+}
+
+impl WorstCase{
+    /// Number of units that are distributed and the maximal number of units per country
+    fn units_and_levels(&self) -> (u32, u32)
+    {
+        match self.budget{
+            None => (self.k as u32, 1),
+            Some(b) => ((b * self.resolution as f64).round() as u32, self.resolution)
+        }
+    }
+
+    /// Remaining export fraction of a country that got `units` units
+    fn export_frac(&self, units: u32) -> f64
+    {
+        match self.budget{
+            None => self.export_frac,
+            Some(_) => 1.0 - units as f64 / self.resolution as f64
+        }
+    }
+}
+
+/// Number of units every candidate got
+type Allocation = Vec<u32>;
+
+/// Everything needed to evaluate the objective for one year
+struct Evaluator<'a>{
+    json: &'a WorstCase,
+    ctx: &'a SimulationContext,
+    export: &'a Network,
+    import: &'a Network,
+    enrich: &'a BTreeMap<String, ExtraInfo>,
+    candidates: &'a [usize],
+    baseline: &'a [f64],
+    original_exports: Vec<f64>,
+    original_exports_recip: Vec<f64>,
+    original_imports: Vec<f64>,
+    original_imports_recip: Vec<f64>,
+    order: Vec<usize>,
+    quiet: bool,
+    evaluations: usize,
+    convergence: ConvergenceSummary,
+    cache: BTreeMap<Allocation, f64>
+}
+
+impl Evaluator<'_>{
+    fn objective(&mut self, allocation: &Allocation) -> f64
+    {
+        if let Some(&val) = self.cache.get(allocation){
+            return val;
+        }
+        self.evaluations += 1;
+        let exporter = self.candidates
+            .iter()
+            .zip(allocation.iter())
+            .filter(|(_, &units)| units > 0)
+            .map(|(&export_id, &units)| ExportShockItem{export_id, export_frac: self.json.export_frac(units)})
+            .collect_vec();
+        let disrupted: BTreeSet<_> = exporter.iter()
+            .map(|e| e.export_id)
+            .collect();
+        let shock_result = if exporter.is_empty(){
+            ShockRes::no_shock(self.export.node_count())
+        } else {
+            let job = CalcShockMultiJob::new_exporter(
+                exporter,
+                self.json.iterations,
+                self.export,
+                &self.original_imports,
+                &self.original_imports_recip,
+                &self.original_exports,
+                &self.original_exports_recip
+            ).with_tolerance(self.json.tolerance)
+            .with_solver(self.json.solver, &self.order);
+            let res = multi_shock_distribution(self.import, &job);
+            self.convergence.add(&res.convergence);
+            res
+        };
+        let (avail, _) = calc_available(self.export, self.enrich, &shock_result, self.ctx, self.quiet);
+        let counted = (0..self.export.node_count())
+            .filter(|idx| !disrupted.contains(idx))
+            .filter(|&idx| self.baseline[idx] >= self.json.original_avail_filter);
+        let val = match self.json.objective{
+            Objective::UnstableCountries => {
+                counted.filter(|&idx| avail[idx] / self.baseline[idx] < self.json.unstable_country_threshold)
+                    .count() as f64
+            },
+            Objective::MissingSupply => {
+                counted.map(|idx| (self.baseline[idx] - avail[idx]).max(0.0))
+                    .sum()
+            }
+        };
+        self.cache.insert(allocation.clone(), val);
+        val
+    }
+
+    /// All allocations that have one unit more than the given one
+    fn successors(&self, allocation: &Allocation, levels: u32) -> Vec<Allocation>
+    {
+        (0..allocation.len())
+            .filter(|&i| allocation[i] < levels)
+            .map(
+                |i|
+                {
+                    let mut next = allocation.clone();
+                    next[i] += 1;
+                    next
+                }
+            ).collect()
+    }
+}
+
+/// Gives the units to the largest exporters, filling one country after another
+fn top_k_allocation(candidates: usize, units: u32, levels: u32) -> Allocation
+{
+    let mut allocation = vec![0; candidates];
+    let mut remaining = units;
+    for a in allocation.iter_mut(){
+        let u = remaining.min(levels);
+        *a = u;
+        remaining -= u;
+    }
+    allocation
+}
+
+fn beam(eval: &mut Evaluator, units: u32, levels: u32, width: usize) -> (Allocation, f64)
+{
+    let mut beam = vec![(vec![0; eval.candidates.len()], 0.0)];
+    for _ in 0..units{
+        let next: BTreeSet<Allocation> = beam.iter()
+            .flat_map(|(a, _)| eval.successors(a, levels))
+            .collect();
+        if next.is_empty(){
+            break;
+        }
+        let mut scored = next.into_iter()
+            .map(
+                |a|
+                {
+                    let val = eval.objective(&a);
+                    (a, val)
+                }
+            ).collect_vec();
+        // stable sort keeps the allocation order for ties, so the result is deterministic
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(width);
+        beam = scored;
+    }
+    beam.swap_remove(0)
+}
+
+fn annealing(
+    eval: &mut Evaluator,
+    start: Allocation,
+    levels: u32,
+    steps: usize,
+    start_temperature: f64,
+    end_temperature: f64,
+    rng: &mut Pcg64
+) -> (Allocation, f64)
+{
+    let mut current_val = eval.objective(&start);
+    let mut current = start;
+    let mut best = (current.clone(), current_val);
+    if current.len() < 2 || current.iter().all(|&u| u == 0) {
+        return best;
+    }
+    let cooling = (end_temperature / start_temperature).powf(1.0 / steps.max(1) as f64);
+    let mut temperature = start_temperature;
+    for _ in 0..steps{
+        // move one unit from a country that has some to one that has room left
+        let from = loop {
+            let i = rng.gen_range(0..current.len());
+            if current[i] > 0 {
+                break i;
+            }
+        };
+        let to = rng.gen_range(0..current.len());
+        if to == from || current[to] >= levels {
+            temperature *= cooling;
+            continue;
+        }
+        let mut proposal = current.clone();
+        proposal[from] -= 1;
+        proposal[to] += 1;
+        let val = eval.objective(&proposal);
+        let accept = val >= current_val
+            || rng.gen::<f64>() < ((val - current_val) / temperature).exp();
+        if accept{
+            current = proposal;
+            current_val = val;
+            if current_val > best.1 {
+                best = (current.clone(), current_val);
+            }
+        }
+        temperature *= cooling;
+    }
+    best
+}
+
+struct YearResult{
+    year: i32,
+    top_k: f64,
+    worst: f64,
+    evaluations: usize,
+    countries: String,
+    convergence: ConvergenceSummary
+}
+
+pub fn worst_case_search(opt: WorstCaseOpts)
+{
+    let json: WorstCase = parse_and_add_to_global(opt.json);
+    assert!(
+        (0.0..=1.0).contains(&json.export_frac),
+        "export_frac has to be in range 0.0..=1.0"
+    );
+    assert!(json.resolution > 0, "resolution has to be positive");
+    let (units, levels) = json.units_and_levels();
+    assert!(
+        units <= levels * json.candidates as u32,
+        "The candidates cannot absorb the whole budget"
+    );
+
+    let mut lazy_networks = LazyNetworks::Filename(json.network_file.clone());
+    lazy_networks.assure_availability();
+    let mut lazy_enrichments = LazyEnrichmentInfos::Filename(
+        json.enrich_file.clone(),
+        json.item_code.clone()
+    );
+    lazy_enrichments.assure_availability();
+    let enrichment_infos = lazy_enrichments.enrichment_infos_unchecked();
+    let ctx = SimulationContext::new(opt.mode)
+        .with_node_map(&lazy_enrichments.extra_info_idmap_unchecked());
+
+    let mut rng = Pcg64::seed_from_u64(json.seed);
+    let years_and_rngs = json.years
+        .clone()
+        .map(|y| (y, Pcg64::from_rng(&mut rng).unwrap()))
+        .collect_vec();
+
+    let results = years_and_rngs
+        .into_par_iter()
+        .map(
+            |(year, mut rng)|
+            {
+                let export = lazy_networks
+                    .get_export_network_unchecked(year)
+                    .without_unconnected_nodes();
+                let import = export.invert();
+                let enrich = enrichment_infos.get_year(year);
+                let candidates = get_top_k_ids(&export, json.candidates);
+                let no_shock = ShockRes::no_shock(export.node_count());
+                let (baseline, _) = calc_available(&export, enrich, &no_shock, &ctx, opt.quiet);
+                let original_exports = calc_acc_trade(&export);
+                let original_imports = calc_acc_trade(&import);
+                let mut eval = Evaluator{
+                    json: &json,
+                    ctx: &ctx,
+                    export: &export,
+                    import: &import,
+                    enrich,
+                    candidates: &candidates,
+                    baseline: &baseline,
+                    original_exports_recip: calc_recip(&original_exports),
+                    original_exports,
+                    original_imports_recip: calc_recip(&original_imports),
+                    original_imports,
+                    order: if json.solver.needs_order(){
+                        sweep_order(&import)
+                    } else {
+                        Vec::new()
+                    },
+                    quiet: opt.quiet,
+                    evaluations: 0,
+                    convergence: ConvergenceSummary::default(),
+                    cache: BTreeMap::new()
+                };
+                // fewer trading countries than candidates
+                let units = units.min(levels * candidates.len() as u32);
+
+                let top_k = top_k_allocation(candidates.len(), units, levels);
+                let top_k_val = eval.objective(&top_k);
+                let (worst, worst_val) = match json.search{
+                    Search::Greedy => beam(&mut eval, units, levels, 1),
+                    Search::Beam{width} => beam(&mut eval, units, levels, width.max(1)),
+                    Search::Annealing{steps, start_temperature, end_temperature} => {
+                        annealing(
+                            &mut eval,
+                            top_k,
+                            levels,
+                            steps,
+                            start_temperature,
+                            end_temperature,
+                            &mut rng
+                        )
+                    }
+                };
+                let countries = candidates.iter()
+                    .zip(worst.iter())
+                    .filter(|(_, &units)| units > 0)
+                    .map(|(&idx, &units)| format!("{}:{}", export.nodes[idx].identifier, json.export_frac(units)))
+                    .join(",");
+                YearResult{
+                    year,
+                    top_k: top_k_val,
+                    worst: worst_val,
+                    evaluations: eval.evaluations,
+                    countries,
+                    convergence: eval.convergence
+                }
+            }
+        ).collect::<Vec<_>>();
+
+    let item = json.item_code
+        .as_deref()
+        .map(|i| format!("_Item{i}"))
+        .unwrap_or_default();
+    let budget = match json.budget{
+        None => format!("k{}", json.k),
+        Some(b) => format!("B{b}")
+    };
+    let name = format!(
+        "{}{item}_{budget}_{}_{}.worst",
+        opt.out_stub,
+        json.search.name(),
+        ctx.mode_str()
+    );
+    let header = [
+        "year",
+        "top_k_objective",
+        "worst_objective",
+        "ratio",
+        "evaluations",
+        "worst_countries"
+    ];
+    let mut buf = create_buf_with_command_and_version_and_header(name, header);
+    writeln!(buf, "# objective {:?}", json.objective).unwrap();
+    writeln!(buf, "# ratio is NaN if the top_k_objective is 0, worst_countries is - for an empty allocation").unwrap();
+    let mut total = ConvergenceSummary::default();
+    for r in results.iter(){
+        let ratio = if r.top_k == 0.0 {
+            f64::NAN
+        } else {
+            r.worst / r.top_k
+        };
+        // the countries are joined with commas, so the table stays whitespace separated
+        let countries = if r.countries.is_empty(){
+            "-"
+        } else {
+            r.countries.as_str()
+        };
+        writeln!(
+            buf,
+            "{} {} {} {ratio} {} {countries}",
+            r.year,
+            r.top_k,
+            r.worst,
+            r.evaluations
+        ).unwrap();
+        total.merge(&r.convergence);
+    }
+    total.write_comment(&mut buf).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_k_fills_largest_first() {
+        assert_eq!(top_k_allocation(4, 2, 1), vec![1, 1, 0, 0]);
+        assert_eq!(top_k_allocation(3, 25, 10), vec![10, 10, 5]);
+    }
+}
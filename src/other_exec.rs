@@ -31,7 +31,7 @@ pub fn worst_integral_sorting(opt: WorstIntegralCombineOpts)
     }
 
     for other_name in opt.filenames[1..].iter(){
-        let filename = other_name.split('/').last().unwrap();
+        let filename = other_name.split('/').next_back().unwrap();
         let new_name = format!("{filename}.sorted");
         println!("CREATING {new_name}");
         let mut buf = create_buf_with_command_and_version(new_name);